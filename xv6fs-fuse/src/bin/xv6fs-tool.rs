@@ -0,0 +1,330 @@
+//! Inspect and edit an xv6 file system image without mounting it.
+//!
+//! ```text
+//! xv6fs-tool fs.img ls /bin
+//! xv6fs-tool fs.img put README /README
+//! xv6fs-tool fs.img get /README README.out
+//! ```
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use xv6fs::disk_inode::InodeType;
+use xv6fs::file::VFile;
+use xv6fs::{bitmap, fsck};
+use xv6fs::host::{FileDevice, StdInterface};
+use xv6fs::{FsError, MountOptions, Xv6FS};
+
+use std::fs::File;
+use std::io::{stdout, Read, Write};
+use std::process::exit;
+use std::sync::Arc;
+
+/// Files are copied in chunks of this size
+const COPY_CHUNK: usize = 64 * 1024;
+
+fn main() {
+    let path_arg = |help| Arg::with_name("path").required(true).help(help);
+    let matches = App::new("xv6fs-tool")
+        .about("Inspect and edit an xv6 file system image")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Image to operate on"))
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .help("Open the image read-only, without replaying its log"))
+        .subcommand(SubCommand::with_name("ls")
+            .about("List a directory")
+            .arg(Arg::with_name("path").default_value("/").help("Directory in the image")))
+        .subcommand(SubCommand::with_name("cat")
+            .about("Print a file to stdout")
+            .arg(path_arg("File in the image")))
+        .subcommand(SubCommand::with_name("put")
+            .about("Copy a host file into the image, replacing its content")
+            .arg(Arg::with_name("host").required(true).help("Host file"))
+            .arg(path_arg("Destination in the image")))
+        .subcommand(SubCommand::with_name("get")
+            .about("Copy a file out of the image")
+            .arg(path_arg("File in the image"))
+            .arg(Arg::with_name("host").required(true).help("Host destination")))
+        .subcommand(SubCommand::with_name("mkdir")
+            .about("Make a directory")
+            .arg(path_arg("Directory to create")))
+        .subcommand(SubCommand::with_name("rm")
+            .about("Remove a file")
+            .arg(Arg::with_name("recursive")
+                .short("r")
+                .help("Remove directories and their contents"))
+            .arg(path_arg("Entry to remove")))
+        .subcommand(SubCommand::with_name("stat")
+            .about("Print the metadata of a file")
+            .arg(path_arg("Entry in the image")))
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Check the image for consistency")
+            .arg(Arg::with_name("repair")
+                .short("y")
+                .long("repair")
+                .help("Repair the problems found")))
+        .subcommand(SubCommand::with_name("trim")
+            .about("Punch holes in the image for its free blocks"))
+        .get_matches();
+
+    let image = matches.value_of("image").unwrap();
+    let read_only = matches.is_present("read-only");
+    let dev = if read_only { FileDevice::open_read_only(image) } else { FileDevice::open(image) };
+    let dev = dev.unwrap_or_else(|err| fail(&format!("cannot open {}: {}", image, err)));
+
+    StdInterface::install();
+    let fs = Xv6FS::open_with(Arc::new(dev), 1, MountOptions::new().read_only(read_only))
+        .unwrap_or_else(|err| fail(&format!("cannot open {}: {}", image, err)));
+
+    let (cmd, args) = matches.subcommand();
+    if let Err(err) = run(&fs, cmd, args.unwrap(), &mut stdout()) {
+        fail(&err);
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("xv6fs-tool: {}", msg);
+    exit(1)
+}
+
+fn run(fs: &Xv6FS, cmd: &str, args: &ArgMatches, out: &mut dyn Write) -> Result<(), String> {
+    let path = args.value_of("path").unwrap_or("/");
+    let fs_err = |err: FsError| format!("{}: {}", path, err);
+    match cmd {
+        "ls" => ls(fs, path, out).map_err(fs_err),
+        "cat" => {
+            let file = lookup(fs, path).map_err(fs_err)?;
+            copy_out(&file, out).map_err(|err| format!("{}: {}", path, err))
+        }
+        "put" => {
+            let host = args.value_of("host").unwrap();
+            let mut src = File::open(host).map_err(|err| format!("cannot open {}: {}", host, err))?;
+            put(fs, &mut src, path).map_err(|err| format!("{}: {}", path, err))
+        }
+        "get" => {
+            let host = args.value_of("host").unwrap();
+            let file = lookup(fs, path).map_err(fs_err)?;
+            let mut dst = File::create(host).map_err(|err| format!("cannot create {}: {}", host, err))?;
+            copy_out(&file, &mut dst).map_err(|err| format!("{}: {}", path, err))
+        }
+        "mkdir" => {
+            if lookup(fs, path).is_ok() {
+                return Err(fs_err(FsError::Exists));
+            }
+            VFile::vfile_create_dir(fs, path, true, true).map(drop).map_err(fs_err)
+        }
+        "rm" => rm(fs, path, args.is_present("recursive")).map_err(fs_err),
+        "stat" => stat(fs, path, out).map_err(fs_err),
+        "fsck" => fsck(fs, args.is_present("repair"), out).map_err(|err| format!("fsck: {}", err)),
+        "trim" => trim(fs, out).map_err(|err| format!("trim: {}", err)),
+        _ => unreachable!(),
+    }
+}
+
+/// Look up `path`, which may be the root itself.
+fn lookup(fs: &Xv6FS, path: &str) -> Result<VFile, FsError> {
+    if path.trim_matches('/').is_empty() {
+        return fs.get_root_vfile();
+    }
+    VFile::vfile_lookup(fs, path)
+}
+
+/// Entry names are padded with NULs on disk.
+fn trim_name(name: &str) -> &str {
+    name.trim_end_matches('\0')
+}
+
+fn ls(fs: &Xv6FS, path: &str, out: &mut dyn Write) -> Result<(), FsError> {
+    let file = lookup(fs, path)?;
+    if !file.vfile_is_dir() {
+        let _ = writeln!(out, "{}", path);
+        return Ok(());
+    }
+    for (name, itype) in file.vfile_pass_dir()? {
+        let suffix = if itype == InodeType::Directory { "/" } else { "" };
+        let _ = writeln!(out, "{}{}", trim_name(&name), suffix);
+    }
+    Ok(())
+}
+
+fn copy_out(file: &VFile, out: &mut dyn Write) -> Result<(), String> {
+    if file.vfile_is_dir() {
+        return Err(FsError::IsDir.to_string());
+    }
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut offset = 0;
+    loop {
+        let len = file.vfile_read(buf.as_mut_ptr() as usize, offset, COPY_CHUNK)
+            .map_err(|err| err.to_string())?;
+        if len == 0 {
+            return Ok(());
+        }
+        out.write_all(&buf[..len]).map_err(|err| err.to_string())?;
+        offset += len;
+    }
+}
+
+/// Create `path` if needed, then replace its content with `src`.
+fn put(fs: &Xv6FS, src: &mut dyn Read, path: &str) -> Result<(), String> {
+    if let Ok(file) = lookup(fs, path) {
+        if file.vfile_is_dir() {
+            return Err(FsError::IsDir.to_string());
+        }
+    }
+    let file = VFile::vfile_create_file(fs, path, true, true).map_err(|err| err.to_string())?;
+    file.vfile_truncate(0).map_err(|err| err.to_string())?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut offset = 0;
+    loop {
+        let len = src.read(&mut buf).map_err(|err| err.to_string())?;
+        if len == 0 {
+            return Ok(());
+        }
+        file.vfile_write_at(buf.as_ptr() as usize, offset, len).map_err(|err| err.to_string())?;
+        offset += len;
+    }
+}
+
+fn rm(fs: &Xv6FS, path: &str, recursive: bool) -> Result<(), FsError> {
+    let file = lookup(fs, path)?;
+    if file.vfile_is_dir() && !recursive {
+        return Err(FsError::IsDir);
+    }
+    drop(file);
+    fs.get_root_vfile()?.vfile_remove(path)
+}
+
+fn stat(fs: &Xv6FS, path: &str, out: &mut dyn Write) -> Result<(), FsError> {
+    let st = lookup(fs, path)?.vfile_stat()?;
+    let _ = writeln!(out, "  File: {}", path);
+    let _ = writeln!(out, "  Type: {:?}", st.itype);
+    let _ = writeln!(out, " Inode: {}", st.inum);
+    let _ = writeln!(out, " Links: {}", st.nlink);
+    let _ = writeln!(out, "  Size: {}", st.size);
+    Ok(())
+}
+
+/// Print the problems found, and with `repair` fix them and check again.
+fn fsck(fs: &Xv6FS, repair: bool, out: &mut dyn Write) -> Result<(), String> {
+    let found = if repair { fsck::repair(fs) } else { fsck::check(fs) };
+    let found = found.map_err(|err| err.to_string())?;
+    for problem in &found {
+        let _ = writeln!(out, "{}", problem);
+    }
+    let left = if repair {
+        fsck::check(fs).map_err(|err| err.to_string())?.len()
+    } else {
+        found.len()
+    };
+    if left > 0 {
+        return Err(format!("{} problems left", left));
+    }
+    if !found.is_empty() {
+        let _ = writeln!(out, "{} problems repaired", found.len());
+    }
+    Ok(())
+}
+
+/// Discard the free blocks and print how many there were.
+fn trim(fs: &Xv6FS, out: &mut dyn Write) -> Result<(), FsError> {
+    let trimmed = bitmap::trim(fs)?;
+    let _ = writeln!(out, "{} blocks trimmed", trimmed);
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_fs(image: &str) -> Arc<Xv6FS> {
+    StdInterface::install();
+    let dev = Arc::new(FileDevice::create(image, 4096).unwrap());
+    Xv6FS::create(dev, 1, xv6fs::FormatOptions::new(4096), MountOptions::new()).unwrap()
+}
+
+#[test]
+fn tool_test_put_get() {
+    let xfs = test_fs("target/tool_put_get.img");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    put(&xfs, &mut &data[..], "/big").unwrap();
+    // putting again replaces the old content
+    put(&xfs, &mut &data[..1000], "/big").unwrap();
+    let mut got = Vec::new();
+    copy_out(&VFile::vfile_lookup(&xfs, "/big").unwrap(), &mut got).unwrap();
+    assert_eq!(got, &data[..1000]);
+}
+
+#[test]
+fn tool_test_ls_rm() {
+    let xfs = test_fs("target/tool_ls_rm.img");
+    VFile::vfile_create_dir(&xfs, "/dir", true, true).unwrap();
+    put(&xfs, &mut &b"hello"[..], "/dir/a").unwrap();
+    let mut out = Vec::new();
+    ls(&xfs, "/", &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "./\n../\ndir/\n");
+    assert_eq!(rm(&xfs, "/dir", false).unwrap_err(), FsError::IsDir);
+    rm(&xfs, "/dir", true).unwrap();
+    assert_eq!(VFile::vfile_lookup(&xfs, "/dir/a").unwrap_err(), FsError::NotFound);
+}
+
+#[test]
+fn tool_test_fsck() {
+    use fsck::Problem;
+    let xfs = test_fs("target/tool_fsck.img");
+    VFile::vfile_create_dir(&xfs, "/dir", true, true).unwrap();
+    put(&xfs, &mut &b"hello"[..], "/dir/a").unwrap();
+    put(&xfs, &mut &[7u8; 2000][..], "/b").unwrap();
+    put(&xfs, &mut &b"orphan"[..], "/c").unwrap();
+    assert_eq!(fsck::check(&xfs).unwrap(), vec![]);
+
+    // free a block still in use, bump a link count,
+    // drop the only entry of /c and add an entry to a free inode
+    let tx = xfs.begin_op();
+    let b = xfs.icache.namei(b"/b").unwrap();
+    let bno = b.lock().unwrap().dinode.addrs[0];
+    xv6fs::bitmap::bfree(&xfs, bno).unwrap();
+    let a = xfs.icache.namei(b"/dir/a").unwrap();
+    let mut adata = a.lock().unwrap();
+    adata.dinode.nlink = 3;
+    adata.update().unwrap();
+    drop(adata);
+    let c = xfs.icache.namei(b"/c").unwrap().inum;
+    let root = xfs.icache.get_root_dir().unwrap();
+    let mut rdata = root.lock().unwrap();
+    rdata.dir_unlink(b"c").unwrap();
+    rdata.dir_link(b"ghost", 40).unwrap();
+    drop(rdata);
+    tx.end().unwrap();
+    drop((a, b, root));
+
+    let found = fsck::check(&xfs).unwrap();
+    assert!(found.contains(&Problem::MarkedFree { start: bno, len: 1 }));
+    assert!(found.contains(&Problem::LinkCount { inum: 3, nlink: 3, refs: 1 }));
+    assert!(found.contains(&Problem::Unreachable { inum: c, nlink: 1 }));
+    assert!(found.iter().any(|p| matches!(p, Problem::Dangling { inum: 40, .. })));
+
+    assert_eq!(fsck::repair(&xfs).unwrap(), found);
+    assert_eq!(fsck::check(&xfs).unwrap(), vec![]);
+    let mut got = Vec::new();
+    copy_out(&lookup(&xfs, &format!("/lost+found/#{}", c)).unwrap(), &mut got).unwrap();
+    assert_eq!(got, b"orphan");
+    assert!(lookup(&xfs, "/ghost").is_err());
+    assert_eq!(lookup(&xfs, "/dir/a").unwrap().vfile_stat().unwrap().nlink, 1);
+}
+
+#[test]
+fn tool_test_trim() {
+    let xfs = test_fs("target/tool_trim.img");
+    let free = |xfs: &Xv6FS| (xfs.sb.datastart()..xfs.sb.size())
+        .filter(|&b| !bitmap::bisalloc(xfs, b).unwrap())
+        .count();
+    put(&xfs, &mut &[7u8; 20000][..], "/a").unwrap();
+    put(&xfs, &mut &b"kept"[..], "/b").unwrap();
+    rm(&xfs, "/a", false).unwrap();
+    let mut out = Vec::new();
+    trim(&xfs, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), format!("{} blocks trimmed\n", free(&xfs)));
+    let mut got = Vec::new();
+    copy_out(&lookup(&xfs, "/b").unwrap(), &mut got).unwrap();
+    assert_eq!(got, b"kept");
+    assert_eq!(fsck::check(&xfs).unwrap(), vec![]);
+}
@@ -0,0 +1,387 @@
+//! FUSE binding of an xv6 file system.
+//!
+//! Every request is handled by a plain `do_*` method returning
+//! `Result<_, FsError>`, so the handlers can be tested without a mount;
+//! the `Filesystem` impl only turns those results into replies.
+//! FUSE inode numbers are xv6 inode numbers, and both use 1 for the root.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+};
+
+use xv6fs::disk_inode::InodeType;
+use xv6fs::file::VFile;
+use xv6fs::fs_const::{BSIZE, DIRSIZ};
+use xv6fs::inode::Inode;
+use xv6fs::{FsError, Xv6FS};
+
+/// How long the kernel may cache entries and attributes
+const TTL: Duration = Duration::from_secs(1);
+
+pub struct Xv6Fuse {
+    fs: Arc<Xv6FS>,
+    uid: u32,
+    gid: u32,
+}
+
+impl Xv6Fuse {
+    /// xv6 keeps no owners, so every file is reported as owned by uid:gid.
+    pub fn new(fs: Arc<Xv6FS>, uid: u32, gid: u32) -> Self {
+        Self { fs, uid, gid }
+    }
+
+    fn inode(&self, ino: u64) -> Result<Inode, FsError> {
+        if ino == 0 || ino >= self.fs.sb.ninodes() as u64 {
+            return Err(FsError::NotFound);
+        }
+        self.fs.icache.get(self.fs.dev, ino as u32)
+    }
+
+    fn name<'a>(&self, name: &'a OsStr) -> Result<&'a [u8], FsError> {
+        let name = name.as_bytes();
+        if name.len() >= DIRSIZ {
+            return Err(FsError::NameTooLong);
+        }
+        Ok(name)
+    }
+
+    /// Run a mutating request as one file system operation.
+    /// Inodes must be dropped inside `f`, so that freeing them is committed too.
+    fn op<T>(&self, f: impl FnOnce() -> Result<T, FsError>) -> Result<T, FsError> {
+        let tx = self.fs.begin_op();
+        let res = f();
+        tx.end()?;
+        res
+    }
+
+    fn attr(&self, inode: &Inode) -> Result<FileAttr, FsError> {
+        let idata = inode.lock()?;
+        let (kind, perm) = match idata.dinode.itype {
+            InodeType::Directory => (FileType::Directory, 0o755),
+            InodeType::Device => (FileType::CharDevice, 0o644),
+            _ => (FileType::RegularFile, 0o644),
+        };
+        let size = idata.dinode.size as u64;
+        Ok(FileAttr {
+            ino: inode.inum as u64,
+            size,
+            blocks: (size + 511) / 512,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: idata.dinode.nlink.max(0) as u32,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BSIZE as u32,
+            flags: 0,
+        })
+    }
+
+    pub fn do_lookup(&self, parent: u64, name: &OsStr) -> Result<FileAttr, FsError> {
+        let name = self.name(name)?;
+        let dir = self.inode(parent)?;
+        let inode = dir.lock()?.dir_lookup(name)?;
+        self.attr(&inode)
+    }
+
+    pub fn do_getattr(&self, ino: u64) -> Result<FileAttr, FsError> {
+        self.attr(&self.inode(ino)?)
+    }
+
+    /// Only the size can change, xv6 keeps no modes, owners or times.
+    pub fn do_setattr(&self, ino: u64, size: Option<u64>) -> Result<FileAttr, FsError> {
+        let inode = self.inode(ino)?;
+        if let Some(size) = size {
+            self.op(|| {
+                let mut idata = inode.lock()?;
+                if idata.dinode.itype == InodeType::Directory {
+                    return Err(FsError::IsDir);
+                }
+                idata.resize(&inode, size)?;
+                Ok(())
+            })?;
+        }
+        self.attr(&inode)
+    }
+
+    /// Directory entries from `offset` on, as (ino, next offset, kind, name).
+    pub fn do_readdir(&self, ino: u64, offset: i64) -> Result<Vec<(u64, i64, FileType, OsString)>, FsError> {
+        let dir = self.inode(ino)?;
+        let entries = dir.lock()?.dir_entries()?;
+        let mut v = Vec::new();
+        for (i, (inum, name)) in entries.into_iter().enumerate().skip(offset.max(0) as usize) {
+            let kind = match self.fs.icache.get_inum_type(self.fs.dev, inum)? {
+                InodeType::Directory => FileType::Directory,
+                InodeType::Device => FileType::CharDevice,
+                _ => FileType::RegularFile,
+            };
+            v.push((inum as u64, i as i64 + 1, kind, OsStr::from_bytes(&name).to_os_string()));
+        }
+        Ok(v)
+    }
+
+    pub fn do_open(&self, ino: u64) -> Result<(), FsError> {
+        self.inode(ino)?.lock()?;
+        Ok(())
+    }
+
+    pub fn do_read(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, FsError> {
+        let offset = u32::try_from(offset).map_err(|_| FsError::InvalidArg)?;
+        let inode = self.inode(ino)?;
+        let mut idata = inode.lock()?;
+        if idata.dinode.itype == InodeType::Directory {
+            return Err(FsError::IsDir);
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = idata.read(buf.as_mut_ptr() as usize, offset, size)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub fn do_write(&self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, FsError> {
+        let offset = usize::try_from(offset).map_err(|_| FsError::InvalidArg)?;
+        let file = VFile::from_inode(self.inode(ino)?)?;
+        if file.vfile_is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let len = file.vfile_write_at(data.as_ptr() as usize, offset, data.len())?;
+        Ok(len as u32)
+    }
+
+    fn do_make(&self, parent: u64, name: &OsStr, itype: InodeType) -> Result<FileAttr, FsError> {
+        let name = self.name(name)?;
+        let dir = self.inode(parent)?;
+        let inode = self.op(|| {
+            if dir.lock()?.dir_lookup(name).is_ok() {
+                return Err(FsError::Exists);
+            }
+            self.fs.icache.create_at(&dir, name, itype, 0, 0)
+        })?;
+        self.attr(&inode)
+    }
+
+    pub fn do_create(&self, parent: u64, name: &OsStr) -> Result<FileAttr, FsError> {
+        self.do_make(parent, name, InodeType::File)
+    }
+
+    pub fn do_mkdir(&self, parent: u64, name: &OsStr) -> Result<FileAttr, FsError> {
+        self.do_make(parent, name, InodeType::Directory)
+    }
+
+    /// Remove `name`, which must (`dir`) or must not be a directory.
+    fn do_remove(&self, parent: u64, name: &OsStr, dir: bool) -> Result<(), FsError> {
+        let name = self.name(name)?;
+        let parent = self.inode(parent)?;
+        self.op(|| {
+            let inode = parent.lock()?.dir_lookup(name)?;
+            let is_dir = inode.lock()?.dinode.itype == InodeType::Directory;
+            drop(inode);
+            match (is_dir, dir) {
+                (true, false) => Err(FsError::IsDir),
+                (false, true) => Err(FsError::NotDir),
+                _ => self.fs.icache.unlink_at(&parent, name),
+            }
+        })
+    }
+
+    pub fn do_unlink(&self, parent: u64, name: &OsStr) -> Result<(), FsError> {
+        self.do_remove(parent, name, false)
+    }
+
+    pub fn do_rmdir(&self, parent: u64, name: &OsStr) -> Result<(), FsError> {
+        self.do_remove(parent, name, true)
+    }
+
+    pub fn do_rename(&self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), FsError> {
+        let name = self.name(name)?;
+        let newname = self.name(newname)?;
+        let old_dir = self.inode(parent)?;
+        let new_dir = self.inode(newparent)?;
+        self.op(|| self.fs.icache.rename_at(&old_dir, name, &new_dir, newname))
+    }
+
+    pub fn do_link(&self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr, FsError> {
+        let newname = self.name(newname)?;
+        let inode = self.inode(ino)?;
+        let dir = self.inode(newparent)?;
+        self.op(|| self.fs.icache.link_at(&inode, &dir, newname))?;
+        self.attr(&inode)
+    }
+}
+
+impl Filesystem for Xv6Fuse {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.do_lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.do_getattr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.do_setattr(ino, size) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        match self.do_readdir(ino, offset) {
+            Ok(entries) => {
+                for (ino, next, kind, name) in entries {
+                    if reply.add(ino, next, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.do_open(ino) {
+            Ok(()) => reply.opened(0, 0),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.do_read(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.do_write(ino, offset, data) {
+            Ok(len) => reply.written(len),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.do_create(parent, name) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+        match self.do_mkdir(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_rename(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        match self.do_link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.fs.sync() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.to_errno()),
+        }
+    }
+
+    fn destroy(&mut self) {
+        let _ = self.fs.sync();
+    }
+}
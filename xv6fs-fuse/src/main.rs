@@ -2,67 +2,237 @@
 use axlog::{info, warn}; // Use log crate when building application
  
 #[cfg(test)]
-use std::{println as info}; // Workaround to use prinltn! for logs.use clap::{App, Arg};
-
-use xv6fs::bitmap::bfree;
-use xv6fs::fs_const::BSIZE;
-use xv6fs::inode::ICACHE;
-use xv6fs::log::LOG_MANAGER;
-use xv6fs::{BlockDevice,xv6fs::Xv6FS,disk_inode::DiskInode,log::LogHeader,buffer_cache::BLOCK_CACHE_MANAGER};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::{println as info, eprintln as warn}; // Workaround to use prinltn! for logs.
+
+mod fuse;
+
+use clap::{App, Arg};
+use fuser::MountOption;
+
+use xv6fs::host::{FileDevice, StdInterface};
+use xv6fs::xv6fs::Xv6FS;
+use xv6fs::{JournalMode, MountOptions};
+use std::os::unix::fs::MetadataExt;
+use std::process::exit;
 use std::sync::Arc;
-use std::sync::Mutex;
-
-/// Use a block size of 1024 bytes
-const BLOCK_SZ: usize = 1024;
-const BLOCK_NUM: usize = 131072; //64*2048
-
-struct BlockFile(Mutex<File>);
-
-impl BlockDevice for BlockFile {
-    /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        info!("read block {}",block_id);
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-        //info!("read block {} buf {:?}",block_id,buf);
+
+use fuse::Xv6Fuse;
+
+#[cfg(test)]
+use xv6fs::{FormatOptions,bitmap::{balloc, balloc_data, bfree},disk_inode::DiskInode,log::{CommitRecord, LogHeader},misc::crc32c};
+#[cfg(test)]
+use xv6fs::fs_const::{BSIZE, LOGSIZE};
+#[cfg(test)]
+use std::ffi::OsStr;
+#[cfg(test)]
+use std::collections::BTreeMap;
+
+/// Size of the images made by the tests, in blocks
+#[cfg(test)]
+const BLOCK_NUM: usize = 16384;
+
+fn main() {
+    let matches = App::new("xv6fs-fuse")
+        .about("Mount an xv6 file system image with FUSE")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Image to mount"))
+        .arg(Arg::with_name("mountpoint")
+            .required(true)
+            .help("Directory to mount it on"))
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .help("Mount read-only, leaving the image untouched"))
+        .arg(Arg::with_name("ro-on-corruption")
+            .long("ro-on-corruption")
+            .help("Go read-only once a corrupted structure is found"))
+        .arg(Arg::with_name("journal")
+            .long("journal")
+            .takes_value(true)
+            .possible_values(&["data", "ordered", "writeback"])
+            .default_value("data")
+            .help("What goes through the log besides metadata"))
+        .arg(Arg::with_name("cache")
+            .long("cache")
+            .takes_value(true)
+            .value_name("BLOCKS")
+            .help("Size of the buffer cache"))
+        .arg(Arg::with_name("write-back")
+            .long("write-back")
+            .help("Keep changed blocks in the cache until flushed"))
+        .arg(Arg::with_name("flush-interval")
+            .long("flush-interval")
+            .takes_value(true)
+            .value_name("MS")
+            .requires("write-back")
+            .help("How often the write-back cache is flushed, 0 for never"))
+        .arg(Arg::with_name("read-ahead")
+            .long("read-ahead")
+            .takes_value(true)
+            .value_name("BLOCKS")
+            .help("Blocks prefetched past a sequential read, 0 for none"))
+        .arg(Arg::with_name("discard")
+            .long("discard")
+            .help("Punch holes in the image for the blocks freed"))
+        .get_matches();
+    let image = matches.value_of("image").unwrap();
+    let mountpoint = matches.value_of("mountpoint").unwrap();
+
+    let read_only = matches.is_present("read-only");
+    let dev = if read_only { FileDevice::open_read_only(image) } else { FileDevice::open(image) };
+    let dev = dev
+        .unwrap_or_else(|err| fail(&format!("cannot open {}: {}", image, err)));
+    let meta = std::fs::metadata(mountpoint)
+        .unwrap_or_else(|err| fail(&format!("cannot stat {}: {}", mountpoint, err)));
+
+    StdInterface::install();
+    let journal = match matches.value_of("journal") {
+        Some("ordered") => JournalMode::Ordered,
+        Some("writeback") => JournalMode::Writeback,
+        _ => JournalMode::Data,
+    };
+    let mut opts = MountOptions::new()
+        .read_only(read_only)
+        .ro_on_corruption(matches.is_present("ro-on-corruption"))
+        .journal(journal);
+    if let Some(cache) = matches.value_of("cache") {
+        let blocks = cache.parse()
+            .unwrap_or_else(|_| fail(&format!("bad cache size {}", cache)));
+        opts = opts.cache_blocks(blocks);
+    }
+    opts = opts.write_back(matches.is_present("write-back"))
+        .discard(matches.is_present("discard"));
+    if let Some(interval) = matches.value_of("flush-interval") {
+        let ms = interval.parse()
+            .unwrap_or_else(|_| fail(&format!("bad flush interval {}", interval)));
+        opts = opts.flush_interval_ms(ms);
+    }
+    if let Some(read_ahead) = matches.value_of("read-ahead") {
+        let blocks = read_ahead.parse()
+            .unwrap_or_else(|_| fail(&format!("bad read-ahead {}", read_ahead)));
+        opts = opts.read_ahead(blocks);
+    }
+    let fs = Xv6FS::open_with(Arc::new(dev), 1, opts)
+        .unwrap_or_else(|err| fail(&format!("cannot mount {}: {}", image, err)));
+    info!("[Xv6fs] mount {} on {}", image, mountpoint);
+    let mut options = vec![MountOption::FSName("xv6fs".to_string())];
+    if read_only {
+        options.push(MountOption::RO);
     }
-    /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-        //info!("write block {} with buf {:?}",block_id,buf);
+    if let Err(err) = fuser::mount2(Xv6Fuse::new(fs, meta.uid(), meta.gid()), mountpoint, &options) {
+        warn!("[Xv6fs] mount: {}", err);
+        fail(&format!("cannot mount on {}: {}", mountpoint, err));
     }
 }
 
-fn main(){
+fn fail(msg: &str) -> ! {
+    eprintln!("xv6fs-fuse: {}", msg);
+    exit(1)
+}
 
+/// Format a fresh image for one test, tests run in parallel.
+#[cfg(test)]
+fn test_fs(image: &str) -> Arc<Xv6FS> {
+    StdInterface::install();
+    let dev = Arc::new(FileDevice::create(image, BLOCK_NUM as u32).unwrap());
+    Xv6FS::create(dev, 1, FormatOptions::new(BLOCK_NUM as u32), MountOptions::new()).unwrap()
+}
+
+/// Log a committed transaction straight into an image,
+/// as a crash right after its commit record leaves it.
+#[cfg(test)]
+fn log_by_hand(data: &mut [u8], (logstart, nlog): (u32, u32), seq: u32, blocks: &[(u32, Vec<u8>)]) {
+    let first=(logstart+LogHeader::blocks(nlog)) as usize;
+    let mut checksum=0;
+    for (i, (_, content)) in blocks.iter().enumerate() {
+        let b=(first+i)*BSIZE;
+        data[b..b+BSIZE].fill(0);
+        data[b..b+content.len()].copy_from_slice(content);
+        checksum=crc32c(checksum, &data[b..b+BSIZE]);
+    }
+    let blocknos:Vec<u32>=blocks.iter().map(|(bno, _)| *bno).collect();
+    LogHeader::new(seq, checksum, &blocknos).encode(&mut data[logstart as usize*BSIZE..]);
+    let c=(first+blocks.len())*BSIZE;
+    CommitRecord { seq, checksum }.encode(&mut data[c..c+BSIZE]);
+}
+
+/// Every file and directory below the root, with the content of files.
+#[cfg(test)]
+fn tree(xfs: &Xv6FS) -> BTreeMap<String, Option<Vec<u8>>> {
+    use xv6fs::file::VFile;
+    use xv6fs::disk_inode::InodeType;
+    let mut tree=BTreeMap::new();
+    let mut dirs=vec![String::new()];
+    while let Some(dir)=dirs.pop() {
+        let vdir=match dir.is_empty() {
+            true => xfs.get_root_vfile().unwrap(),
+            false => VFile::vfile_lookup(xfs, &dir).unwrap(),
+        };
+        for (name, itype) in vdir.vfile_pass_dir().unwrap() {
+            let name=name.trim_end_matches('\0');
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path=format!("{}/{}", dir, name);
+            if itype == InodeType::Directory {
+                dirs.push(path.clone());
+                tree.insert(path, None);
+                continue;
+            }
+            let file=VFile::vfile_lookup(xfs, &path).unwrap();
+            let mut buf=vec![0u8; file.vfile_size().unwrap()];
+            file.vfile_read(buf.as_mut_ptr() as usize, 0, buf.len()).unwrap();
+            tree.insert(path, Some(buf));
+        }
+    }
+    tree
+}
+
+/// Run `op` on a copy of `image` while recording its writes, then check
+/// that a crash at any point of them, or one losing a write not yet
+/// flushed, leaves a consistent file system showing the tree from
+/// before or after `op`. Unless `opts` journals data,
+/// file contents are written in place and only the names are compared.
+/// Returns the image after `op`.
+#[cfg(test)]
+fn crash_check(image: Vec<u8>, opts: MountOptions, what: &str, op: &dyn Fn(&Xv6FS)) -> Vec<u8> {
+    use xv6fs::crash::{crash_images, reordered_images, RecordingDevice};
+    use xv6fs::MemDevice;
+    let mount=|image: Vec<u8>| Xv6FS::open(Arc::new(MemDevice::from_image(image)), 1).unwrap();
+    let before=tree(&mount(image.clone()));
+    let mem=Arc::new(MemDevice::from_image(image.clone()));
+    let rec=Arc::new(RecordingDevice::new(mem.clone()));
+    let xfs=Xv6FS::open_with(rec.clone(), 1, opts).unwrap();
+    op(&xfs);
+    drop(xfs);
+    let flushes=rec.flushes();
+    let writes=rec.take_writes();
+    let after_image=mem.image();
+    let after=tree(&mount(after_image.clone()));
+    assert_ne!(before, after, "{} changed nothing", what);
+    for crash in crash_images(&image, &writes).chain(reordered_images(&image, &writes, &flushes)) {
+        let xfs=mount(crash.image);
+        let at=format!("{:?} {} crashed after {} of {} writes, torn {:?}, lost {:?}",
+            opts.journal, what, crash.writes, writes.len(), crash.torn, crash.lost);
+        assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![], "{}", at);
+        let now=tree(&xfs);
+        let same=|tree: &BTreeMap<_, _>| match opts.journal {
+            JournalMode::Data => now == *tree,
+            _ => now.keys().eq(tree.keys()),
+        };
+        assert!(same(&before) || same(&after), "{}: {:?}", at, now.keys().collect::<Vec<_>>());
+    }
+    after_image
 }
 
 #[test]
 fn xv6fs_test_create() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,size_of::<DiskInode>(),size_of::<LogHeader>());
-    let mut xfs=Xv6FS::new();
+    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,DiskInode::SIZE,LogHeader::size(LOGSIZE as u32));
     //xfs.create(block_file.clone());
-    unsafe{xv6fs::init(block_file.clone(), 1);}
-    let root_inode=xfs.get_root_inode();
+    let xfs=test_fs("target/create.img");
+    let root_inode=xfs.get_root_inode().unwrap();
     info!("root inode is {:?}",root_inode);
-    let mut root_data=root_inode.lock();
+    let mut root_data=root_inode.lock().unwrap();
     info!("root get locked");
     let dir_list=root_data.ls().unwrap();
     info!("{:?}",dir_list);
@@ -71,90 +241,63 @@ fn xv6fs_test_create() -> std::io::Result<()> {
     let path2:&[u8]=b"/test1\0\0";
     let path3:&[u8]=b"/test2\0\0";
     let path4:&[u8]=b"/testdir\0\0";
-    let mut test_inode=ICACHE.create(&path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut test_inode2=ICACHE.create(&path2, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut test_inode3=ICACHE.create(&path3, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut test_inode4=ICACHE.create(&path4, xv6fs::disk_inode::InodeType::Directory, 2, 1).unwrap();
+    let tx=xfs.begin_op();
+    let mut test_inode=xfs.icache.create(&path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    let mut test_inode2=xfs.icache.create(&path2, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    let mut test_inode3=xfs.icache.create(&path3, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    let mut test_inode4=xfs.icache.create(&path4, xv6fs::disk_inode::InodeType::Directory, 2, 1).unwrap();
     let path5:&[u8]=b"/testdir/test7\0\0\0\0";
-    let mut test_inode5=ICACHE.create(path5,xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut root_data=root_inode.lock();
+    let mut test_inode5=xfs.icache.create(path5,xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    let mut root_data=root_inode.lock().unwrap();
     let dir_list=root_data.ls().unwrap();
     info!("{:?}",dir_list);
     drop(root_data);
-    let mut test_data=test_inode4.lock();
+    let mut test_data=test_inode4.lock().unwrap();
     let dir_list=test_data.ls().unwrap();
     info!("{:?}",dir_list);
-    LOG_MANAGER.end_op();
+    drop(test_data);
+    tx.end().unwrap();
     Ok(())
 }
 
 #[test]
 fn xv6fs_log_delete() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,size_of::<DiskInode>(),size_of::<LogHeader>());
-    let mut xfs=Xv6FS::new();
+    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,DiskInode::SIZE,LogHeader::size(LOGSIZE as u32));
     //xfs.create(block_file.clone());
-    unsafe{xv6fs::init(block_file.clone(), 1);}
-    let root_inode=xfs.get_root_inode();
+    let xfs=test_fs("target/log_delete.img");
+    let root_inode=xfs.get_root_inode().unwrap();
     //info!("root inode is {:?}",root_inode);
-    let mut root_data=root_inode.lock();
+    let mut root_data=root_inode.lock().unwrap();
     let dir_list=root_data.ls().unwrap();
     info!("{:?}",dir_list);
     drop(root_data);
-    let mut buf = BLOCK_CACHE_MANAGER.bread(0, 2);
-    let raw_lh = buf.raw_data_mut() as *mut LogHeader;
-    info!("log header is {:?}",unsafe{raw_lh.as_ref().unwrap()});
+    let buf = xfs.bcache.bread(0, 2).unwrap();
+    let lh = LogHeader::decode(buf.data(), LOGSIZE - 2);
+    info!("log header is {:?}",lh);
     Ok(())
 }
 
 #[test]
 fn xv6fs_test_write() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let mut xfs=Xv6FS::new();
-    unsafe{xv6fs::init(block_file.clone(), 1);}
+    let xfs=test_fs("target/write.img");
     let path:&[u8]=b"/test\0\0\0";
-    let mut inode=ICACHE.create(path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut inode_data=inode.lock();
+    let tx=xfs.begin_op();
+    let mut inode=xfs.icache.create(path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    let mut inode_data=inode.lock().unwrap();
     let buf:&[u8]=b"1919810";
-    inode_data.write(buf.as_ptr() as usize, 0, 7);
+    inode_data.write(buf.as_ptr() as usize, 0, 7).unwrap();
     drop(inode_data);
     drop(inode);
-    LOG_MANAGER.end_op();
+    tx.end().unwrap();
     Ok(())
 }
 
 #[test]
 fn xv6fs_ls_root() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let mut xfs=Xv6FS::new();
-    unsafe{xv6fs::init(block_file.clone(), 1);}
-    let root_inode=xfs.get_root_inode();
+    let xfs=test_fs("target/ls_root.img");
+    let root_inode=xfs.get_root_inode().unwrap();
     //info!("root inode is {:?}",root_inode);
-    let mut root_data=root_inode.lock();
+    let mut root_data=root_inode.lock().unwrap();
     let dir_list=root_data.ls().unwrap();
     info!("{:?}",dir_list);
     drop(root_data);
@@ -163,24 +306,16 @@ fn xv6fs_ls_root() -> std::io::Result<()> {
 
 #[test]
 fn xv6fs_test_read() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,size_of::<DiskInode>(),size_of::<LogHeader>());
-    let mut xfs=Xv6FS::new();
+    info!("block size:{}, disk inode size:{}, log header size:{}",BSIZE,DiskInode::SIZE,LogHeader::size(LOGSIZE as u32));
     //xfs.create(block_file.clone());
-    unsafe{xv6fs::init(block_file.clone(), 1);}
+    let xfs=test_fs("target/read.img");
     let path:&[u8]=b"/test\0\0\0";
-    let mut inode=ICACHE.create(path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
-    let mut inode_data=inode.lock();
+    let tx=xfs.begin_op();
+    let mut inode=xfs.icache.create(path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap();
+    tx.end().unwrap();
+    let mut inode_data=inode.lock().unwrap();
     let mut buf:[u8;10]=[0;10];
-    inode_data.read(buf.as_mut_ptr() as usize, 0, 6);
+    inode_data.read(buf.as_mut_ptr() as usize, 0, 6).unwrap();
     drop(inode_data);
     drop(inode);
     info!("buf is {:?}",String::from_utf8(buf.to_vec()).unwrap());
@@ -195,19 +330,11 @@ fn xv6fs_test_read() -> std::io::Result<()> {
 
 #[test]
 fn xv6fs_test_bdealloc() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let mut xfs=Xv6FS::new();
-    unsafe{xv6fs::init(block_file.clone(), 1);}
-    bfree(47);
-    LOG_MANAGER.end_op();
+    let xfs=test_fs("target/bdealloc.img");
+    let tx=xfs.begin_op();
+    let bno=balloc(&xfs, 1).unwrap();
+    bfree(&xfs, bno).unwrap();
+    tx.end().unwrap();
     Ok(())
     //获取root节点,ok
     //写入文件,ok
@@ -219,54 +346,909 @@ fn xv6fs_test_bdealloc() -> std::io::Result<()> {
 
 #[test]
 fn xv6fs_test_remove()->std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let mut xfs=Xv6FS::new();
-    unsafe{xv6fs::init(block_file.clone(), 1);}
+    let xfs=test_fs("target/remove.img");
     let path:&[u8]=b"/test\0\0\0";
-    let rinode=ICACHE.get_root_dir();
-    ICACHE.remove(path);
-    let mut rdata=rinode.lock();
+    let tx=xfs.begin_op();
+    drop(xfs.icache.create(path, xv6fs::disk_inode::InodeType::File, 2, 1).unwrap());
+    let rinode=xfs.icache.get_root_dir().unwrap();
+    xfs.icache.remove(path).unwrap();
+    let mut rdata=rinode.lock().unwrap();
     //rdata.dir_unlink(path);
     let dir_list=rdata.ls().unwrap();
     info!("{:?}",dir_list);
     drop(rdata);
     drop(rinode);
-    LOG_MANAGER.end_op();
+    tx.end().unwrap();
     //目录的nlink还没有处理
     Ok(())
 }
 
 #[test]
 fn xv6fs_test_remove_dir()->std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let mut xfs=Xv6FS::new();
-    unsafe{xv6fs::init(block_file.clone(), 1);}
+    let xfs=test_fs("target/remove_dir.img");
     let path:&[u8]=b"/testdir\0\0\0";
-    let rinode=ICACHE.get_root_dir();
-    ICACHE.remove(path);
-    let mut rdata=rinode.lock();
+    let tx=xfs.begin_op();
+    drop(xfs.icache.create(path, xv6fs::disk_inode::InodeType::Directory, 2, 1).unwrap());
+    let rinode=xfs.icache.get_root_dir().unwrap();
+    xfs.icache.remove(path).unwrap();
+    let mut rdata=rinode.lock().unwrap();
     //rdata.dir_unlink(path);
     let dir_list=rdata.ls().unwrap();
     info!("{:?}",dir_list);
     drop(rdata);
     drop(rinode);
-    LOG_MANAGER.end_op();
+    tx.end().unwrap();
     //目录的nlink还没有处理
     Ok(())
-}
\ No newline at end of file
+}
+#[test]
+fn xv6fuse_test_file_io() {
+    let xfs=test_fs("target/fuse_file_io.img");
+    let fuse=Xv6Fuse::new(xfs, 0, 0);
+    let attr=fuse.do_create(1, OsStr::new("hello")).unwrap();
+    assert_eq!(attr.size, 0);
+    assert_eq!(fuse.do_lookup(1, OsStr::new("hello")).unwrap().ino, attr.ino);
+    // unaligned write across several transactions
+    let data:Vec<u8>=(0..5000u32).map(|i| i as u8).collect();
+    assert_eq!(fuse.do_write(attr.ino, 100, &data).unwrap(), 5000);
+    assert_eq!(fuse.do_getattr(attr.ino).unwrap().size, 5100);
+    assert_eq!(fuse.do_read(attr.ino, 100, 5000).unwrap(), data);
+    assert_eq!(fuse.do_read(attr.ino, 0, 100).unwrap(), vec![0u8;100]);
+    assert_eq!(fuse.do_read(attr.ino, 5000, 1000).unwrap().len(), 100);
+    // shrink then grow again, the tail reads back as zeros
+    assert_eq!(fuse.do_setattr(attr.ino, Some(600)).unwrap().size, 600);
+    assert_eq!(fuse.do_setattr(attr.ino, Some(2000)).unwrap().size, 2000);
+    let buf=fuse.do_read(attr.ino, 0, 2000).unwrap();
+    assert_eq!(&buf[100..600], &data[..500]);
+    assert!(buf[600..].iter().all(|&b| b == 0));
+    assert_eq!(fuse.do_create(1, OsStr::new("hello")).unwrap_err(), xv6fs::FsError::Exists);
+}
+
+#[test]
+fn xv6fuse_test_namespace() {
+    let xfs=test_fs("target/fuse_namespace.img");
+    let fuse=Xv6Fuse::new(xfs, 0, 0);
+    let dir=fuse.do_mkdir(1, OsStr::new("dir")).unwrap();
+    let file=fuse.do_create(dir.ino, OsStr::new("a")).unwrap();
+    fuse.do_write(file.ino, 0, b"1919810").unwrap();
+    let names=|ino| -> Vec<String> {
+        fuse.do_readdir(ino, 0).unwrap().into_iter()
+            .map(|(_, _, _, name)| name.into_string().unwrap()).collect()
+    };
+    assert_eq!(names(dir.ino), vec![".", "..", "a"]);
+    // readdir resumes after the offset it handed out
+    let entries=fuse.do_readdir(dir.ino, 0).unwrap();
+    assert_eq!(fuse.do_readdir(dir.ino, entries[1].1).unwrap().len(), 1);
+
+    assert_eq!(fuse.do_link(file.ino, 1, OsStr::new("b")).unwrap().nlink, 2);
+    fuse.do_rename(dir.ino, OsStr::new("a"), 1, OsStr::new("c")).unwrap();
+    assert!(names(dir.ino).len() == 2);
+    assert_eq!(fuse.do_read(fuse.do_lookup(1, OsStr::new("c")).unwrap().ino, 0, 16).unwrap(), b"1919810");
+
+    assert_eq!(fuse.do_rmdir(1, OsStr::new("c")).unwrap_err(), xv6fs::FsError::NotDir);
+    assert_eq!(fuse.do_unlink(1, OsStr::new("dir")).unwrap_err(), xv6fs::FsError::IsDir);
+    fuse.do_unlink(1, OsStr::new("c")).unwrap();
+    assert_eq!(fuse.do_getattr(file.ino).unwrap().nlink, 1);
+    fuse.do_unlink(1, OsStr::new("b")).unwrap();
+    assert_eq!(fuse.do_getattr(file.ino).unwrap_err(), xv6fs::FsError::NotFound);
+
+    // a moved directory stays reachable through its new parent
+    let sub=fuse.do_mkdir(dir.ino, OsStr::new("sub")).unwrap();
+    assert_eq!(fuse.do_rmdir(1, OsStr::new("dir")).unwrap_err(), xv6fs::FsError::NotEmpty);
+    fuse.do_rename(dir.ino, OsStr::new("sub"), 1, OsStr::new("top")).unwrap();
+    assert_eq!(fuse.do_lookup(sub.ino, OsStr::new("..")).unwrap().ino, 1);
+    fuse.do_rmdir(1, OsStr::new("dir")).unwrap();
+    fuse.do_rmdir(1, OsStr::new("top")).unwrap();
+    assert_eq!(names(1), vec![".", ".."]);
+}
+
+#[test]
+fn xv6fs_test_threads() {
+    let xfs=test_fs("target/threads.img");
+    xv6fs::file::VFile::vfile_create_dir(&xfs, "/dir", true, true).unwrap();
+    for (i, path) in ["/f", "/dir/f"].iter().enumerate() {
+        let file=xv6fs::file::VFile::vfile_create_file(&xfs, path, true, true).unwrap();
+        let data=vec![i as u8; 12000];
+        file.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+    }
+    let handles:Vec<_>=(0..4usize).map(|i| {
+        let xfs=xfs.clone();
+        std::thread::spawn(move || {
+            // relative paths start from the directory of each thread
+            let cwd=if i % 2 == 0 { "/" } else { "/dir" };
+            StdInterface::set_cur_dir(Some(xfs.icache.namei(cwd.as_bytes()).unwrap()));
+            for _ in 0..20 {
+                let file=xv6fs::file::VFile::vfile_lookup(&xfs, "f").unwrap();
+                let mut buf=vec![0xffu8; 12000];
+                assert_eq!(file.vfile_read(buf.as_mut_ptr() as usize, 0, buf.len()).unwrap(), 12000);
+                assert!(buf.iter().all(|&b| b as usize == i % 2));
+            }
+            StdInterface::set_cur_dir(None);
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+}
+
+#[test]
+fn xv6fs_test_concurrent_writes() {
+    use xv6fs::file::VFile;
+    let image="target/concurrent.img";
+    let xfs=test_fs(image);
+    let handles:Vec<_>=(0..4usize).map(|i| {
+        let xfs=xfs.clone();
+        std::thread::spawn(move || {
+            for j in 0..5 {
+                let path=format!("/t{}_{}", i, j);
+                let file=VFile::vfile_create_file(&xfs, &path, true, true).unwrap();
+                let data=vec![(i * 5 + j) as u8; 3000];
+                file.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+            }
+            xfs.get_root_vfile().unwrap().vfile_remove(&format!("/t{}_0", i)).unwrap();
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    drop(xfs);
+
+    // every op committed, and they did not mix up their blocks
+    let xfs=Xv6FS::open(Arc::new(FileDevice::open(image).unwrap()), 1).unwrap();
+    for i in 0..4usize {
+        assert!(VFile::vfile_lookup(&xfs, &format!("/t{}_0", i)).is_err());
+        for j in 1..5 {
+            let file=VFile::vfile_lookup(&xfs, &format!("/t{}_{}", i, j)).unwrap();
+            let mut buf=vec![0u8; 3000];
+            assert_eq!(file.vfile_read(buf.as_mut_ptr() as usize, 0, buf.len()).unwrap(), 3000);
+            assert!(buf.iter().all(|&b| b as usize == i * 5 + j));
+            assert_eq!(file.vfile_offset(), 3000);
+        }
+    }
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+}
+
+#[test]
+fn xv6fs_test_large_log() {
+    use xv6fs::file::VFile;
+    let image="target/large_log.img";
+    StdInterface::install();
+    let dev=Arc::new(FileDevice::create(image, BLOCK_NUM as u32).unwrap());
+    let xfs=Xv6FS::create(dev, 1, FormatOptions::new(BLOCK_NUM as u32).log_blocks(1000), MountOptions::new()).unwrap();
+    assert_eq!(LogHeader::blocks(1000), 8);
+    let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    file.vfile_write([5u8; 3000].as_ptr() as usize, 3000).unwrap();
+    let log=xfs.sb.read_log();
+    let datastart=xfs.sb.datastart() as usize;
+    drop(file);
+    drop(xfs);
+
+    // a committed transaction of 300 blocks, its header spans 3 blocks
+    let mut data=std::fs::read(image).unwrap();
+    let blocks:Vec<_>=(0..300).map(|i| ((datastart + 1000 + i) as u32, vec![i as u8; BSIZE])).collect();
+    log_by_hand(&mut data, log, 1, &blocks);
+    std::fs::write(image, &data).unwrap();
+    let lh=log.0 as usize*BSIZE;
+
+    let xfs=Xv6FS::open(Arc::new(FileDevice::open(image).unwrap()), 1).unwrap();
+    let mut buf=[0u8; 3000];
+    VFile::vfile_lookup(&xfs, "/f").unwrap().vfile_read(buf.as_mut_ptr() as usize, 0, 3000).unwrap();
+    assert!(buf.iter().all(|&b| b == 5));
+    drop(xfs);
+    let data=std::fs::read(image).unwrap();
+    assert_eq!(&data[lh..lh+4], &[0, 0, 0, 0]);
+    for i in 0..300 {
+        let home=(datastart + 1000 + i)*BSIZE;
+        assert!(data[home..home+BSIZE].iter().all(|&b| b == i as u8));
+    }
+}
+
+#[test]
+fn xv6fs_test_layout() {
+    let xfs=test_fs("target/layout.img");
+    let file=xv6fs::file::VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    file.vfile_write(b"x".as_ptr() as usize, 1).unwrap();
+    let istart=xfs.sb.inodestart() as usize;
+    drop(file);
+    drop(xfs);
+    // the image reads the same on any host: every field is little-endian
+    let image=std::fs::read("target/layout.img").unwrap();
+    assert_eq!(&image[BSIZE..BSIZE+4], &[0x40, 0x30, 0x20, 0x10]);
+    assert_eq!(&image[BSIZE+4..BSIZE+8], &(BLOCK_NUM as u32).to_le_bytes());
+    let root=&image[istart*BSIZE+DiskInode::SIZE..istart*BSIZE+2*DiskInode::SIZE];
+    assert_eq!(&root[0..2], &[1, 0]);
+    assert_eq!(&root[8..12], &[48, 0, 0, 0]);
+    let dinode=DiskInode::decode(root).unwrap();
+    let dirents=&image[dinode.addrs[0] as usize*BSIZE..];
+    assert_eq!(&dirents[32..36], &[2, 0, b'f', 0]);
+    let f=&image[istart*BSIZE+2*DiskInode::SIZE..istart*BSIZE+3*DiskInode::SIZE];
+    assert_eq!(&f[0..2], &[2, 0]);
+    assert_eq!(&f[8..12], &[1, 0, 0, 0]);
+}
+
+#[test]
+fn xv6fs_test_corrupted() {
+    use xv6fs::FsError;
+    let image="target/corrupted.img";
+    let xfs=test_fs(image);
+    let file=xv6fs::file::VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    file.vfile_write(b"hello".as_ptr() as usize, 5).unwrap();
+    let istart=xfs.sb.inodestart() as usize;
+    let root_block=xfs.sb.datastart() as usize;
+    drop(file);
+    drop(xfs);
+    let good=std::fs::read(image).unwrap();
+    let mount=|data: &[u8], opts: MountOptions| {
+        std::fs::write(image, data).unwrap();
+        Xv6FS::open_with(Arc::new(FileDevice::open(image).unwrap()), 1, opts)
+    };
+
+    // a log larger than the in-memory header
+    let mut bad=good.clone();
+    bad[BSIZE+16..BSIZE+20].copy_from_slice(&1000u32.to_le_bytes());
+    assert_eq!(mount(&bad, MountOptions::new()).err(), Some(FsError::Corrupted));
+
+    // a block number inside the inode table flips the mount read-only
+    let mut bad=good.clone();
+    let f=istart*BSIZE+2*DiskInode::SIZE;
+    bad[f+12..f+16].copy_from_slice(&(istart as u32).to_le_bytes());
+    let xfs=mount(&bad, MountOptions::new().ro_on_corruption(true)).unwrap();
+    let inode=xfs.icache.namei(b"/f").unwrap();
+    assert_eq!(inode.lock().err(), Some(FsError::Corrupted));
+    assert!(xfs.is_read_only());
+    assert_eq!(xv6fs::file::VFile::vfile_create_file(&xfs, "/g", true, true).err(), Some(FsError::ReadOnly));
+    drop(inode);
+    drop(xfs);
+    assert_eq!(std::fs::read(image).unwrap(), bad);
+
+    // an unknown type, and an entry past the inode table
+    let mut bad=good.clone();
+    bad[f..f+2].copy_from_slice(&[9, 0]);
+    bad[root_block*BSIZE+32..root_block*BSIZE+34].copy_from_slice(&60000u16.to_le_bytes());
+    let xfs=mount(&bad, MountOptions::new()).unwrap();
+    assert_eq!(xfs.icache.get(1, 2).unwrap().lock().err(), Some(FsError::Corrupted));
+    assert_eq!(xfs.icache.namei(b"/g").err(), Some(FsError::Corrupted));
+    assert!(!xfs.is_read_only());
+}
+
+#[test]
+fn xv6fs_test_read_only() {
+    use xv6fs::FsError;
+    use xv6fs::file::VFile;
+    let image="target/read_only.img";
+    let xfs=test_fs(image);
+    let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    file.vfile_write(b"hello".as_ptr() as usize, 5).unwrap();
+    let bno=file.inode().unwrap().lock().unwrap().dinode.addrs[0] as usize;
+    let log=xfs.sb.read_log();
+    drop(file);
+    drop(xfs);
+
+    // a committed transaction that was never installed
+    let mut data=std::fs::read(image).unwrap();
+    log_by_hand(&mut data, log, 1, &[(bno as u32, b"HELLO".to_vec())]);
+    std::fs::write(image, &data).unwrap();
+
+    let read=|xfs: &Xv6FS| {
+        let file=VFile::vfile_lookup(xfs, "/f").unwrap();
+        let mut buf=[0u8; 5];
+        file.vfile_read(buf.as_mut_ptr() as usize, 0, 5).unwrap();
+        buf
+    };
+    let dev=Arc::new(FileDevice::open_read_only(image).unwrap());
+    let xfs=Xv6FS::open_with(dev, 1, MountOptions::new().read_only(true)).unwrap();
+    assert_eq!(&read(&xfs), b"hello");
+    assert_eq!(VFile::vfile_create_file(&xfs, "/g", true, true).err(), Some(FsError::ReadOnly));
+    let file=VFile::vfile_lookup(&xfs, "/f").unwrap();
+    assert_eq!(file.vfile_write(b"x".as_ptr() as usize, 1).err(), Some(FsError::ReadOnly));
+    assert_eq!(file.vfile_truncate(0).err(), Some(FsError::ReadOnly));
+    assert_eq!(file.vfile_remove("/f").err(), Some(FsError::ReadOnly));
+    drop(file);
+    drop(xfs);
+    assert_eq!(std::fs::read(image).unwrap(), data);
+
+    // a writable mount replays it
+    let xfs=Xv6FS::open(Arc::new(FileDevice::open(image).unwrap()), 1).unwrap();
+    assert_eq!(&read(&xfs), b"HELLO");
+
+    // a change the log refuses is not left in the cache
+    let tx=xfs.begin_op();
+    let bno=balloc(&xfs, 1).unwrap();
+    tx.end().unwrap();
+    xfs.log.set_read_only();
+    let tx=xfs.begin_op();
+    assert_eq!(bfree(&xfs, bno).err(), Some(FsError::ReadOnly));
+    tx.end().unwrap();
+    assert!(xv6fs::bitmap::bisalloc(&xfs, bno).unwrap());
+}
+
+#[test]
+fn xv6fs_test_torn_log() {
+    use xv6fs::file::VFile;
+    let image="target/torn_log.img";
+    let xfs=test_fs(image);
+    let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    file.vfile_write(b"hello".as_ptr() as usize, 5).unwrap();
+    let bno=file.inode().unwrap().lock().unwrap().dinode.addrs[0];
+    let log=xfs.sb.read_log();
+    drop(file);
+    drop(xfs);
+    let good=std::fs::read(image).unwrap();
+    let lh=log.0 as usize*BSIZE;
+    let first=(log.0+LogHeader::blocks(log.1)) as usize*BSIZE;
+
+    // only the intact transaction is installed, the torn ones are discarded
+    for tear in 0..4 {
+        let mut data=good.clone();
+        log_by_hand(&mut data, log, 9, &[(bno, b"HELLO".to_vec())]);
+        match tear {
+            0 => {}
+            // a logged block torn
+            1 => data[first+100]^=1,
+            // no commit record
+            2 => data[first+BSIZE..first+2*BSIZE].fill(0),
+            // the header torn
+            _ => data[lh+16]^=1,
+        }
+        std::fs::write(image, &data).unwrap();
+        let xfs=Xv6FS::open(Arc::new(FileDevice::open(image).unwrap()), 1).unwrap();
+        let file=VFile::vfile_lookup(&xfs, "/f").unwrap();
+        let mut buf=[0u8; 5];
+        file.vfile_read(buf.as_mut_ptr() as usize, 0, 5).unwrap();
+        assert_eq!(&buf, if tear == 0 { b"HELLO" } else { b"hello" });
+        drop(file);
+        drop(xfs);
+        assert_eq!(&std::fs::read(image).unwrap()[lh..lh+4], &[0; 4]);
+    }
+}
+
+#[test]
+fn xv6fs_test_ordered() {
+    use xv6fs::file::VFile;
+    let image="target/ordered.img";
+    drop(test_fs(image));
+    let mount=|journal| {
+        let opts=MountOptions::new().journal(journal);
+        Xv6FS::open_with(Arc::new(FileDevice::open(image).unwrap()), 1, opts).unwrap()
+    };
+
+    // a block freed by a transaction is kept from file data until it commits
+    for journal in [JournalMode::Data, JournalMode::Ordered, JournalMode::Writeback] {
+        let xfs=mount(journal);
+        let tx=xfs.begin_op();
+        let bno=balloc(&xfs, 1).unwrap();
+        tx.end().unwrap();
+        let tx=xfs.begin_op();
+        bfree(&xfs, bno).unwrap();
+        let data=balloc_data(&xfs, 1).unwrap();
+        assert_eq!(data == bno, journal == JournalMode::Data);
+        bfree(&xfs, data).unwrap();
+        tx.end().unwrap();
+        let tx=xfs.begin_op();
+        assert_eq!(balloc_data(&xfs, 1).unwrap(), bno);
+        bfree(&xfs, bno).unwrap();
+        tx.end().unwrap();
+    }
+
+    // large writes go in place, in transactions of NINDIRECT blocks
+    let xfs=mount(JournalMode::Ordered);
+    let data:Vec<u8>=(0..300_000).map(|i| (i % 251) as u8).collect();
+    let file=VFile::vfile_create_file(&xfs, "/big", true, true).unwrap();
+    assert_eq!(file.vfile_write(data.as_ptr() as usize, data.len()).unwrap(), data.len());
+    file.vfile_truncate(100_000).unwrap();
+    drop(file);
+    let file=VFile::vfile_create_file(&xfs, "/ro", true, false).unwrap();
+    assert_eq!(file.vfile_truncate(0).err(), Some(xv6fs::FsError::BadFile));
+    drop(file);
+    xfs.get_root_vfile().unwrap().vfile_unlink("/ro").unwrap();
+    drop(xfs);
+    let xfs=mount(JournalMode::Data);
+    let file=VFile::vfile_lookup(&xfs, "/big").unwrap();
+    let mut buf=vec![0u8; 100_000];
+    assert_eq!(file.vfile_read(buf.as_mut_ptr() as usize, 0, buf.len()).unwrap(), buf.len());
+    assert_eq!(&buf[..], &data[..100_000]);
+    drop(file);
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+}
+
+#[test]
+fn xv6fs_test_crash() {
+    use xv6fs::file::VFile;
+    use xv6fs::disk_inode::InodeType;
+    StdInterface::install();
+    let dev=xv6fs::MemDevice::new(1000);
+    xv6fs::format(&dev, FormatOptions::new(1000)).unwrap();
+    let mut image=dev.image();
+    let data:Vec<u8>=(0..1500).map(|i| (i % 253) as u8).collect();
+    let ops:Vec<(&str, Box<dyn Fn(&Xv6FS)>)>=vec![
+        ("mkdir", Box::new(|xfs| { VFile::vfile_create_dir(xfs, "/d", true, true).unwrap(); })),
+        ("create", Box::new(|xfs| { VFile::vfile_create_file(xfs, "/d/f", true, true).unwrap(); })),
+        ("write", Box::new(|xfs| {
+            let file=VFile::vfile_lookup(xfs, "/d/f").unwrap();
+            file.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+        })),
+        ("link", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_link("/d/f", "/g").unwrap())),
+        ("rename", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_rename("/g", "h").unwrap())),
+        ("truncate", Box::new(|xfs| { VFile::vfile_lookup(xfs, "/d/f").unwrap().vfile_truncate(100).unwrap(); })),
+        ("unlink", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_unlink("/h").unwrap())),
+        ("unlink last", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_unlink("/d/f").unwrap())),
+        ("create_at", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            xfs.icache.create_at(&root, b"x", InodeType::File, 2, 1).unwrap();
+            tx.end().unwrap();
+        })),
+        ("link_at", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            let x=xfs.icache.namei(b"/x").unwrap();
+            xfs.icache.link_at(&x, &root, b"y").unwrap();
+            tx.end().unwrap();
+        })),
+        ("rename_at", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            let d=xfs.icache.namei(b"/d").unwrap();
+            xfs.icache.rename_at(&root, b"x", &d, b"z").unwrap();
+            tx.end().unwrap();
+        })),
+        ("unlink_at", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            xfs.icache.unlink_at(&root, b"y").unwrap();
+            tx.end().unwrap();
+        })),
+        ("create under dir", Box::new(|xfs| {
+            xfs.get_root_vfile().unwrap().vfile_create_under_dir("w", InodeType::File).unwrap();
+        })),
+        ("rename_at over", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            let d=xfs.icache.namei(b"/d").unwrap();
+            xfs.icache.rename_at(&root, b"w", &d, b"z").unwrap();
+            tx.end().unwrap();
+        })),
+        ("unlink_at last", Box::new(|xfs| {
+            let tx=xfs.begin_op();
+            let d=xfs.icache.namei(b"/d").unwrap();
+            xfs.icache.unlink_at(&d, b"z").unwrap();
+            tx.end().unwrap();
+        })),
+        ("rmdir", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_remove("/d").unwrap())),
+        ("create in an unflushed block", Box::new(|xfs| {
+            // the first transaction leaves the root inode's block unflushed
+            // in write-back mode, the second allocates an inode in it
+            let tx=xfs.begin_op();
+            let root=xfs.icache.get_root_dir().unwrap();
+            root.lock().unwrap().update().unwrap();
+            drop(root);
+            tx.end().unwrap();
+            VFile::vfile_create_file(xfs, "/m", true, true).unwrap();
+        })),
+        ("unlink created", Box::new(|xfs| xfs.get_root_vfile().unwrap().vfile_unlink("/m").unwrap())),
+    ];
+    let write_back=MountOptions::new().write_back(true).flush_interval_ms(0);
+    let discard=MountOptions::new().discard(true);
+    for opts in [MountOptions::new(), write_back, write_back.journal(JournalMode::Ordered),
+                 discard, discard.write_back(true).flush_interval_ms(0).journal(JournalMode::Ordered)] {
+        for (what, op) in &ops {
+            image=crash_check(image, opts, what, op.as_ref());
+        }
+        assert!(tree(&Xv6FS::open(Arc::new(xv6fs::MemDevice::from_image(image.clone())), 1).unwrap()).is_empty());
+    }
+}
+
+#[test]
+fn xv6fs_test_faults() {
+    use xv6fs::fault::{Access, Effect, Fault, FaultDevice, Trigger};
+    use xv6fs::file::VFile;
+    use xv6fs::{DevError, FsError, MemDevice};
+    StdInterface::install();
+    let mem=Arc::new(MemDevice::new(1000));
+    xv6fs::format(mem.as_ref(), FormatOptions::new(1000)).unwrap();
+    let dev=Arc::new(FaultDevice::new(mem.clone()));
+    let fail=|access, trigger| Fault::new(access, trigger, Effect::Fail(DevError::Io));
+
+    // a failed commit is reported, and retried by the next one
+    let xfs=Xv6FS::open(dev.clone(), 1).unwrap();
+    dev.inject(fail(Access::Write, Trigger::Nth(2)).times(1));
+    assert_eq!(VFile::vfile_create_file(&xfs, "/a", true, true).unwrap_err(), FsError::Io);
+    VFile::vfile_create_file(&xfs, "/b", true, true).unwrap();
+    assert_eq!(dev.triggered(), 1);
+    drop(xfs);
+    assert_eq!(tree(&Xv6FS::open(mem.clone(), 1).unwrap()).len(), 2);
+
+    // failed reads surface as errors, the next access retries the device
+    dev.inject(fail(Access::Read, Trigger::Always));
+    assert_eq!(Xv6FS::open(dev.clone(), 1).err(), Some(FsError::Io));
+    dev.clear();
+    let xfs=Xv6FS::open(dev.clone(), 1).unwrap();
+    dev.inject(fail(Access::Read, Trigger::Always));
+    assert_eq!(VFile::vfile_lookup(&xfs, "/a").unwrap_err(), FsError::Io);
+    dev.clear();
+    VFile::vfile_lookup(&xfs, "/a").unwrap();
+    drop(xfs);
+
+    // a zeroed super block is no file system
+    dev.inject(Fault::new(Access::Read, Trigger::Block(1), Effect::Short(0)).times(1));
+    assert_eq!(Xv6FS::open(dev.clone(), 1).err(), Some(FsError::Corrupted));
+
+    // a corrupted log block is caught by the checksum when the device goes
+    // away before the install: the transaction is discarded
+    let xfs=Xv6FS::open(dev.clone(), 1).unwrap();
+    dev.inject(Fault::new(Access::Write, Trigger::Nth(0), Effect::Corrupt).times(1));
+    dev.inject(Fault::new(Access::Write, Trigger::Nth(4), Effect::Gone));
+    assert_eq!(VFile::vfile_create_file(&xfs, "/c", true, true).unwrap_err(), FsError::Io);
+    assert_eq!(VFile::vfile_create_file(&xfs, "/d", true, true).unwrap_err(), FsError::Io);
+    drop(xfs);
+    dev.clear();
+    let xfs=Xv6FS::open(dev.clone(), 1).unwrap();
+    assert_eq!(tree(&xfs).keys().collect::<Vec<_>>(), vec!["/a", "/b"]);
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+    drop(xfs);
+
+    // random failures only ever turn into errors, and the image
+    // is repaired into a consistent one afterwards
+    for seed in 1..=8 {
+        let dev=Arc::new(FaultDevice::with_seed(mem.clone(), seed));
+        let fuse=Xv6Fuse::new(Xv6FS::open(dev.clone(), 1).unwrap(), 0, 0);
+        dev.inject(fail(Access::Both, Trigger::OneIn(12)));
+        for i in 0..40 {
+            let name=format!("f{}", i % 7);
+            let _=match i % 4 {
+                0 => fuse.do_create(1, OsStr::new(&name)).map(drop),
+                1 => fuse.do_lookup(1, OsStr::new(&name))
+                    .and_then(|attr| fuse.do_write(attr.ino, i * 100, &[i as u8; 700])).map(drop),
+                2 => fuse.do_mkdir(1, OsStr::new(&format!("d{}", i % 3))).map(drop),
+                _ => fuse.do_unlink(1, OsStr::new(&name)),
+            };
+        }
+        assert!(dev.triggered() > 0);
+        drop(fuse);
+        let xfs=Xv6FS::open(mem.clone(), 1).unwrap();
+        xv6fs::fsck::repair(&xfs).unwrap();
+        assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+        tree(&xfs);
+    }
+}
+
+#[test]
+fn xv6fs_test_cache_size() {
+    use xv6fs::file::VFile;
+    use xv6fs::{FsError, MemDevice};
+    use xv6fs::fs_const::MAXOPBLOCKS;
+    StdInterface::install();
+    let mem=Arc::new(MemDevice::new(4000));
+    xv6fs::format(mem.as_ref(), FormatOptions::new(4000).log_blocks(200)).unwrap();
+    let mount=|cache_blocks| Xv6FS::open_with(mem.clone(), 1, MountOptions::new().cache_blocks(cache_blocks));
+    assert_eq!(mount(MAXOPBLOCKS + 1).err(), Some(FsError::InvalidArg));
+
+    // with the smallest cache every buf gets pinned by the log, and threads
+    // wait for one instead of running out; a large one holds the whole image
+    for (round, cache_blocks) in [MAXOPBLOCKS + 2, 4096].into_iter().enumerate() {
+        let xfs=mount(cache_blocks).unwrap();
+        let handles:Vec<_>=(0..4usize).map(|i| {
+            let xfs=xfs.clone();
+            std::thread::spawn(move || {
+                let path=format!("/r{}_{}", round, i);
+                let file=VFile::vfile_create_file(&xfs, &path, true, true).unwrap();
+                let data=vec![i as u8; 20000];
+                assert_eq!(file.vfile_write(data.as_ptr() as usize, data.len()).unwrap(), data.len());
+                let mut buf=vec![0u8; 20000];
+                assert_eq!(file.vfile_read(buf.as_mut_ptr() as usize, 0, buf.len()).unwrap(), buf.len());
+                assert_eq!(buf, data);
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        drop(xfs);
+    }
+    let xfs=mount(MAXOPBLOCKS + 2).unwrap();
+    assert_eq!(tree(&xfs).len(), 8);
+
+    // with every buf held, bread waits until one is released
+    let held:Vec<_>=(0..MAXOPBLOCKS as u32 + 2).map(|b| xfs.bcache.bread(1, 100 + b).unwrap()).collect();
+    let done=Arc::new(std::sync::atomic::AtomicBool::new(false));
+    std::thread::scope(|s| {
+        let waiter=s.spawn(|| {
+            let buf=xfs.bcache.bread(1, 99).unwrap();
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            buf.read_blockno()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!done.load(std::sync::atomic::Ordering::SeqCst));
+        drop(held);
+        assert_eq!(waiter.join().unwrap(), 99);
+    });
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+}
+
+#[test]
+fn xv6fs_test_write_back() {
+    use xv6fs::crash::RecordingDevice;
+    use xv6fs::file::VFile;
+    use xv6fs::fs_const::{MAXOPBLOCKS, NINDIRECT};
+    use xv6fs::MemDevice;
+    StdInterface::install();
+    let mem=Arc::new(MemDevice::new(2000));
+    xv6fs::format(mem.as_ref(), FormatOptions::new(2000)).unwrap();
+    let rec=Arc::new(RecordingDevice::new(mem.clone()));
+    let opts=MountOptions::new().write_back(true).flush_interval_ms(0);
+    let xfs=Xv6FS::open_with(rec.clone(), 1, opts).unwrap();
+    let (logstart, nlog)=xfs.sb.read_log();
+    let in_log=|b: usize| b >= logstart as usize && b < (logstart + nlog) as usize;
+
+    // committed blocks stay in the cache, only the log reaches the device
+    // until the next commit needs the log again
+    rec.take_writes();
+    let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    assert!(rec.take_writes().iter().all(|(b, _)| in_log(*b)));
+    let data:Vec<u8>=(0..3000).map(|i| (i % 241) as u8).collect();
+    assert_eq!(file.vfile_write(data.as_ptr() as usize, data.len()).unwrap(), data.len());
+    drop(file);
+    assert!(rec.take_writes().iter().any(|(b, _)| !in_log(*b)));
+    // a crash now leaves the last transaction to recovery
+    let xfs2=Xv6FS::open(Arc::new(MemDevice::from_image(mem.image())), 1).unwrap();
+    assert_eq!(tree(&xfs2)["/f"], Some(data.clone()));
+    drop(xfs2);
+
+    // sync writes everything home and empties the log
+    xfs.sync().unwrap();
+    assert!(rec.take_writes().iter().any(|(b, _)| !in_log(*b)));
+    let image=mem.image();
+    assert_eq!(&image[logstart as usize * BSIZE..logstart as usize * BSIZE + 4], &[0; 4]);
+    xfs.sync().unwrap();
+    assert!(rec.take_writes().is_empty());
+    drop(xfs);
+    let xfs=Xv6FS::open(Arc::new(MemDevice::from_image(image)), 1).unwrap();
+    assert_eq!(tree(&xfs)["/f"], Some(data.clone()));
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+    drop(xfs);
+
+    // with a small cache dirty bufs are written back when evicted
+    for journal in [JournalMode::Data, JournalMode::Ordered, JournalMode::Writeback] {
+        let xfs=Xv6FS::open_with(mem.clone(), 1, opts.journal(journal).cache_blocks(MAXOPBLOCKS + 2)).unwrap();
+        let big:Vec<u8>=(0..200_000).map(|i| (i % 239) as u8).collect();
+        let file=VFile::vfile_create_file(&xfs, "/big", true, true).unwrap();
+        assert_eq!(file.vfile_write(big.as_ptr() as usize, big.len()).unwrap(), big.len());
+        drop(file);
+        drop(xfs);
+        let xfs=Xv6FS::open(mem.clone(), 1).unwrap();
+        assert_eq!(tree(&xfs)["/big"], Some(big));
+        assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+        xfs.get_root_vfile().unwrap().vfile_unlink("/big").unwrap();
+    }
+
+    // a block logged and freed by one transaction may hold file data,
+    // written in place and evicted, before that transaction's checkpoint
+    let mem2=Arc::new(MemDevice::new(2000));
+    xv6fs::format(mem2.as_ref(), FormatOptions::new(2000)).unwrap();
+    let xfs=Xv6FS::open_with(mem2.clone(), 1, opts.journal(JournalMode::Ordered).cache_blocks(MAXOPBLOCKS + 2)).unwrap();
+    VFile::vfile_create_dir(&xfs, "/d", true, true).unwrap();
+    VFile::vfile_create_file(&xfs, "/d/e", true, true).unwrap();
+    let file=VFile::vfile_create_file(&xfs, "/h", true, true).unwrap();
+    let dir_block=xfs.icache.namei(b"/d").unwrap().lock().unwrap().dinode.addrs[0];
+    xfs.get_root_vfile().unwrap().vfile_remove("/d").unwrap();
+    let big:Vec<u8>=(0..NINDIRECT * BSIZE).map(|i| (i % 227 + 1) as u8).collect();
+    assert_eq!(file.vfile_write(big.as_ptr() as usize, big.len()).unwrap(), big.len());
+    drop(file);
+    assert_eq!(xfs.icache.namei(b"/h").unwrap().lock().unwrap().dinode.addrs[0], dir_block);
+    xfs.sync().unwrap();
+    drop(xfs);
+    let xfs=Xv6FS::open(mem2, 1).unwrap();
+    assert_eq!(tree(&xfs)["/h"], Some(big));
+    assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+    drop(xfs);
+
+    // the background flusher writes the cache back on its own
+    let xfs=Xv6FS::open_with(rec.clone(), 1, opts.flush_interval_ms(10)).unwrap();
+    VFile::vfile_create_file(&xfs, "/g", true, true).unwrap();
+    rec.take_writes();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(rec.take_writes().iter().any(|(b, _)| !in_log(*b)));
+    assert_eq!(&mem.image()[logstart as usize * BSIZE..logstart as usize * BSIZE + 4], &[0; 4]);
+}
+
+#[test]
+fn xv6fs_test_read_ahead() {
+    use xv6fs::file::VFile;
+    use xv6fs::MemDevice;
+    StdInterface::install();
+    let mem=Arc::new(MemDevice::new(2000));
+    xv6fs::format(mem.as_ref(), FormatOptions::new(2000)).unwrap();
+    let data:Vec<u8>=(0..100_000).map(|i| (i % 247) as u8).collect();
+    let xfs=Xv6FS::open(mem.clone(), 1).unwrap();
+    let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+    assert_eq!(file.vfile_write(data.as_ptr() as usize, data.len()).unwrap(), data.len());
+    drop(file);
+    drop(xfs);
+
+    // read the file in chunks of 700 bytes, from the start or backwards
+    let read=|read_ahead, backwards| {
+        let xfs=Xv6FS::open_with(mem.clone(), 1, MountOptions::new().cache_blocks(64).read_ahead(read_ahead)).unwrap();
+        let file=VFile::vfile_lookup(&xfs, "/f").unwrap();
+        let mut buf=vec![0u8; data.len()];
+        let chunks:Vec<usize>=(0..data.len()).step_by(700).collect();
+        let before=xfs.bcache.stats();
+        for i in 0..chunks.len() {
+            let at=if backwards { chunks[chunks.len() - 1 - i] } else { chunks[i] };
+            let len=700.min(data.len() - at);
+            assert_eq!(file.vfile_read(buf[at..].as_mut_ptr() as usize, at, len).unwrap(), len);
+        }
+        assert_eq!(buf, data);
+        let after=xfs.bcache.stats();
+        drop(file);
+        (after.misses - before.misses, after.prefetched, after.prefetch_hits, after.read_ahead)
+    };
+    let (misses, prefetched, _, window)=read(0, false);
+    assert_eq!((prefetched, window), (0, 0));
+    // sequential reads find almost every data block prefetched
+    let (ra_misses, prefetched, prefetch_hits, window)=read(16, false);
+    assert_eq!(window, 16);
+    assert!(prefetched > 150 && prefetch_hits + 2 >= prefetched, "{} {}", prefetched, prefetch_hits);
+    assert!(ra_misses < misses / 4, "{} {}", ra_misses, misses);
+    // other reads prefetch nothing
+    let (_, prefetched, _, _)=read(16, true);
+    assert_eq!(prefetched, 0);
+    // the window is kept to half the cache
+    let xfs=Xv6FS::open_with(mem.clone(), 1, MountOptions::new().cache_blocks(40).read_ahead(100)).unwrap();
+    assert_eq!(xfs.bcache.stats().read_ahead, 20);
+}
+
+#[test]
+fn xv6fs_test_vectored() {
+    use std::sync::Mutex;
+    use xv6fs::file::VFile;
+    use xv6fs::{BlockDevice, DevResult, MemDevice};
+    type Calls=Arc<Mutex<Vec<usize>>>;
+    /// Records the length of every transfer, and only serves single
+    /// blocks: ranges go through the default loops.
+    struct Counting {
+        inner: Arc<MemDevice>,
+        reads: Calls,
+        writes: Calls,
+    }
+    impl BlockDevice for Counting {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+            self.reads.lock().unwrap().push(buf.len() / BSIZE);
+            self.inner.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+            self.writes.lock().unwrap().push(buf.len() / BSIZE);
+            self.inner.write_block(block_id, buf)
+        }
+    }
+    /// Serves a range with one transfer.
+    struct Vectored(Counting);
+    impl BlockDevice for Vectored {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+            self.0.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+            self.0.write_block(block_id, buf)
+        }
+        fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+            self.0.read_block(block_id, buf)
+        }
+        fn write_blocks(&self, block_id: usize, buf: &[u8]) -> DevResult {
+            self.0.write_block(block_id, buf)
+        }
+    }
+    StdInterface::install();
+    let largest=|calls: &Calls| std::mem::take(&mut *calls.lock().unwrap()).into_iter().max().unwrap_or(0);
+    let data:Vec<u8>=(0..60_000).map(|i| (i % 233) as u8).collect();
+    let mut images=Vec::new();
+    for vectored in [true, false] {
+        let (mem, reads, writes)=(Arc::new(MemDevice::new(2000)), Calls::default(), Calls::default());
+        let counting=Counting { inner: mem.clone(), reads: reads.clone(), writes: writes.clone() };
+        let dev:Arc<dyn BlockDevice>=match vectored {
+            true => Arc::new(Vectored(counting)),
+            false => Arc::new(counting),
+        };
+
+        // formatting zeroes the device in a few large writes
+        xv6fs::format(dev.as_ref(), FormatOptions::new(2000)).unwrap();
+        assert_eq!(writes.lock().unwrap().len() < 100, vectored);
+        writes.lock().unwrap().clear();
+
+        // a commit writes the log in one go, a large read fetches runs
+        // of blocks, and so does a file write with its data in place
+        for journal in [JournalMode::Data, JournalMode::Ordered] {
+            let opts=MountOptions::new().journal(journal).cache_blocks(64).read_ahead(0);
+            let xfs=Xv6FS::open_with(dev.clone(), 1, opts).unwrap();
+            let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+            file.vfile_write(data.as_ptr() as usize, 4000).unwrap();
+            assert_eq!(largest(&writes) > 1, vectored);
+            file.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+            drop(file);
+            drop(xfs);
+            let xfs=Xv6FS::open_with(dev.clone(), 1, opts).unwrap();
+            largest(&reads);
+            assert_eq!(tree(&xfs)["/f"], Some(data.clone()));
+            assert_eq!(largest(&reads) > 1, vectored);
+            assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+            xfs.get_root_vfile().unwrap().vfile_unlink("/f").unwrap();
+        }
+        images.push(mem.image());
+    }
+    // the default loops leave the same image as the ranges served whole
+    assert!(images[0] == images[1]);
+}
+
+#[test]
+fn xv6fs_test_discard() {
+    use std::ops::Range;
+    use std::sync::Mutex;
+    use xv6fs::file::VFile;
+    use xv6fs::fs_const::NDIRECT;
+    use xv6fs::{BlockDevice, DevResult, MemDevice};
+    /// Records the ranges discarded.
+    struct Discarding {
+        inner: Arc<MemDevice>,
+        ranges: Mutex<Vec<Range<usize>>>,
+    }
+    impl BlockDevice for Discarding {
+        fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+            self.inner.read_block(block_id, buf)
+        }
+        fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+            self.inner.write_block(block_id, buf)
+        }
+        fn discard(&self, blocks: Range<usize>) -> DevResult {
+            self.ranges.lock().unwrap().push(blocks.clone());
+            self.inner.discard(blocks)
+        }
+    }
+    StdInterface::install();
+    let data:Vec<u8>=(0..NDIRECT * BSIZE).map(|i| (i % 233 + 1) as u8).collect();
+    let zeroed=|mem: &MemDevice, bnos: &[u32]| {
+        let image=mem.image();
+        bnos.iter().all(|&b| image[b as usize * BSIZE..(b as usize + 1) * BSIZE].iter().all(|&c| c == 0))
+    };
+    for discard in [false, true] {
+        let mem=Arc::new(MemDevice::new(2000));
+        xv6fs::format(mem.as_ref(), FormatOptions::new(2000)).unwrap();
+        let dev=Arc::new(Discarding { inner: mem.clone(), ranges: Mutex::new(Vec::new()) });
+        let xfs=Xv6FS::open_with(dev.clone(), 1, MountOptions::new().discard(discard)).unwrap();
+        let file=VFile::vfile_create_file(&xfs, "/f", true, true).unwrap();
+        file.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+        drop(file);
+        let bnos=xfs.icache.namei(b"/f").unwrap().lock().unwrap().dinode.addrs[..NDIRECT].to_vec();
+        assert!(!zeroed(&mem, &bnos));
+
+        // the blocks of a removed file are discarded once the removal
+        // commits, in runs of contiguous blocks
+        xfs.get_root_vfile().unwrap().vfile_remove("/f").unwrap();
+        assert_eq!(zeroed(&mem, &bnos), discard);
+        let ranges=std::mem::take(&mut *dev.ranges.lock().unwrap());
+        assert_eq!(ranges.iter().map(|r| r.len()).sum::<usize>(), if discard { NDIRECT } else { 0 });
+        assert!(ranges.len() < NDIRECT);
+
+        // a block freed and allocated again by the same transaction is kept
+        let tx=xfs.begin_op();
+        let bno=balloc(&xfs, 1).unwrap();
+        tx.end().unwrap();
+        let tx=xfs.begin_op();
+        bfree(&xfs, bno).unwrap();
+        assert_eq!(balloc(&xfs, 1).unwrap(), bno);
+        let mut buf=xfs.bcache.bread(1, bno).unwrap();
+        xfs.log.reserve(&buf).unwrap();
+        buf.data_mut().fill(7);
+        xfs.log.write(buf).unwrap();
+        tx.end().unwrap();
+        assert_eq!(mem.image()[bno as usize * BSIZE], 7);
+
+        // the offline pass discards every free block, and only those
+        if !discard {
+            let free=(xfs.sb.datastart()..xfs.sb.size())
+                .filter(|&b| !xv6fs::bitmap::bisalloc(&xfs, b).unwrap())
+                .count();
+            assert_eq!(xv6fs::bitmap::trim(&xfs).unwrap() as usize, free);
+            let rest:Vec<u32>=bnos.iter().copied().filter(|&b| b != bno).collect();
+            assert!(zeroed(&mem, &rest));
+            assert_eq!(mem.image()[bno as usize * BSIZE], 7);
+        }
+        assert_eq!(xv6fs::fsck::check(&xfs).unwrap(), vec![]);
+    }
+}
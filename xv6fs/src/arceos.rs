@@ -0,0 +1,310 @@
+//! ArceOS binding, built with the `arceos` feature:
+//! `VfsOps`/`VfsNodeOps` over the inode layer,
+//! and an [`FsInterface`] whose sleep locks wait on task wait queues.
+//!
+//! ```ignore
+//! AxTaskInterface::install();
+//! let fs = Xv6FS::open(disk, 1)?;
+//! let ops: Arc<dyn VfsOps> = Arc::new(Xv6VfsOps::new(fs)?);
+//! ```
+//!
+//! Paths handed to the nodes are relative to the node, as the ArceOS
+//! VFS resolves them, so the current directory of the interface is never used.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axerrno::AxError;
+use axfs_vfs::{
+    VfsDirEntry, VfsNodeAttr, VfsNodeOps, VfsNodePerm, VfsNodeRef, VfsNodeType, VfsOps, VfsResult,
+};
+use axtask::WaitQueue;
+use spin::Mutex;
+
+use crate::disk_inode::InodeType;
+use crate::error::FsError;
+use crate::file::VFile;
+use crate::fs_const::{DIRSIZ, ROOTINUM};
+use crate::inode::Inode;
+use crate::interface::{FsInterface, InterfaceManager, INTERFACE_MANAGER};
+use crate::xv6fs::Xv6FS;
+
+impl From<FsError> for AxError {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => AxError::NotFound,
+            FsError::Exists => AxError::AlreadyExists,
+            FsError::NotDir => AxError::NotADirectory,
+            FsError::IsDir => AxError::IsADirectory,
+            FsError::NotEmpty => AxError::DirectoryNotEmpty,
+            FsError::NoSpace | FsError::NoInodes => AxError::StorageFull,
+            FsError::CacheFull => AxError::NoMemory,
+            FsError::NameTooLong | FsError::FileTooLarge | FsError::InvalidArg => AxError::InvalidInput,
+            FsError::BadFile | FsError::PermissionDenied | FsError::ReadOnly => AxError::PermissionDenied,
+            FsError::Io => AxError::Io,
+            FsError::Corrupted => AxError::InvalidData,
+        }
+    }
+}
+
+/// A mounted xv6 file system.
+pub struct Xv6VfsOps {
+    root: Arc<Xv6Node>,
+}
+
+impl Xv6VfsOps {
+    pub fn new(fs: Arc<Xv6FS>) -> Result<Self, FsError> {
+        let root = fs.icache.get_root_dir()?;
+        Ok(Self { root: Arc::new(Xv6Node::new(root)) })
+    }
+}
+
+impl VfsOps for Xv6VfsOps {
+    fn root_dir(&self) -> VfsNodeRef {
+        self.root.clone()
+    }
+
+    /// Write back what a write-back cache still holds.
+    fn umount(&self) -> VfsResult {
+        Ok(self.root.inode.fs().sync()?)
+    }
+}
+
+/// A file or directory, holding a reference to its inode.
+pub struct Xv6Node {
+    inode: Inode,
+}
+
+/// Walk the relative `path` from `dir`.
+fn walk(dir: &Inode, path: &str) -> Result<Inode, FsError> {
+    let mut inode = dir.clone();
+    for name in path.split('/') {
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        if name.len() >= DIRSIZ {
+            return Err(FsError::NameTooLong);
+        }
+        let mut idata = inode.lock()?;
+        if idata.dinode.itype != InodeType::Directory {
+            return Err(FsError::NotDir);
+        }
+        let next = idata.dir_lookup(name.as_bytes());
+        drop(idata);
+        inode = next?;
+    }
+    Ok(inode)
+}
+
+/// Split the relative `path` into the directory holding its last name, and the name.
+fn walk_parent<'a>(dir: &Inode, path: &'a str) -> Result<(Inode, &'a str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidArg);
+    }
+    if name.len() >= DIRSIZ {
+        return Err(FsError::NameTooLong);
+    }
+    let parent = walk(dir, parent)?;
+    if parent.lock()?.dinode.itype != InodeType::Directory {
+        return Err(FsError::NotDir);
+    }
+    Ok((parent, name))
+}
+
+fn node_type(itype: InodeType) -> VfsNodeType {
+    match itype {
+        InodeType::Directory => VfsNodeType::Dir,
+        InodeType::Device => VfsNodeType::CharDevice,
+        _ => VfsNodeType::File,
+    }
+}
+
+impl Xv6Node {
+    pub fn new(inode: Inode) -> Self {
+        Self { inode }
+    }
+
+    /// Run a mutating request as one file system operation.
+    /// Inodes must be dropped inside `f`, so that freeing them is committed too.
+    fn op<T>(&self, f: impl FnOnce() -> Result<T, FsError>) -> Result<T, FsError> {
+        let tx = self.inode.fs().begin_op();
+        let res = f();
+        tx.end()?;
+        res
+    }
+}
+
+impl VfsNodeOps for Xv6Node {
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        let idata = self.inode.lock()?;
+        let ty = node_type(idata.dinode.itype);
+        let perm = if ty == VfsNodeType::Dir { 0o755 } else { 0o644 };
+        let size = idata.dinode.size as u64;
+        Ok(VfsNodeAttr::new(VfsNodePerm::from_bits_truncate(perm), ty, size, (size + 511) / 512))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let offset = u32::try_from(offset).map_err(|_| AxError::InvalidInput)?;
+        let mut idata = self.inode.lock()?;
+        if idata.dinode.itype == InodeType::Directory {
+            return Err(AxError::IsADirectory);
+        }
+        Ok(idata.read(buf.as_mut_ptr() as usize, offset, buf.len() as u32)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        let file = VFile::from_inode(self.inode.clone())?;
+        if file.vfile_is_dir() {
+            return Err(AxError::IsADirectory);
+        }
+        Ok(file.vfile_write_at(buf.as_ptr() as usize, offset as usize, buf.len())?)
+    }
+
+    /// Every operation is committed to the log when it ends,
+    /// this also writes back what a write-back cache still holds.
+    fn fsync(&self) -> VfsResult {
+        Ok(self.inode.fs().sync()?)
+    }
+
+    fn truncate(&self, size: u64) -> VfsResult {
+        self.op(|| {
+            let mut idata = self.inode.lock()?;
+            if idata.dinode.itype == InodeType::Directory {
+                return Err(FsError::IsDir);
+            }
+            idata.resize(&self.inode, size).map(drop)
+        })?;
+        Ok(())
+    }
+
+    fn parent(&self) -> Option<VfsNodeRef> {
+        if self.inode.inum == ROOTINUM {
+            return None;
+        }
+        let parent = self.inode.lock().ok()?.dir_lookup(b"..").ok()?;
+        Some(Arc::new(Xv6Node::new(parent)))
+    }
+
+    fn lookup(self: Arc<Self>, path: &str) -> VfsResult<VfsNodeRef> {
+        let inode = walk(&self.inode, path)?;
+        Ok(Arc::new(Xv6Node::new(inode)))
+    }
+
+    fn create(&self, path: &str, ty: VfsNodeType) -> VfsResult {
+        let itype = match ty {
+            VfsNodeType::File => InodeType::File,
+            VfsNodeType::Dir => InodeType::Directory,
+            _ => return Err(AxError::Unsupported),
+        };
+        let (dir, name) = walk_parent(&self.inode, path)?;
+        let fs = self.inode.fs();
+        self.op(|| {
+            if dir.lock()?.dir_lookup(name.as_bytes()).is_ok() {
+                return Err(FsError::Exists);
+            }
+            fs.icache.create_at(&dir, name.as_bytes(), itype, 0, 0).map(drop)
+        })?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> VfsResult {
+        let (dir, name) = walk_parent(&self.inode, path)?;
+        let fs = self.inode.fs();
+        self.op(|| fs.icache.unlink_at(&dir, name.as_bytes()))?;
+        Ok(())
+    }
+
+    fn read_dir(&self, start_idx: usize, dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
+        let entries = self.inode.lock()?.dir_entries()?;
+        let fs = self.inode.fs();
+        let mut n = 0;
+        for ((inum, name), ent) in entries.iter().skip(start_idx).zip(dirents.iter_mut()) {
+            let itype = fs.icache.get_inum_type(self.inode.dev, *inum)?;
+            *ent = VfsDirEntry::new(str::from_utf8(name).map_err(|_| AxError::InvalidData)?, node_type(itype));
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn rename(&self, src_path: &str, dst_path: &str) -> VfsResult {
+        let (src_dir, src_name) = walk_parent(&self.inode, src_path)?;
+        let (dst_dir, dst_name) = walk_parent(&self.inode, dst_path)?;
+        let fs = self.inode.fs();
+        self.op(|| fs.icache.rename_at(&src_dir, src_name.as_bytes(), &dst_dir, dst_name.as_bytes()))?;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// One sleep lock: tasks wait on the queue while it is held.
+struct TaskLock {
+    held: AtomicBool,
+    queue: WaitQueue,
+}
+
+/// Sleep locks on ArceOS tasks.
+pub struct AxTaskInterface {
+    locks: Mutex<Vec<Arc<TaskLock>>>,
+}
+
+impl AxTaskInterface {
+    pub const fn new() -> Self {
+        Self { locks: Mutex::new(Vec::new()) }
+    }
+
+    /// Make an `AxTaskInterface` the interface of the library.
+    /// Call it once during boot, before opening a file system.
+    pub fn install() {
+        if !INTERFACE_MANAGER.is_init() {
+            INTERFACE_MANAGER.init_by(InterfaceManager { interface: Arc::new(AxTaskInterface::new()) });
+        }
+    }
+
+    fn lock_at(&self, index: usize) -> Arc<TaskLock> {
+        self.locks.lock()[index].clone()
+    }
+}
+
+impl FsInterface for AxTaskInterface {
+    /// ArceOS resolves relative paths before they reach the nodes.
+    fn get_cur_dir_inode(&self) -> Option<Inode> {
+        None
+    }
+
+    /// Block the current task until the sleep lock is free, then take it.
+    fn sleep_cur_proc(&self, index: usize) {
+        let lock = self.lock_at(index);
+        while lock.held.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            lock.queue.wait_until(|| !lock.held.load(Ordering::Acquire));
+        }
+    }
+
+    /// Release the sleep lock and wake one waiting task.
+    fn wake_up_next_proc(&self, index: usize) {
+        let lock = self.lock_at(index);
+        lock.held.store(false, Ordering::Release);
+        lock.queue.notify_one(true);
+    }
+
+    fn new_sleep_lock(&self) -> usize {
+        let mut locks = self.locks.lock();
+        locks.push(Arc::new(TaskLock { held: AtomicBool::new(false), queue: WaitQueue::new() }));
+        locks.len() - 1
+    }
+
+    /// Whether the sleep lock is held.
+    fn get_flag(&self, index: usize) -> bool {
+        self.lock_at(index).held.load(Ordering::Acquire)
+    }
+}
@@ -5,22 +5,23 @@ use axlog::{info}; // Use log crate when building application
 #[cfg(test)]
 use std::{println as info}; // Workaround to use prinltn! for logs.
 
-use crate::{superblock::SUPER_BLOCK};
-use crate::log::LOG_MANAGER;
-use crate::buffer_cache::BLOCK_CACHE_MANAGER;
+use crate::xv6fs::Xv6FS;
+use crate::error::FsError;
 use super::{ InodeType, DiskInode };
 
 
-use crate::fs_const::{ BPB,IPB };
+use crate::fs_const::{ BPB, BSIZE, IPB };
 
 use core::ptr;
+use alloc::vec::Vec;
 
-// / Zero a block. 
-// pub fn bzero(dev: u32, bno: u32) {
-//     let mut buf = BCACHE.bread(dev, bno);
-//     unsafe{ (&mut *buf.raw_data_mut()).zero() };
-//     LOG.write(buf);
-// }
+/// Zero a block. 
+pub fn bzero(fs: &Xv6FS, dev: u32, bno: u32) -> Result<(), FsError> {
+    let mut buf = fs.bcache.bread(dev, bno)?;
+    fs.log.reserve(&buf)?;
+    unsafe{ ptr::write_bytes(buf.raw_data_mut() as *mut u8, 0, BSIZE) };
+    fs.log.write(buf)
+}
 
 /// Given an inode number. 
 /// Calculate the offset index of this inode inside the block. 
@@ -47,92 +48,155 @@ fn locate_inode_offset(inum: u32) -> usize {
 
 
 /// Allocate a zeroed disk block 
-pub fn balloc(dev: u32) -> u32 {
+/// Fails with `NoSpace` if every block is in use,
+/// and with `Corrupted` if the bitmap marks a metadata block free. 
+pub fn balloc(fs: &Xv6FS, dev: u32) -> Result<u32, FsError> {
+    alloc(fs, dev, false)
+}
+
+/// Allocate a zeroed disk block for file data.
+/// Unless the file system journals data, it is zeroed in place,
+/// and never one freed by a transaction that has not committed yet.
+pub fn balloc_data(fs: &Xv6FS, dev: u32) -> Result<u32, FsError> {
+    alloc(fs, dev, !fs.journals_data())
+}
+
+fn alloc(fs: &Xv6FS, dev: u32, in_place: bool) -> Result<u32, FsError> {
     let mut b = 0;
-    let sb_size = unsafe{ SUPER_BLOCK.size() };
+    let sb_size = fs.sb.size();
     while b < sb_size {
-        let bm_blockno = unsafe{ SUPER_BLOCK.bitmap_blockno(b) };
-        let mut buf = BLOCK_CACHE_MANAGER.bread(dev, bm_blockno);
+        let bm_blockno = fs.sb.bitmap_blockno(b);
+        let mut buf = fs.bcache.bread(dev, bm_blockno)?;
         let mut bi = 0;
         while bi < BPB && b + bi < sb_size {
-            bi += 1;
             let m = 1 << (bi % 8);
             let buf_ptr = unsafe{ (buf.raw_data_mut() as *mut u8).offset((bi / 8) as isize).as_mut().unwrap() };
             let buf_val = unsafe{ ptr::read(buf_ptr) };
             //info!("bval is {}",buf_val);
             if (buf_val&m) == 0{ // Is block free?
+                if b + bi < fs.sb.datastart() {
+                    // metadata is always marked in use
+                    return Err(fs.corrupted());
+                }
+                if in_place && fs.log.is_freed(b + bi) {
+                    bi += 1;
+                    continue;
+                }
+                fs.log.reserve(&buf)?;
                 let new_val:u8=buf_val|m;
                 unsafe{ ptr::write(buf_ptr, new_val) };
                 debug!("[Xv6fs] balloc: inum is {}",bi);
-                LOG_MANAGER.write(buf);
+                fs.log.write(buf)?;
+                fs.log.note_reused(b + bi);
                 // drop(buf);
-                // bzero(dev, b + bi);
-                return b + bi
+                if in_place {
+                    let mut zero = fs.bcache.bread(dev, b + bi)?;
+                    zero.data_mut().fill(0);
+                    fs.log.note_data(b + bi);
+                    zero.bdwrite()?;
+                } else {
+                    bzero(fs, dev, b + bi)?;
+                }
+                return Ok(b + bi)
             }
+            bi += 1;
         }
         drop(buf);
         b += BPB;
     }
-    panic!("balloc: out of the block ranges.")
+    Err(FsError::NoSpace)
 }
 
-pub fn bisalloc(blockno:u32)->bool{
-    if blockno > 1000{
-        return false;
+pub fn bisalloc(fs: &Xv6FS, blockno:u32)->Result<bool,FsError>{
+    if blockno >= fs.sb.size(){
+        return Ok(false);
     }
-    let bm_blockno=unsafe {SUPER_BLOCK.bitmap_blockno(blockno)};
-    let mut buf=BLOCK_CACHE_MANAGER.bread(0, bm_blockno);
+    let bm_blockno=fs.sb.bitmap_blockno(blockno);
+    let mut buf=fs.bcache.bread(fs.dev, bm_blockno)?;
     let bi=blockno%8;
-    let offset=blockno/8;
+    let offset=(blockno%BPB)/8;
     let buf_ptr=unsafe {(buf.raw_data_mut() as *mut u8).offset(offset as isize).as_mut().unwrap()};
     let buf_val=unsafe {ptr::read(buf_ptr)};
     //info!("buf val is {}",buf_val);
     info!("bisalloc end");
-    if buf_val&(1<<bi)==0{
-        false
-    }else {
-        true
-    }
+    Ok(buf_val&(1<<bi)!=0)
 }
 
-pub fn bfree(blockno:u32)->Result<(),&'static str>{
+pub fn bfree(fs: &Xv6FS, blockno:u32)->Result<(),FsError>{
     info!("[Xv6fs] bfree: free block no is {}",blockno);
-    if blockno > 1000{
-        return Ok(())
+    if !fs.sb.is_data_block(blockno){
+        return Err(fs.corrupted())
     }
-    let bm_blockno=unsafe {SUPER_BLOCK.bitmap_blockno(blockno)};
-    let mut buf=BLOCK_CACHE_MANAGER.bread(0, bm_blockno);
+    let bm_blockno=fs.sb.bitmap_blockno(blockno);
+    let mut buf=fs.bcache.bread(fs.dev, bm_blockno)?;
     let bi=blockno%8;
-    let offset=blockno/8;
+    let offset=(blockno%BPB)/8;
     let buf_ptr=unsafe {(buf.raw_data_mut() as *mut u8).offset(offset as isize).as_mut().unwrap()};
     let buf_val=unsafe {ptr::read(buf_ptr)};
     //info!("buf val is {}",buf_val);
     if buf_val&(1<<bi)==0{
-        panic!("this bit is not alloc yet");
+        // the bitmap disagrees with the inode claiming this block
+        return Err(fs.corrupted());
     }
+    fs.log.reserve(&buf)?;
     let new_val=buf_val^(1<<bi);
     //info!("new val is {}",new_val);
     unsafe{ptr::write(buf_ptr, new_val)};
     //unsafe{info!("buf is {:?}",buf.raw_data().as_ref().unwrap())};
-    LOG_MANAGER.write(buf);
+    fs.log.write(buf)?;
+    fs.log.note_freed(blockno);
     Ok(())
 }
 
-pub fn inode_alloc(dev: u32, itype: InodeType) -> u32 {
-    let size = unsafe { SUPER_BLOCK.ninodes() };
+/// Discard every free data block according to the bitmap, as fstrim does,
+/// one device request per run of free blocks within a bitmap block.
+/// Commits and writes back what the cache holds first, and expects the
+/// file system to be idle: a block allocated meanwhile could be lost.
+/// Returns the number of blocks discarded.
+pub fn trim(fs: &Xv6FS) -> Result<u32, FsError> {
+    fs.check_writable()?;
+    fs.sync()?;
+    let sb_size = fs.sb.size();
+    let mut trimmed = 0;
+    let mut b = fs.sb.datastart();
+    while b < sb_size {
+        let buf = fs.bcache.bread(fs.dev, fs.sb.bitmap_blockno(b))?;
+        let mut free = Vec::new();
+        let end = (b - b % BPB + BPB).min(sb_size);
+        for blockno in b..end {
+            let bi = blockno % BPB;
+            if buf.data()[(bi / 8) as usize] & (1 << (bi % 8)) == 0 {
+                free.push(blockno);
+            }
+        }
+        drop(buf);
+        fs.bcache.discard(fs.dev, &free)?;
+        trimmed += free.len() as u32;
+        b = end;
+    }
+    info!("[Xv6fs] trim: {} free blocks discarded", trimmed);
+    Ok(trimmed)
+}
+
+pub fn inode_alloc(fs: &Xv6FS, dev: u32, itype: InodeType) -> Result<u32, FsError> {
+    let size = fs.sb.ninodes();
     for inum in 1..size {
-        let blockno = unsafe { SUPER_BLOCK.locate_inode(inum) };
-        let offset = locate_inode_offset(inum) as isize;
+        let blockno = fs.sb.locate_inode(inum)?;
+        let offset = locate_inode_offset(inum) * DiskInode::SIZE;
         debug!("inode alloc");
-        let mut buf = BLOCK_CACHE_MANAGER.bread(dev, blockno);
-        let dinode = unsafe { (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
-        let dinode = unsafe { &mut *dinode };
+        let mut buf = fs.bcache.bread(dev, blockno)?;
+        let mut dinode = match DiskInode::decode(&buf.data()[offset..]) {
+            Ok(dinode) => dinode,
+            Err(_) => continue,
+        };
         if dinode.try_alloc(itype).is_ok() {
+            fs.log.reserve(&buf)?;
+            dinode.encode(&mut buf.data_mut()[offset..]);
             info!("[Xv6fs] inode alloc: inum is {} and offset is {}",inum,offset);
-            LOG_MANAGER.write(buf);
-            return inum
+            fs.log.write(buf)?;
+            return Ok(inum)
         }
     }
 
-    panic!("not enough inode to alloc");
+    Err(FsError::NoInodes)
 }
\ No newline at end of file
@@ -1,19 +1,141 @@
 use core::any::Any;
+use core::fmt;
+use core::ops::Range;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs_const::BSIZE;
+
+/// Errors reported by a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DevError {
+    /// The block id is beyond the end of the device
+    OutOfRange,
+    /// The transfer failed or was incomplete
+    Io,
+    /// There is no device behind this handle
+    NoDevice,
+}
+
+pub type DevResult<T = ()> = Result<T, DevError>;
+
+impl fmt::Display for DevError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            DevError::OutOfRange => "block out of range",
+            DevError::Io => "device I/O error",
+            DevError::NoDevice => "no such device",
+        };
+        f.write_str(msg)
+    }
+}
 
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
 pub trait BlockDevice : Send + Sync + Any {
-    fn read_block(&self, _block_id: usize, _buf: &mut [u8]);
-    fn write_block(&self, _block_id: usize, _buf: &[u8]);
+    fn read_block(&self, _block_id: usize, _buf: &mut [u8]) -> DevResult;
+    fn write_block(&self, _block_id: usize, _buf: &[u8]) -> DevResult;
+
+    /// Read the contiguous blocks from `block_id` on into `buf`,
+    /// whose length is a multiple of the block size.
+    /// The default reads them one by one, a device able to serve
+    /// the whole range in one request should do so.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        for (i, block) in buf.chunks_mut(BSIZE).enumerate() {
+            self.read_block(block_id + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to the contiguous blocks from `block_id` on,
+    /// as `read_blocks` reads them.
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        for (i, block) in buf.chunks(BSIZE).enumerate() {
+            self.write_block(block_id + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Make every write completed so far durable, so that none
+    /// is reordered after a later one by a volatile write cache.
+    /// The default suits devices that write through.
+    fn flush(&self) -> DevResult {
+        Ok(())
+    }
+
+    /// Tell the device that the blocks in `blocks` hold nothing worth
+    /// keeping, so that it may unmap them. Until written again they read
+    /// back as zeros or as they were. The default ignores it.
+    fn discard(&self, _blocks: Range<usize>) -> DevResult {
+        Ok(())
+    }
 }
 
 pub struct BlockNone;
 
 impl BlockDevice for BlockNone{
-    fn read_block(&self, _block_id: usize, _buf: &mut [u8]) {
-        panic!("read from BlockNone!");
+    fn read_block(&self, _block_id: usize, _buf: &mut [u8]) -> DevResult {
+        Err(DevError::NoDevice)
+    }
+    fn write_block(&self, _block_id: usize, _buf: &[u8]) -> DevResult {
+        Err(DevError::NoDevice)
+    }
+}
+
+/// A block device kept in memory, for tests and tools.
+pub struct MemDevice {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemDevice {
+    /// A device of `blocks` zeroed blocks.
+    pub fn new(blocks: usize) -> Self {
+        Self::from_image(vec![0; blocks * BSIZE])
+    }
+
+    /// A device holding a copy of an image.
+    pub fn from_image(image: Vec<u8>) -> Self {
+        Self { data: Mutex::new(image) }
+    }
+
+    /// A copy of the whole device.
+    pub fn image(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        let data = self.data.lock();
+        let start = block_id * BSIZE;
+        let block = data.get(start..start + buf.len()).ok_or(DevError::OutOfRange)?;
+        buf.copy_from_slice(block);
+        Ok(())
     }
-    fn write_block(&self, _block_id: usize, _buf: &[u8]) {
-        panic!("write from BlockNone!");
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        let mut data = self.data.lock();
+        let start = block_id * BSIZE;
+        let block = data.get_mut(start..start + buf.len()).ok_or(DevError::OutOfRange)?;
+        block.copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)
+    }
+
+    /// Zero the blocks.
+    fn discard(&self, blocks: Range<usize>) -> DevResult {
+        let mut data = self.data.lock();
+        let range = data.get_mut(blocks.start * BSIZE..blocks.end * BSIZE).ok_or(DevError::OutOfRange)?;
+        range.fill(0);
+        Ok(())
     }
 }
@@ -7,156 +7,393 @@ use axlog::{info, warn}; // Use log crate when building application
 #[cfg(test)]
 use std::{println as info, println as warn}; // Workaround to use prinltn! for logs.
 
-use array_macro::array;
-
-use core::ptr;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{Ordering, AtomicBool};
 
 use spin::{Mutex, MutexGuard};
 use crate::{SleepLock, SleepLockGuard, init_lock};
-use crate::block_dev::BlockNone;
 
-use super::{BlockDevice,NBUF, BSIZE};
+use super::{BlockDevice, BSIZE};
+use crate::error::FsError;
+use crate::sync::wait_queue::WaitQueue;
 use alloc::sync::Arc;
-use lazy_static::*;
-use crate::sync::UPSafeCell;
-// lazy_static!{
-//     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
-//         BlockCacheManager::new());
-// }
-
-lazy_static!{
-    pub static ref BLOCK_CACHE_MANAGER:BlockCacheManager=BlockCacheManager::new();
-}
-
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Buffer cache of one mounted file system,
+/// owned by its `Xv6FS` handle.
+/// Its size is chosen at mount time, cached blocks are found
+/// by hashing their (dev, blockno), and the least recently used
+/// unused buf is recycled. With every buf in use, `bread` waits
+/// until one is released.
+///
+/// A write-back cache only marks the bufs written with `bdwrite` dirty,
+/// they reach the device when recycled or on `sync`.
+///
+/// Sequential file reads prefetch up to `read_ahead` blocks past
+/// the end of the read into unused bufs.
 pub struct BlockCacheManager {
     ctrl: Mutex<BufLru>,
-    bufs: [BufInner; NBUF],
-    inner: UPSafeCell<BlockCacheManagerInner>,
-}
-
-pub struct BlockCacheManagerInner{
+    bufs: Vec<BufInner>,
     block_device: Arc<dyn BlockDevice>,
+    write_back: bool,
+    read_ahead: usize,
+    /// bgets waiting for a buf to be released
+    waiters: WaitQueue,
 }
 
-impl BlockCacheManagerInner {
-    pub fn new()->Self{
-        BlockCacheManagerInner { block_device: Arc::new(BlockNone) }
-    }
+/// Counters of a buffer cache, see `BlockCacheManager::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of bufs
+    pub capacity: usize,
+    /// Blocks prefetched past a sequential read
+    pub read_ahead: usize,
+    /// Lookups finding the block cached
+    pub hits: u64,
+    /// Lookups recycling a buf for the block
+    pub misses: u64,
+    /// Blocks read by read-ahead
+    pub prefetched: u64,
+    /// Prefetched blocks looked up before being recycled
+    pub prefetch_hits: u64,
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
+    /// A cache of `nbuf` bufs over the block device.
+    pub fn new(block_device: Arc<dyn BlockDevice>, nbuf: usize, write_back: bool) -> Self {
         Self {
-            ctrl: Mutex::new(BufLru::new()),
-            bufs: array![_ => BufInner::new(); NBUF],
-            inner:unsafe{UPSafeCell::new(BlockCacheManagerInner::new())},
+            ctrl: Mutex::new(BufLru::new(nbuf)),
+            bufs: (0..nbuf).map(|_| BufInner::new()).collect(),
+            block_device,
+            write_back,
+            read_ahead: 0,
+            waiters: WaitQueue::new(),
         }
     }
 
-    pub fn set_block_device(&self,block_device: Arc<dyn BlockDevice>){
-        self.inner.exclusive_access().block_device=Arc::clone(&block_device);
+    /// Set the read-ahead window, at most half the cache
+    /// so that prefetching does not push out the blocks in use.
+    pub fn read_ahead(mut self, blocks: usize) -> Self {
+        self.read_ahead = blocks.min(self.bufs.len() / 2);
+        self
     }
 
-    /// Init the bcache.
-    /// Should only be called once when the kernel inits itself.
-    pub fn binit(&self) {
-        let mut ctrl = self.ctrl.lock();
-        let len = ctrl.inner.len();
+    pub fn read_ahead_window(&self) -> usize {
+        self.read_ahead
+    }
 
-        // init the head and tail of the lru list
-        ctrl.head = &mut ctrl.inner[0];
-        ctrl.tail = &mut ctrl.inner[len-1];
+    pub fn stats(&self) -> CacheStats {
+        let ctrl = self.ctrl.lock();
+        CacheStats {
+            capacity: self.bufs.len(),
+            read_ahead: self.read_ahead,
+            ..ctrl.stats
+        }
+    }
+
+    /// Number of bufs in the cache.
+    pub fn capacity(&self) -> usize {
+        self.bufs.len()
+    }
+
+    pub fn is_write_back(&self) -> bool {
+        self.write_back
+    }
 
-        // init prev and next field
-        ctrl.inner[0].prev = ptr::null_mut();
-        ctrl.inner[0].next = &mut ctrl.inner[1];
-        ctrl.inner[len-1].prev = &mut ctrl.inner[len-2];
-        ctrl.inner[len-1].next = ptr::null_mut();
-        for i in 1..(len-1) {
-            ctrl.inner[i].prev = &mut ctrl.inner[i-1];
-            ctrl.inner[i].next = &mut ctrl.inner[i+1];
+    /// The buf at `index`, whose refcnt was just taken.
+    fn buf_at(&self, index: usize, dev: u32, blockno: u32, rc_ptr: *mut usize) -> Buf<'_> {
+        Buf {
+            mgr: self,
+            index,
+            dev,
+            block_id: blockno,
+            rc_ptr,
+            data: Some(self.bufs[index].data.lock()),
         }
-        
-        // init index
-        ctrl.inner.iter_mut()
-            .enumerate()
-            .for_each(|(i, b)| b.index = i);
     }
 
     ///获取block device对应的buffer
-    fn bget(&self,block_device: Arc<dyn BlockDevice>, dev: u32, blockno: u32) -> Buf<'_> {
+    /// Fails with `Io` if the dirty buf to recycle cannot be written back.
+    fn bget(&self, dev: u32, blockno: u32) -> Result<Buf<'_>, FsError> {
         //debug!("bget blockno is {}",blockno);
-        let ctrl = self.ctrl.lock();
+        loop {
+            let mut ctrl = self.ctrl.lock();
 
-        // find cached block
-        match ctrl.find_cached(dev, blockno) {
-            Some((index, rc_ptr)) => {
-                // found
+            // find cached block
+            if let Some((index, rc_ptr)) = ctrl.find_cached(dev, blockno) {
                 drop(ctrl);
-                Buf {
-                    index,
-                    dev,
-                    block_device,
-                    block_id: blockno,
-                    rc_ptr,
-                    data: Some(self.bufs[index].data.lock()),
+                return Ok(self.buf_at(index, dev, blockno, rc_ptr));
+            }
+
+            // not cached
+            // recycle the least recently used (LRU) unused buffer
+            debug!("bget recycle blockno {}",blockno);
+            if let Some(index) = ctrl.lru_unused() {
+                if self.bufs[index].dirty.load(Ordering::Relaxed) {
+                    // write it back first, it stays cached until then
+                    let (old_dev, old_blockno) = (ctrl.inner[index].dev, ctrl.inner[index].blockno);
+                    let rc_ptr = ctrl.hold(index);
+                    drop(ctrl);
+                    self.buf_at(index, old_dev, old_blockno, rc_ptr).write_dirty()?;
+                    continue;
+                }
+                let rc_ptr = ctrl.reuse(index, dev, blockno);
+                ctrl.stats.misses += 1;
+                self.bufs[index].valid.store(false, Ordering::Relaxed);
+                drop(ctrl);
+                return Ok(self.buf_at(index, dev, blockno, rc_ptr));
+            }
+
+            // every buf is in use, wait for one to be released
+            self.waiters.wait(ctrl);
+        }
+    }
+
+    /// Read the blocks past a sequential read that are not cached yet.
+    pub fn prefetch(&self, dev: u32, blocknos: &[u32]) {
+        self.load(dev, blocknos, true)
+    }
+
+    /// Read the blocks that are not cached yet with as few device reads
+    /// as possible, before they are read one by one.
+    pub fn preload(&self, dev: u32, blocknos: &[u32]) {
+        self.load(dev, blocknos, false)
+    }
+
+    /// Read the blocks missing from the cache into unused bufs,
+    /// one device read per run of contiguous blocks, counting them as
+    /// read-ahead or as misses. It never waits for a buf or writes a dirty
+    /// one back, and since it only saves reads it stops quietly when it
+    /// cannot go on.
+    fn load(&self, dev: u32, blocknos: &[u32], ahead: bool) {
+        let mut missing: Vec<u32> = {
+            let ctrl = self.ctrl.lock();
+            blocknos.iter().copied().filter(|&b| ctrl.lookup(dev, b).is_none()).collect()
+        };
+        missing.sort_unstable();
+        missing.dedup();
+        // hold at most a quarter of the cache at a time
+        let max_run = (self.bufs.len() / 4).max(1);
+        for run in missing.chunk_by(|a, b| a + 1 == *b).flat_map(|run| run.chunks(max_run)) {
+            let mut ctrl = self.ctrl.lock();
+            let mut taken = Vec::with_capacity(run.len());
+            for &blockno in run {
+                if ctrl.lookup(dev, blockno).is_some() {
+                    break;
                 }
+                let index = match ctrl.lru_unused() {
+                    Some(index) if !self.bufs[index].dirty.load(Ordering::Relaxed) => index,
+                    _ => break,
+                };
+                let rc_ptr = ctrl.reuse(index, dev, blockno);
+                if ahead {
+                    ctrl.inner[index].prefetched = true;
+                    ctrl.stats.prefetched += 1;
+                } else {
+                    ctrl.stats.misses += 1;
+                }
+                self.bufs[index].valid.store(false, Ordering::Relaxed);
+                taken.push((index, blockno, rc_ptr));
+            }
+            drop(ctrl);
+            let mut bufs: Vec<Buf<'_>> = taken.into_iter()
+                .map(|(index, blockno, rc_ptr)| self.buf_at(index, dev, blockno, rc_ptr))
+                .collect();
+            if bufs.is_empty() {
+                return;
+            }
+            let mut data = vec![0u8; bufs.len() * BSIZE];
+            if self.block_device.read_blocks(run[0] as usize, &mut data).is_err() {
+                return;
+            }
+            for (buf, block) in bufs.iter_mut().zip(data.chunks(BSIZE)) {
+                // a bread may have got the buf first
+                if !self.bufs[buf.index].valid.load(Ordering::Relaxed) {
+                    buf.data_mut().copy_from_slice(block);
+                    self.bufs[buf.index].valid.store(true, Ordering::Relaxed);
+                }
+            }
+            if bufs.len() < run.len() {
+                return;
             }
-            None => {
-                // not cached
-                // recycle the least recently used (LRU) unused buffer
-                debug!("bget recycle blockno {}",blockno);
-                match ctrl.recycle(dev, blockno) {
-                    Some((index, rc_ptr)) => {
-                        self.bufs[index].valid.store(false, Ordering::Relaxed);
-                        //info!("end store!");
-                        drop(ctrl);
-                        //info!("end drop");
-                        return Buf {
-                            index,
-                            dev,
-                            block_device,
-                            block_id: blockno,
-                            rc_ptr,
-                            data: Some(self.bufs[index].data.lock()),
-                        }
+        }
+    }
+
+    /// The buf of a cached block, without counting a lookup.
+    fn cached(&self, dev: u32, blockno: u32) -> Option<Buf<'_>> {
+        let mut ctrl = self.ctrl.lock();
+        let index = ctrl.lookup(dev, blockno)?;
+        let rc_ptr = ctrl.hold(index);
+        drop(ctrl);
+        Some(self.buf_at(index, dev, blockno, rc_ptr))
+    }
+
+    /// Write contiguous blocks from `start` on straight to the device,
+    /// updating the cached copies.
+    pub fn write_blocks(&self, dev: u32, start: u32, data: &[u8]) -> Result<(), FsError> {
+        self.block_device.write_blocks(start as usize, data)?;
+        for (i, block) in data.chunks(BSIZE).enumerate() {
+            if let Some(mut buf) = self.cached(dev, start + i as u32) {
+                buf.data_mut().copy_from_slice(block);
+                self.bufs[buf.index].valid.store(true, Ordering::Relaxed);
+                self.bufs[buf.index].dirty.store(false, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the dirty bufs of these blocks back, one device write
+    /// per run of contiguous blocks. They must not change meanwhile,
+    /// as the blocks of a transaction being installed do not.
+    pub fn flush_blocks(&self, dev: u32, blocknos: &[u32]) -> Result<(), FsError> {
+        let mut blocknos = blocknos.to_vec();
+        blocknos.sort_unstable();
+        blocknos.dedup();
+        let mut data = Vec::new();
+        let mut start = 0;
+        for &blockno in blocknos.iter() {
+            if !data.is_empty() && blockno != start + (data.len() / BSIZE) as u32 {
+                self.write_blocks(dev, start, &data)?;
+                data.clear();
+            }
+            match self.cached(dev, blockno) {
+                Some(buf) if self.bufs[buf.index].dirty.load(Ordering::Relaxed) => {
+                    if data.is_empty() {
+                        start = blockno;
+                    }
+                    data.extend_from_slice(buf.data());
+                }
+                // clean, or written back when it was recycled
+                _ => {
+                    if !data.is_empty() {
+                        self.write_blocks(dev, start, &data)?;
+                        data.clear();
                     }
-                    None => panic!("no usable buffer")
                 }
             }
         }
+        if !data.is_empty() {
+            self.write_blocks(dev, start, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Write these copies of blocks home, one device write per run of
+    /// contiguous blocks, leaving the cache alone: a cached buf may have
+    /// changed since. Only a buf still holding its copy turns clean.
+    pub fn write_copies(&self, dev: u32, blocks: &[(u32, [u8; BSIZE])]) -> Result<(), FsError> {
+        let mut order: Vec<usize> = (0..blocks.len()).collect();
+        order.sort_unstable_by_key(|&i| blocks[i].0);
+        order.dedup_by_key(|i| blocks[*i].0);
+        let mut data = Vec::new();
+        let mut start = 0;
+        for &i in order.iter() {
+            let (blockno, ref block) = blocks[i];
+            if !data.is_empty() && blockno != start + (data.len() / BSIZE) as u32 {
+                self.block_device.write_blocks(start as usize, &data)?;
+                data.clear();
+            }
+            if data.is_empty() {
+                start = blockno;
+            }
+            data.extend_from_slice(block);
+        }
+        if !data.is_empty() {
+            self.block_device.write_blocks(start as usize, &data)?;
+        }
+        for (blockno, block) in blocks.iter() {
+            if let Some(buf) = self.cached(dev, *blockno) {
+                if buf.data() == block {
+                    self.bufs[buf.index].dirty.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the device these blocks are free, one request per run
+    /// of contiguous blocks. Their cached copies stay readable but are
+    /// no longer written back, which would map them again.
+    pub fn discard(&self, dev: u32, blocknos: &[u32]) -> Result<(), FsError> {
+        let mut blocknos = blocknos.to_vec();
+        blocknos.sort_unstable();
+        blocknos.dedup();
+        for &blockno in blocknos.iter() {
+            if let Some(buf) = self.cached(dev, blockno) {
+                self.bufs[buf.index].dirty.store(false, Ordering::Relaxed);
+            }
+        }
+        // the run being gathered, start..end
+        let (mut start, mut end) = (0, 0);
+        for &blockno in blocknos.iter() {
+            if blockno != end {
+                if end > start {
+                    self.block_device.discard(start as usize..end as usize)?;
+                }
+                start = blockno;
+            }
+            end = blockno + 1;
+        }
+        if end > start {
+            self.block_device.discard(start as usize..end as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Make the writes to the device so far durable.
+    pub fn flush(&self) -> Result<(), FsError> {
+        Ok(self.block_device.flush()?)
+    }
+
+    /// Write every dirty buf back, logged ones included:
+    /// no transaction may be open meanwhile, see `LogManager::sync`.
+    pub fn sync(&self) -> Result<(), FsError> {
+        for index in 0..self.bufs.len() {
+            let mut ctrl = self.ctrl.lock();
+            if !self.bufs[index].dirty.load(Ordering::Relaxed) {
+                continue;
+            }
+            let (dev, blockno) = (ctrl.inner[index].dev, ctrl.inner[index].blockno);
+            let rc_ptr = ctrl.hold(index);
+            drop(ctrl);
+            self.buf_at(index, dev, blockno, rc_ptr).write_dirty()?;
+        }
+        Ok(())
     }
 
      /// Get the buf from the cache/disk(block device)
-     pub fn bread<'a>(&'a self, dev: u32, block_id: u32) -> Buf<'a> {
+     /// A failed read leaves the buffer invalid, so the next bread retries the device.
+     pub fn bread<'a>(&'a self, dev: u32, block_id: u32) -> Result<Buf<'a>, FsError> {
         //info!("block id is {}",block_id);
-        let inner=self.inner.exclusive_access();
         //debug!("bread block id is {}",block_id);
-        let mut b = self.bget(Arc::clone(&inner.block_device), dev, block_id);
+        let mut b = self.bget(dev, block_id)?;
         //info!("end bget");
         if !self.bufs[b.index].valid.load(Ordering::Relaxed) {
             info!("not find block {} in cache!",block_id);
-            inner.block_device.read_block(block_id as usize, b.data.as_mut().unwrap().0.as_mut());
+            self.block_device.read_block(block_id as usize, b.data.as_mut().unwrap().0.as_mut())?;
             self.bufs[b.index].valid.store(true, Ordering::Relaxed);
         }
-        drop(inner);
-        b
+        Ok(b)
     }
 
     /// Move an unlocked buf to the head of the most-recently-used list.
+    /// Wake the bgets waiting for a buf if it is no longer used.
     fn brelse(&self, index: usize) {
-        self.ctrl.lock().move_if_no_ref(index);
+        let mut ctrl = self.ctrl.lock();
+        if ctrl.move_if_no_ref(index) {
+            drop(ctrl);
+            self.waiters.wake_all();
+        }
     }
 }
 
 //TODO！解决buffer data的互斥访问的问题
 /// A wrapper of raw buf data.
 pub struct Buf<'a>{
+    mgr: &'a BlockCacheManager,
     index: usize,
     dev: u32,
-    block_device: Arc<dyn BlockDevice>,
     block_id: u32,
     pub rc_ptr: *mut usize,     // pointer to its refcnt in BufCtrl
     /// Guaranteed to be Some during Buf's lifetime.
@@ -170,8 +407,44 @@ impl<'a> Buf<'a> {
     }
 
     ///write data into block device
-    pub fn bwrite(&mut self) {
-        self.block_device.write_block(self.block_id as usize, self.data.as_ref().unwrap().0.as_ref());
+    pub fn bwrite(&mut self) -> Result<(), FsError> {
+        self.mgr.block_device.write_block(self.block_id as usize, self.data.as_ref().unwrap().0.as_ref())?;
+        self.mgr.bufs[self.index].dirty.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write the buf, or with a write-back cache only mark it dirty.
+    pub fn bdwrite(&mut self) -> Result<(), FsError> {
+        if self.mgr.write_back {
+            self.mark_dirty();
+            Ok(())
+        } else {
+            self.bwrite()
+        }
+    }
+
+    /// Mark the buf changed, for a later `flush_blocks` or `sync`
+    /// to write it, or for its recycling to write it back.
+    pub fn mark_dirty(&mut self) {
+        self.mgr.bufs[self.index].dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the buf if it is dirty.
+    fn write_dirty(&mut self) -> Result<(), FsError> {
+        if self.mgr.bufs[self.index].dirty.load(Ordering::Relaxed) {
+            self.bwrite()?;
+        }
+        Ok(())
+    }
+
+    /// The bytes of the block.
+    pub fn data(&self) -> &[u8; BSIZE] {
+        &self.data.as_ref().unwrap().0
+    }
+
+    /// The bytes of the block, for changing it.
+    pub fn data_mut(&mut self) -> &mut [u8; BSIZE] {
+        &mut self.data.as_mut().unwrap().0
     }
 
     /// Gives out a raw const pointer at the buf data. 
@@ -187,17 +460,20 @@ impl<'a> Buf<'a> {
     }
 
     /// Pin the buf.
+    /// The refcnt is guarded by the bcache spinlock like the lru list,
+    /// since ops ending on other threads pin and unpin bufs concurrently.
     /// SAFETY: it should be definitly safe.
     ///     Because the current refcnt >= 1, so the rc_ptr is valid.
     pub unsafe fn pin(&self) {
-        let rc = *self.rc_ptr;
-        *self.rc_ptr = rc + 1;
+        let _ctrl = self.mgr.ctrl.lock();
+        *self.rc_ptr += 1;
         //info!("buf {} rc +1 = {}",self.block_id,*self.rc_ptr);
     }
 
     /// Unpin the buf.
     /// SAFETY: it should be called matching pin.
     pub unsafe fn unpin(&self) {
+        let _ctrl = self.mgr.ctrl.lock();
         //info!("buf {} rc = {}",self.block_id,*self.rc_ptr);
         let rc = *self.rc_ptr;
         if rc <= 1 {
@@ -210,109 +486,171 @@ impl<'a> Buf<'a> {
 impl<'a> Drop for Buf<'a> {
     fn drop(&mut self) {
         drop(self.data.take());
-        BLOCK_CACHE_MANAGER.brelse(self.index);        
+        self.mgr.brelse(self.index);
     }
 }
 
+/// Marks the end of the lru list and of the hash chains
+const NIL: usize = usize::MAX;
+
 struct BufLru {
-    inner: [BufCtrl; NBUF],
-    head: *mut BufCtrl,
-    tail: *mut BufCtrl,
+    inner: Vec<BufCtrl>,
+    /// first buf of each hash chain
+    buckets: Vec<usize>,
+    /// most recently used
+    head: usize,
+    /// least recently used
+    tail: usize,
+    /// counters, the capacity and window are filled in by `stats`
+    stats: CacheStats,
 }
 
-/// Raw pointers are automatically thread-unsafe.
-/// See doc https://doc.rust-lang.org/nomicon/send-and-sync.html.
-unsafe impl Send for BufLru {}
-
 impl BufLru {
-    const fn new() -> Self {
+    fn new(nbuf: usize) -> Self {
+        let mut inner: Vec<BufCtrl> = (0..nbuf).map(|_| BufCtrl::new()).collect();
+        for (i, b) in inner.iter_mut().enumerate() {
+            b.prev = if i == 0 { NIL } else { i - 1 };
+            b.next = if i + 1 == nbuf { NIL } else { i + 1 };
+        }
         Self {
-            inner: array![_ => BufCtrl::new(); NBUF],
-            head: ptr::null_mut(),
-            tail: ptr::null_mut(),
+            inner,
+            buckets: vec![NIL; nbuf.next_power_of_two()],
+            head: if nbuf == 0 { NIL } else { 0 },
+            tail: nbuf.wrapping_sub(1),
+            stats: CacheStats::default(),
         }
     }
 
+    fn bucket(&self, dev: u32, blockno: u32) -> usize {
+        let key = ((dev as u64) << 32 | blockno as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (key >> 32) as usize & (self.buckets.len() - 1)
+    }
+
     /// Find if the requested block is cached.
     /// Return its index and incr the refcnt if found.
-    fn find_cached(&self, dev: u32, blockno: u32) -> Option<(usize, *mut usize)> {
-        let mut b = self.head;
-        while !b.is_null() {
-            let bref = unsafe { b.as_mut().unwrap() };
-            if bref.blockno == blockno {
-                bref.refcnt += 1;
-                return Some((bref.index, &mut bref.refcnt));
+    fn find_cached(&mut self, dev: u32, blockno: u32) -> Option<(usize, *mut usize)> {
+        let i = self.lookup(dev, blockno)?;
+        self.stats.hits += 1;
+        if self.inner[i].prefetched {
+            self.inner[i].prefetched = false;
+            self.stats.prefetch_hits += 1;
+        }
+        Some((i, self.hold(i)))
+    }
+
+    /// The index of the buf holding the block, without taking a reference.
+    fn lookup(&self, dev: u32, blockno: u32) -> Option<usize> {
+        let mut i = self.buckets[self.bucket(dev, blockno)];
+        while i != NIL {
+            if self.inner[i].dev == dev && self.inner[i].blockno == blockno {
+                return Some(i);
             }
-            b = bref.next;
+            i = self.inner[i].hnext;
         }
         None
     }
 
-    /// Recycle an unused buffer from the tail.
-    /// Return its index if found.
-    fn recycle(&self, dev: u32, blockno: u32) -> Option<(usize, *mut usize)> {
+    /// The least recently used buf nobody holds.
+    fn lru_unused(&self) -> Option<usize> {
+        let mut i = self.tail;
+        while i != NIL && self.inner[i].refcnt != 0 {
+            i = self.inner[i].prev;
+        }
+        if i == NIL { None } else { Some(i) }
+    }
+
+    /// Take a reference to a buf, keeping the block it holds.
+    fn hold(&mut self, index: usize) -> *mut usize {
+        self.inner[index].refcnt += 1;
+        &mut self.inner[index].refcnt
+    }
+
+    /// Recycle an unused buf for another block, taking a reference to it.
+    fn reuse(&mut self, index: usize, dev: u32, blockno: u32) -> *mut usize {
         debug!("[Xv6fs] BLOCK CACHE MANAGER: recycle unused buffer {}",blockno);
-        let mut b = self.tail;
-        while !b.is_null() {
-            let bref = unsafe { b.as_mut().unwrap() };
-            if bref.refcnt == 0 {
-                bref.dev = dev;
-                bref.blockno = blockno;
-                bref.refcnt += 1;
-                info!("end recycle");
-                return Some((bref.index, &mut bref.refcnt));
-            }
-            b = bref.prev;
+        if self.inner[index].hashed {
+            self.unhash(index);
         }
-        None
+        let bucket = self.bucket(dev, blockno);
+        let b = &mut self.inner[index];
+        b.dev = dev;
+        b.blockno = blockno;
+        b.refcnt += 1;
+        b.hashed = true;
+        b.prefetched = false;
+        b.hnext = self.buckets[bucket];
+        self.buckets[bucket] = index;
+        &mut b.refcnt
+    }
+
+    /// Take a buf out of its hash chain.
+    fn unhash(&mut self, index: usize) {
+        let bucket = self.bucket(self.inner[index].dev, self.inner[index].blockno);
+        let next = self.inner[index].hnext;
+        if self.buckets[bucket] == index {
+            self.buckets[bucket] = next;
+            return;
+        }
+        let mut i = self.buckets[bucket];
+        while self.inner[i].hnext != index {
+            i = self.inner[i].hnext;
+        }
+        self.inner[i].hnext = next;
     }
 
     /// Move an entry to the head if no live ref.
-    fn move_if_no_ref(&mut self, index: usize) {
-        let b = &mut self.inner[index];
-        b.refcnt -= 1;
-        if b.refcnt == 0 && !ptr::eq(self.head, b) {
-            // forward the tail if b is at the tail
-            // b may be the only entry in the lru list
-            if ptr::eq(self.tail, b) && !b.prev.is_null() {
-                self.tail = b.prev;
-            }
-            
-            // detach b
-            unsafe {
-                b.next.as_mut().map(|b_next| b_next.prev = b.prev);
-                b.prev.as_mut().map(|b_prev| b_prev.next = b.next);
+    /// Returns whether it became unused.
+    fn move_if_no_ref(&mut self, index: usize) -> bool {
+        self.inner[index].refcnt -= 1;
+        if self.inner[index].refcnt != 0 {
+            return false;
+        }
+        if self.head != index {
+            let (prev, next) = (self.inner[index].prev, self.inner[index].next);
+            // detach b, forwarding the tail if b is at the tail
+            self.inner[prev].next = next;
+            if next == NIL {
+                self.tail = prev;
+            } else {
+                self.inner[next].prev = prev;
             }
 
             // attach b
-            b.prev = ptr::null_mut();
-            b.next = self.head;
-            unsafe {
-                self.head.as_mut().map(|old_head| old_head.prev = b);
-            }
-            self.head = b;
+            self.inner[index].prev = NIL;
+            self.inner[index].next = self.head;
+            let head = self.head;
+            self.inner[head].prev = index;
+            self.head = index;
         }
+        true
     }
 }
 
 struct BufCtrl {
     dev: u32,
     blockno: u32,
-    prev: *mut BufCtrl,
-    next: *mut BufCtrl,
+    prev: usize,
+    next: usize,
+    /// next buf in the same hash chain
+    hnext: usize,
+    /// the buf holds a block and is in a hash chain
+    hashed: bool,
+    /// read by read-ahead and not looked up since
+    prefetched: bool,
     refcnt: usize,
-    index: usize,
 }
 
 impl BufCtrl {
-    const fn new() -> Self {
+    fn new() -> Self {
         Self {
             dev: 0,
             blockno: 0,
-            prev: ptr::null_mut(),
-            next: ptr::null_mut(),
+            prev: NIL,
+            next: NIL,
+            hnext: NIL,
+            hashed: false,
+            prefetched: false,
             refcnt: 0,
-            index: 0,
         }
     }
 }
@@ -322,6 +660,8 @@ struct BufInner {
     // the bcache spinlock and the relevant buf sleeplock
     // holding either of which can get access to them
     valid: AtomicBool,
+    /// changed since it was last written, set with its sleeplock held
+    dirty: AtomicBool,
     data: SleepLock<BufData>,
 }
 
@@ -329,6 +669,7 @@ impl BufInner {
     fn new() -> Self {
         Self {
             valid: AtomicBool::new(false),
+            dirty: AtomicBool::new(false),
             data: SleepLock::new(BufData::new(),init_lock()),
         }
     }
@@ -0,0 +1,221 @@
+//! Crash-consistency testing.
+//!
+//! A [`RecordingDevice`] keeps every block written through it, in order.
+//! [`crash_images`] replays each prefix of such a write stream onto the
+//! image the workload started from, giving the disk a crash after that
+//! many writes leaves behind, along with torn variants where the next
+//! write reached only half of its block. Mounting each image runs log
+//! recovery, after which the file system must be consistent and show
+//! the state from before or after the workload.
+//!
+//! The device also records its flushes, and [`reordered_images`] gives
+//! the disks a volatile write cache may leave, losing a write that a
+//! later one between the same two flushes overtook. Discarded blocks
+//! are recorded as zeroed.
+//!
+//! ```ignore
+//! let mem = Arc::new(MemDevice::from_image(before.clone()));
+//! let rec = Arc::new(RecordingDevice::new(mem.clone()));
+//! workload(&Xv6FS::open(rec.clone(), 1)?);
+//! for crash in crash_images(&before, &rec.take_writes()) {
+//!     let fs = Xv6FS::open(Arc::new(MemDevice::from_image(crash.image)), 1)?;
+//!     assert_eq!(fsck::check(&fs)?, vec![]);
+//! }
+//! ```
+
+use core::ops::Range;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::block_dev::{BlockDevice, DevResult};
+use crate::fs_const::BSIZE;
+
+/// A device passing everything to another one
+/// and recording the blocks written to it.
+pub struct RecordingDevice {
+    inner: Arc<dyn BlockDevice>,
+    writes: Mutex<Vec<(usize, Vec<u8>)>>,
+    /// Number of writes recorded at each flush
+    flushes: Mutex<Vec<usize>>,
+}
+
+impl RecordingDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self { inner, writes: Mutex::new(Vec::new()), flushes: Mutex::new(Vec::new()) }
+    }
+
+    /// The flushes so far, as the number of writes recorded before each.
+    pub fn flushes(&self) -> Vec<usize> {
+        self.flushes.lock().clone()
+    }
+
+    /// Number of writes recorded so far.
+    pub fn len(&self) -> usize {
+        self.writes.lock().len()
+    }
+
+    /// The recorded writes, oldest first, as block id and content.
+    pub fn writes(&self) -> Vec<(usize, Vec<u8>)> {
+        self.writes.lock().clone()
+    }
+
+    /// Take the recorded writes and start over, forgetting the flushes.
+    pub fn take_writes(&self) -> Vec<(usize, Vec<u8>)> {
+        self.flushes.lock().clear();
+        core::mem::take(&mut *self.writes.lock())
+    }
+}
+
+impl BlockDevice for RecordingDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        self.inner.read_block(block_id, buf)
+    }
+
+    /// Only writes the device took are recorded.
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        self.inner.write_block(block_id, buf)?;
+        self.writes.lock().push((block_id, buf.to_vec()));
+        Ok(())
+    }
+
+    fn flush(&self) -> DevResult {
+        self.inner.flush()?;
+        let writes = self.writes.lock().len();
+        self.flushes.lock().push(writes);
+        Ok(())
+    }
+
+    /// Recorded as writes of zeros, so that a crash image
+    /// shows what the discarded blocks held gone.
+    fn discard(&self, blocks: Range<usize>) -> DevResult {
+        self.inner.discard(blocks.clone())?;
+        let mut writes = self.writes.lock();
+        for block_id in blocks {
+            writes.push((block_id, vec![0; BSIZE]));
+        }
+        Ok(())
+    }
+}
+
+/// Which half of a torn write reached the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Torn {
+    Head,
+    Tail,
+}
+
+/// The disk as a crash left it.
+pub struct CrashImage {
+    /// Writes that fully reached the disk
+    pub writes: usize,
+    /// How the write after them was torn, if it was
+    pub torn: Option<Torn>,
+    /// One of them that never reached the disk after all
+    pub lost: Option<usize>,
+    pub image: Vec<u8>,
+}
+
+/// Every image a crash during `writes` may leave `base` in:
+/// each prefix, from none to all of the writes,
+/// and each prefix followed by either half of the next write.
+pub fn crash_images<'a>(base: &[u8], writes: &'a [(usize, Vec<u8>)]) -> CrashImages<'a> {
+    CrashImages { image: base.to_vec(), writes, done: 0, torn: None, yielded: false }
+}
+
+/// Iterator returned by [`crash_images`].
+pub struct CrashImages<'a> {
+    image: Vec<u8>,
+    writes: &'a [(usize, Vec<u8>)],
+    /// Writes applied to `image`
+    done: usize,
+    /// The last variant yielded for this prefix
+    torn: Option<Torn>,
+    yielded: bool,
+}
+
+impl CrashImages<'_> {
+    /// Apply part of the next write to `image`.
+    fn apply(&self, image: &mut [u8], torn: Option<Torn>) {
+        let (block_id, content) = &self.writes[self.done];
+        let half = content.len() / 2;
+        let range = match torn {
+            None => 0..content.len(),
+            Some(Torn::Head) => 0..half,
+            Some(Torn::Tail) => half..content.len(),
+        };
+        let start = block_id * BSIZE;
+        image[start + range.start..start + range.end].copy_from_slice(&content[range]);
+    }
+}
+
+impl Iterator for CrashImages<'_> {
+    type Item = CrashImage;
+
+    fn next(&mut self) -> Option<CrashImage> {
+        if !self.yielded {
+            if self.done > self.writes.len() {
+                return None;
+            }
+            self.yielded = true;
+            return Some(CrashImage { writes: self.done, torn: None, lost: None, image: self.image.clone() });
+        }
+        if self.done == self.writes.len() {
+            self.done += 1;
+            self.yielded = false;
+            return self.next();
+        }
+        let torn = match self.torn {
+            None => Torn::Head,
+            Some(Torn::Head) => Torn::Tail,
+            Some(Torn::Tail) => {
+                let mut image = core::mem::take(&mut self.image);
+                self.apply(&mut image, None);
+                self.image = image;
+                self.done += 1;
+                self.torn = None;
+                self.yielded = false;
+                return self.next();
+            }
+        };
+        self.torn = Some(torn);
+        let mut image = self.image.clone();
+        self.apply(&mut image, Some(torn));
+        Some(CrashImage { writes: self.done, torn: Some(torn), lost: None, image })
+    }
+}
+
+/// The images a crash may leave `base` in when the device caches writes
+/// and only `flushes` order them: for every write, the disk holding
+/// all the writes up to the next flush, or to the end, but that one.
+/// The prefixes are left to [`crash_images`].
+pub fn reordered_images(base: &[u8], writes: &[(usize, Vec<u8>)], flushes: &[usize]) -> Vec<CrashImage> {
+    let apply = |image: &mut [u8], (block_id, content): &(usize, Vec<u8>)| {
+        image[block_id * BSIZE..block_id * BSIZE + content.len()].copy_from_slice(content);
+    };
+    let mut images = Vec::new();
+    let mut image = base.to_vec();
+    let mut start = 0;
+    for end in flushes.iter().copied().chain(core::iter::once(writes.len())) {
+        if end <= start {
+            continue;
+        }
+        for lost in start..end - 1 {
+            let mut crashed = image.clone();
+            for (i, write) in writes[start..end].iter().enumerate() {
+                // a later write to the same block overwrites it anyway
+                if start + i != lost {
+                    apply(&mut crashed, write);
+                }
+            }
+            images.push(CrashImage { writes: end, torn: None, lost: Some(lost), image: crashed });
+        }
+        for write in &writes[start..end] {
+            apply(&mut image, write);
+        }
+        start = end;
+    }
+    images
+}
@@ -1,6 +1,8 @@
 use core::ptr;
 
+use crate::error::FsError;
 use crate::fs_const::{ NDIRECT, DIRSIZ };
+use crate::misc::{get_u16, get_u32, put_u16, put_u32};
 
 #[repr(u16)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -11,7 +13,31 @@ pub enum InodeType {
     Device = 3
 }
 
-/// On-disk inode structure
+impl InodeType {
+    /// The type stored on disk as `raw`, if it is one.
+    pub fn from_u16(raw: u16) -> Option<Self> {
+        match raw {
+            0 => Some(InodeType::Empty),
+            1 => Some(InodeType::Directory),
+            2 => Some(InodeType::File),
+            3 => Some(InodeType::Device),
+            _ => None,
+        }
+    }
+}
+
+/// On-disk inode structure.
+///
+/// Stored in `DiskInode::SIZE` bytes, little-endian:
+///
+/// | offset | size | field |
+/// |--------|------|-------|
+/// | 0      | 2    | itype |
+/// | 2      | 2    | major |
+/// | 4      | 2    | minor |
+/// | 6      | 2    | nlink |
+/// | 8      | 4    | size  |
+/// | 12     | 52   | addrs, `NDIRECT+2` block numbers |
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct DiskInode {
@@ -23,14 +49,22 @@ pub struct DiskInode {
     pub addrs: [u32; NDIRECT+2] // Data block addresses
 }
 
+/// Directory entry.
+///
+/// Stored in `DirEntry::SIZE` bytes: the inode number as a
+/// little-endian `u16` at offset 0, then the name, NUL padded,
+/// at offset 2. Inode number 0 marks a free entry.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct DirEntry {
     pub inum: u16,
     pub name:[u8;DIRSIZ]
 }
 
 impl DiskInode {
+    /// Size of an inode on disk.
+    pub const SIZE: usize = 64;
+
     pub const fn new() -> Self {
         Self {
             itype: InodeType::Empty,
@@ -51,13 +85,59 @@ impl DiskInode {
             Err(())
         }
     }
+
+    /// Decode an inode from the first `DiskInode::SIZE` bytes of `raw`.
+    /// Fails with `Corrupted` on an unknown type.
+    pub fn decode(raw: &[u8]) -> Result<Self, FsError> {
+        let itype = InodeType::from_u16(get_u16(raw, 0)).ok_or(FsError::Corrupted)?;
+        let mut addrs = [0; NDIRECT+2];
+        for (i, addr) in addrs.iter_mut().enumerate() {
+            *addr = get_u32(raw, 12 + 4 * i);
+        }
+        Ok(Self {
+            itype,
+            major: get_u16(raw, 2) as i16,
+            minor: get_u16(raw, 4) as i16,
+            nlink: get_u16(raw, 6) as i16,
+            size: get_u32(raw, 8),
+            addrs,
+        })
+    }
+
+    /// Encode the inode into the first `DiskInode::SIZE` bytes of `raw`.
+    pub fn encode(&self, raw: &mut [u8]) {
+        put_u16(raw, 0, self.itype as u16);
+        put_u16(raw, 2, self.major as u16);
+        put_u16(raw, 4, self.minor as u16);
+        put_u16(raw, 6, self.nlink as u16);
+        put_u32(raw, 8, self.size);
+        for (i, addr) in self.addrs.iter().enumerate() {
+            put_u32(raw, 12 + 4 * i, *addr);
+        }
+    }
 }
 
 impl DirEntry {
+    /// Size of a directory entry on disk.
+    pub const SIZE: usize = 2 + DIRSIZ;
+
     pub const fn new() -> Self {
         Self {
             inum: 0,
             name: [0;DIRSIZ]
         }
     }
-}
\ No newline at end of file
+
+    /// Decode an entry from the first `DirEntry::SIZE` bytes of `raw`.
+    pub fn decode(raw: &[u8]) -> Self {
+        let mut name = [0; DIRSIZ];
+        name.copy_from_slice(&raw[2..2 + DIRSIZ]);
+        Self { inum: get_u16(raw, 0), name }
+    }
+
+    /// Encode the entry into the first `DirEntry::SIZE` bytes of `raw`.
+    pub fn encode(&self, raw: &mut [u8]) {
+        put_u16(raw, 0, self.inum);
+        raw[2..2 + DIRSIZ].copy_from_slice(&self.name);
+    }
+}
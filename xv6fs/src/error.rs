@@ -0,0 +1,96 @@
+//! File system errors
+
+use core::fmt;
+
+use crate::block_dev::DevError;
+
+/// Errors returned by the file system operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory
+    NotFound,
+    /// The entry already exists
+    Exists,
+    /// A path component is not a directory
+    NotDir,
+    /// The operation is not allowed on a directory
+    IsDir,
+    /// The directory is not empty
+    NotEmpty,
+    /// No free data block, or no room left in the log
+    NoSpace,
+    /// No free on-disk inode
+    NoInodes,
+    /// All in-memory inode slots are in use
+    CacheFull,
+    /// A path component is longer than `DIRSIZ - 1`
+    NameTooLong,
+    /// The file would grow beyond `MAXFILE` blocks
+    FileTooLarge,
+    /// The file was not opened for this kind of access
+    BadFile,
+    /// The operation is not permitted, e.g. hard-linking a directory
+    PermissionDenied,
+    /// Bad argument
+    InvalidArg,
+    /// The block device failed
+    Io,
+    /// The on-disk structures are inconsistent
+    Corrupted,
+    /// The file system is mounted read-only
+    ReadOnly,
+}
+
+impl FsError {
+    /// The errno value (as used by Linux) for this error.
+    pub fn to_errno(self) -> i32 {
+        match self {
+            FsError::NotFound => 2,          // ENOENT
+            FsError::Exists => 17,           // EEXIST
+            FsError::NotDir => 20,           // ENOTDIR
+            FsError::IsDir => 21,            // EISDIR
+            FsError::NotEmpty => 39,         // ENOTEMPTY
+            FsError::NoSpace => 28,          // ENOSPC
+            FsError::NoInodes => 28,         // ENOSPC
+            FsError::CacheFull => 23,        // ENFILE
+            FsError::NameTooLong => 36,      // ENAMETOOLONG
+            FsError::FileTooLarge => 27,     // EFBIG
+            FsError::BadFile => 9,           // EBADF
+            FsError::PermissionDenied => 1,  // EPERM
+            FsError::InvalidArg => 22,       // EINVAL
+            FsError::Io => 5,                // EIO
+            FsError::Corrupted => 117,       // EUCLEAN
+            FsError::ReadOnly => 30,         // EROFS
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::Exists => "file exists",
+            FsError::NotDir => "not a directory",
+            FsError::IsDir => "is a directory",
+            FsError::NotEmpty => "directory not empty",
+            FsError::NoSpace => "no space left on device",
+            FsError::NoInodes => "no free inodes",
+            FsError::CacheFull => "inode cache is full",
+            FsError::NameTooLong => "file name too long",
+            FsError::FileTooLarge => "file too large",
+            FsError::BadFile => "bad file access mode",
+            FsError::PermissionDenied => "operation not permitted",
+            FsError::InvalidArg => "invalid argument",
+            FsError::Io => "I/O error",
+            FsError::Corrupted => "file system is corrupted",
+            FsError::ReadOnly => "read-only file system",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<DevError> for FsError {
+    fn from(_: DevError) -> Self {
+        FsError::Io
+    }
+}
@@ -0,0 +1,236 @@
+//! Fault injection, for testing how the layers above a device
+//! handle its errors.
+//!
+//! A [`FaultDevice`] passes reads and writes to another device until one
+//! of its [`Fault`]s triggers: the access then fails, sees corrupted or
+//! short data, or finds the device gone for good.
+//!
+//! ```ignore
+//! let dev = Arc::new(FaultDevice::new(inner));
+//! // the third write from now fails
+//! dev.inject(Fault::new(Access::Write, Trigger::Nth(2), Effect::Fail(DevError::Io)).times(1));
+//! ```
+
+use core::ops::Range;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::block_dev::{BlockDevice, DevError, DevResult};
+
+/// The accesses a fault applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Both,
+}
+
+impl Access {
+    fn covers(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::Both => true,
+        }
+    }
+}
+
+/// Which of the accesses a fault applies to trigger it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Every one
+    Always,
+    /// Those to this block
+    Block(usize),
+    /// The nth one counted from when the fault was injected, from 0
+    Nth(usize),
+    /// One in that many, drawn from the seeded generator of the device
+    OneIn(u32),
+}
+
+/// What a triggered fault does to the access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Fail without touching the device
+    Fail(DevError),
+    /// Flip the bits of one byte, of the data read or the data written
+    Corrupt,
+    /// Transfer only the first bytes: a read sees zeros past them,
+    /// a write leaves the rest of the block as it was
+    Short(usize),
+    /// Fail this access and every later one with `NoDevice`
+    Gone,
+}
+
+/// A programmed fault of a [`FaultDevice`].
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub access: Access,
+    pub trigger: Trigger,
+    pub effect: Effect,
+    /// How many times it triggers before it is disarmed, `None` for no limit
+    pub times: Option<usize>,
+    /// Accesses it applies to seen so far
+    seen: usize,
+}
+
+impl Fault {
+    pub fn new(access: Access, trigger: Trigger, effect: Effect) -> Self {
+        Self { access, trigger, effect, times: None, seen: 0 }
+    }
+
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+}
+
+struct FaultState {
+    faults: Vec<Fault>,
+    /// xorshift64 state, never 0
+    rng: u64,
+    gone: bool,
+    triggered: usize,
+}
+
+impl FaultState {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// The effect on an access, if a fault triggers.
+    /// Every fault counts the access, the first one injected that triggers wins.
+    fn effect(&mut self, block_id: usize, write: bool) -> Option<(Effect, u64)> {
+        if self.gone {
+            return Some((Effect::Gone, 0));
+        }
+        let mut first = None;
+        for i in 0..self.faults.len() {
+            if !self.faults[i].access.covers(write) || self.faults[i].times == Some(0) {
+                continue;
+            }
+            let seen = self.faults[i].seen;
+            self.faults[i].seen += 1;
+            let hit = match self.faults[i].trigger {
+                Trigger::Always => true,
+                Trigger::Block(b) => b == block_id,
+                Trigger::Nth(n) => n == seen,
+                Trigger::OneIn(n) => self.next_random() % n.max(1) as u64 == 0,
+            };
+            if hit && first.is_none() {
+                first = Some(i);
+            }
+        }
+        let fault = &mut self.faults[first?];
+        if let Some(times) = fault.times.as_mut() {
+            *times -= 1;
+        }
+        let effect = fault.effect;
+        if effect == Effect::Gone {
+            self.gone = true;
+        }
+        self.faults.retain(|fault| fault.times != Some(0));
+        self.triggered += 1;
+        Some((effect, self.next_random()))
+    }
+}
+
+/// A device wrapping another one and failing as it is told to.
+pub struct FaultDevice {
+    inner: Arc<dyn BlockDevice>,
+    state: Mutex<FaultState>,
+}
+
+impl FaultDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self::with_seed(inner, 1)
+    }
+
+    /// A device whose `OneIn` faults draw from a generator seeded with `seed`,
+    /// so that a run can be repeated.
+    pub fn with_seed(inner: Arc<dyn BlockDevice>, seed: u64) -> Self {
+        let state = FaultState { faults: Vec::new(), rng: seed.max(1), gone: false, triggered: 0 };
+        Self { inner, state: Mutex::new(state) }
+    }
+
+    /// Add a fault, tried after the ones already there.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().faults.push(fault);
+    }
+
+    /// Remove every fault, and bring a device that went away back.
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.faults.clear();
+        state.gone = false;
+    }
+
+    /// Number of accesses a fault was applied to.
+    pub fn triggered(&self) -> usize {
+        self.state.lock().triggered
+    }
+}
+
+impl BlockDevice for FaultDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        let effect = self.state.lock().effect(block_id, false);
+        match effect {
+            None => self.inner.read_block(block_id, buf),
+            Some((Effect::Fail(err), _)) => Err(err),
+            Some((Effect::Gone, _)) => Err(DevError::NoDevice),
+            Some((Effect::Corrupt, random)) => {
+                self.inner.read_block(block_id, buf)?;
+                buf[random as usize % buf.len()] ^= 0xff;
+                Ok(())
+            }
+            Some((Effect::Short(len), _)) => {
+                self.inner.read_block(block_id, buf)?;
+                let len = len.min(buf.len());
+                buf[len..].fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        let effect = self.state.lock().effect(block_id, true);
+        match effect {
+            None => self.inner.write_block(block_id, buf),
+            Some((Effect::Fail(err), _)) => Err(err),
+            Some((Effect::Gone, _)) => Err(DevError::NoDevice),
+            Some((Effect::Corrupt, random)) => {
+                let mut data = buf.to_vec();
+                data[random as usize % buf.len()] ^= 0xff;
+                self.inner.write_block(block_id, &data)
+            }
+            Some((Effect::Short(len), _)) => {
+                let mut data = buf.to_vec();
+                self.inner.read_block(block_id, &mut data)?;
+                let len = len.min(buf.len());
+                data[..len].copy_from_slice(&buf[..len]);
+                self.inner.write_block(block_id, &data)
+            }
+        }
+    }
+
+    /// Passed on, or failed once the device is gone.
+    fn flush(&self) -> DevResult {
+        if self.state.lock().gone {
+            return Err(DevError::NoDevice);
+        }
+        self.inner.flush()
+    }
+
+    /// Passed on, or failed once the device is gone.
+    fn discard(&self, blocks: Range<usize>) -> DevResult {
+        if self.state.lock().gone {
+            return Err(DevError::NoDevice);
+        }
+        self.inner.discard(blocks)
+    }
+}
@@ -1,13 +1,15 @@
 use crate::bitmap::inode_alloc;
 use crate::disk_inode::{InodeType,DirEntry};
-use crate::fs_const::{ BSIZE, MAXOPBLOCKS, DIRSIZ };
-use crate::inode::{ICACHE,Inode, InodeData};
+use crate::error::FsError;
+use crate::fs_const::{ BSIZE, MAXOPBLOCKS, DIRSIZ, NINDIRECT };
+use crate::inode::{Inode, InodeData};
+use crate::xv6fs::Xv6FS;
 use super::stat::Stat;
-use crate::log::{LOG_MANAGER};
 use alloc::vec::Vec;
+use alloc::sync::Arc;
 use alloc::string::String;
 use axlog::{info, debug};
-use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u16)]
@@ -38,16 +40,29 @@ pub enum FileInner {
 
 /// Virtual File, which can abstract struct to dispatch 
 /// syscall to specific file.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct VFile {
     pub(crate) ftype: FileType,
     pub(crate) readable: bool,
     pub(crate) writeable: bool,
     pub(crate) inode: Option<Inode>,
-    pub(crate) offset: u32
+    /// advanced by every read
+    pub(crate) offset: AtomicU32
     // inner: FileInner
 }
 
+impl Clone for VFile {
+    fn clone(&self) -> Self {
+        Self {
+            ftype: self.ftype,
+            readable: self.readable,
+            writeable: self.writeable,
+            inode: self.inode.clone(),
+            offset: AtomicU32::new(self.offset.load(Ordering::Relaxed)),
+        }
+    }
+}
+
 impl VFile {
     pub const fn init() -> Self {
         Self{
@@ -55,16 +70,38 @@ impl VFile {
             readable: false,
             writeable: false,
             inode: None,
-            offset: 0,
+            offset: AtomicU32::new(0),
         }
     }
 
-    pub fn get_size(&self)->usize{
+    /// Open an inode the lookup way, readable and writeable. 
+    pub fn from_inode(inode: Inode) -> Result<Self, FsError> {
+        let guard=inode.lock()?;
+        let ftype=match guard.dinode.itype{
+            InodeType::Directory=>FileType::Directory,
+            _=>FileType::File,
+        };
+        drop(guard);
+        let writeable=!inode.fs().is_read_only();
+        Ok(Self { ftype, readable:true, writeable, inode:Some(inode), offset:AtomicU32::new(0)})
+    }
+
+    /// The inode behind this file. 
+    pub fn inode(&self) -> Option<&Inode> {
+        self.inode.as_ref()
+    }
+
+    /// The file system this file belongs to.
+    pub fn fs(&self) -> &Arc<Xv6FS> {
+        self.inode.as_ref().unwrap().fs()
+    }
+
+    pub fn get_size(&self)->Result<usize,FsError>{
         let node=self.inode.as_ref().unwrap();
-        let guard=node.lock();
+        let guard=node.lock()?;
         let res=guard.dinode.size;
         drop(guard);
-        res as usize
+        Ok(res as usize)
     }
 
     ///addr is destination address 
@@ -73,22 +110,21 @@ impl VFile {
         addr: usize,
         offset: usize,
         len: usize
-    ) -> Result<usize, &'static str> {
+    ) -> Result<usize, FsError> {
         let ret;
         if !self.vfile_readable() {
-            panic!("File can't be read!")
+            return Err(FsError::BadFile)
         }
 
         match self.ftype {
             FileType::File|FileType::Directory => {
                 let inode = self.inode.as_ref().unwrap();
-                let mut inode_guard = inode.lock();
+                let mut inode_guard = inode.lock()?;
                 debug!("offset is {}",offset);
                 match inode_guard.read( addr, offset as u32, len as u32) {
                     Ok(size) => {
                         ret = size;
-                        let offset = unsafe { &mut *(&self.offset as *const _ as *mut u32)};
-                        *offset += ret as u32;
+                        self.offset.fetch_add(ret as u32, Ordering::Relaxed);
                         drop(inode_guard);
                         Ok(ret)
                     },
@@ -99,7 +135,7 @@ impl VFile {
             },
 
             _ => {
-                panic!("Invalid file!")
+                Err(FsError::InvalidArg)
             },
         }
     }
@@ -112,10 +148,30 @@ impl VFile {
         &self, 
         addr: usize, 
         len: usize
-    ) -> Result<usize, &'static str> {
+    ) -> Result<usize, FsError> {
+        self.vfile_write_at(addr, 0, len)
+    }
+
+    pub fn vfile_append(
+        &self, 
+        addr: usize, 
+        len: usize
+    ) -> Result<usize, FsError> {
+        let offset = self.vfile_size()?;
+        self.vfile_write_at(addr, offset, len)
+    }
+
+    /// Write len bytes from addr at offset, in as many transactions as needed. 
+    pub fn vfile_write_at(
+        &self, 
+        addr: usize, 
+        offset: usize,
+        len: usize
+    ) -> Result<usize, FsError> {
         let ret; 
+        self.fs().check_writable()?;
         if !self.vfile_writeable() {
-            panic!("file can't be written")
+            return Err(FsError::BadFile)
         }
         
         match self.ftype {
@@ -126,31 +182,35 @@ impl VFile {
                 // and 2 blocks of slop for non-aligned writes. 
                 // this really belongs lower down, since inode write
                 // might be writing a device like console. 
-                let max = ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE;
+                // unless data is journaled, only the inode, bitmap and
+                // indirect blocks are logged, at most 7 for NINDIRECT blocks.
+                let max = if self.fs().journals_data() || self.ftype == FileType::Directory {
+                    ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE
+                } else {
+                    NINDIRECT * BSIZE
+                };
                 let mut count  = 0;
-                let mut offset=0;
+                let mut offset = u32::try_from(offset).map_err(|_| FsError::FileTooLarge)?;
                 while count < len {
                     let mut write_bytes = len - count;
                     if write_bytes > max { write_bytes = max; }
                     info!("[Xv6fs] vfile_write: write bytes is {}",write_bytes);
 
-                    // start log
-                    //LOG.begin_op();
                     let inode = self.inode.as_ref().unwrap();
-                    let mut inode_guard = inode.lock();
+                    let tx = inode.fs().begin_op();
+                    let mut inode_guard = inode.lock()?;
 
                     // return err when failt to write
-                    inode_guard.write(
+                    let res = inode_guard.write(
                         addr + count, 
                         offset, 
                         write_bytes as u32
-                    )?;
+                    );
 
                     // release sleeplock
                     drop(inode_guard);
-                    LOG_MANAGER.end_op();
-                    // end log
-                    //LOG.end_op();
+                    tx.end()?;
+                    res?;
 
                     // update loop data
                     // self.offset += write_bytes as u32;
@@ -163,49 +223,7 @@ impl VFile {
             },
 
             _ => {
-                panic!("Invalid File Type!")
-            }
-        }
-
-    }
-
-    pub fn vfile_append(
-        &self, 
-        addr: usize, 
-        len: usize
-    ) -> Result<usize, &'static str> {
-        let ret; 
-        if !self.vfile_writeable() {
-            panic!("file can't be written")
-        }
-        match self.ftype {
-            FileType::File|FileType::Directory => {
-                let inode = self.inode.as_ref().unwrap();
-                let mut inode_guard = inode.lock();
-                let max = ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE;
-                let mut count  = 0;
-                let mut offset=inode_guard.dinode.size;
-                drop(inode_guard);
-                while count < len {
-                    let mut write_bytes = len - count;
-                    if write_bytes > max { write_bytes = max; }
-                    info!("[Xv6fs] vfile_write: write bytes is {}",write_bytes);
-                    let mut inode_guard = inode.lock();
-                    inode_guard.write(
-                        addr + count, 
-                        offset, 
-                        write_bytes as u32
-                    )?;
-                    drop(inode_guard);
-                    LOG_MANAGER.end_op();
-                    offset+=write_bytes as u32;
-                    count += write_bytes;
-                }
-                ret = count;
-                Ok(ret)
-            },
-            _ => {
-                panic!("Invalid File Type!")
+                Err(FsError::InvalidArg)
             }
         }
 
@@ -221,7 +239,7 @@ impl VFile {
 
     /// Get metadata about file f. 
     /// addr is a user virtual address, pointing to a struct stat. 
-    pub fn vfile_stat(&self) -> Result<Stat, &'static str> {
+    pub fn vfile_stat(&self) -> Result<Stat, FsError> {
         let mut stat: Stat = Stat::new();
         match self.ftype {
             FileType::File|FileType::Directory => {
@@ -230,7 +248,7 @@ impl VFile {
                 #[cfg(feature = "debug")]
                 info!("[Kernel] stat: inode index: {}, dev: {}, inum: {}", inode.index, inode.dev, inode.inum);
 
-                let inode_guard = inode.lock();
+                let inode_guard = inode.lock()?;
                 inode_guard.stat(&mut stat);
                 drop(inode_guard);
                 
@@ -242,7 +260,7 @@ impl VFile {
             },  
 
             _ => {
-                Err("")
+                Err(FsError::InvalidArg)
             }
         }
     }
@@ -261,189 +279,215 @@ impl VFile {
         false
     }
 
-    pub fn vfile_create_file(path:&str,readable:bool,writeable:bool)->Option<Self>{
+    pub fn vfile_create_file(fs:&Xv6FS,path:&str,readable:bool,writeable:bool)->Result<Self,FsError>{
         info!("vfile create file: path is {}",path);
-        let inode=ICACHE.create(path.as_bytes(),crate::disk_inode::InodeType::File, 2, 1).unwrap();
-        LOG_MANAGER.end_op();
-        Some(Self { ftype: FileType::File, readable, writeable, inode:Some(inode), offset:0})
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let inode=fs.icache.create(path.as_bytes(),crate::disk_inode::InodeType::File, 2, 1);
+        tx.end()?;
+        let inode=inode?;
+        Ok(Self { ftype: FileType::File, readable, writeable, inode:Some(inode), offset:AtomicU32::new(0)})
     }
 
-    pub fn vfile_create_dir(path:&str,readable:bool,writeable:bool)->Option<Self>{
+    pub fn vfile_create_dir(fs:&Xv6FS,path:&str,readable:bool,writeable:bool)->Result<Self,FsError>{
         info!("vfile create dir: path is {}",path);
-        let inode=ICACHE.create(path.as_bytes(),crate::disk_inode::InodeType::Directory, 2, 1).unwrap();
-        LOG_MANAGER.end_op();
-        Some(Self { ftype: FileType::Directory, readable, writeable, inode:Some(inode), offset:0})
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let inode=fs.icache.create(path.as_bytes(),crate::disk_inode::InodeType::Directory, 2, 1);
+        tx.end()?;
+        let inode=inode?;
+        Ok(Self { ftype: FileType::Directory, readable, writeable, inode:Some(inode), offset:AtomicU32::new(0)})
     }
 
-    pub fn vfile_lookup(path:&str)->Option<Self>{
+    pub fn vfile_lookup(fs:&Xv6FS,path:&str)->Result<Self,FsError>{
         info!("vfile lookup: path is {}",path);
-        match ICACHE.look_up(path.as_bytes()){
-            Ok(node)=>{
-                let guard=node.lock();
-                let ty=match guard.dinode.itype{
-                    InodeType::Directory=>FileType::Directory,
-                    _=>FileType::File,
-                };
-                drop(guard);
-                Some(Self { ftype: ty, readable:true, writeable:true, inode:Some(node), offset:0})
-            },
-            Err(_)=>None,
-        }
+        let node=fs.icache.look_up(path.as_bytes())?;
+        Self::from_inode(node)
     }
 
-    pub fn vfile_readdir(&self)->Option<Vec<String>>{
+    pub fn vfile_readdir(&self)->Result<Vec<String>,FsError>{
         info!("vfile read dir");
         if self.ftype!=FileType::Directory{
-            panic!("this is not a directory!");
+            return Err(FsError::NotDir);
         }
-        let mut inode_data=self.inode.as_ref().unwrap().lock();
+        let mut inode_data=self.inode.as_ref().unwrap().lock()?;
         inode_data.ls()
     }
 
-    pub fn vfile_remove(&self,path:&str){
+    pub fn vfile_remove(&self,path:&str)->Result<(),FsError>{
         info!("vfile remove");
-        ICACHE.remove(path.as_bytes());
-        LOG_MANAGER.end_op();
+        let fs=self.fs();
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let res=fs.icache.remove(path.as_bytes());
+        tx.end()?;
+        res
     }
 
-    pub fn vfile_create_under_dir(&self,file_name:&str,itype:InodeType)->Self{
+    pub fn vfile_create_under_dir(&self,file_name:&str,itype:InodeType)->Result<Self,FsError>{
         info!("vfile create: path is {}",file_name);
         let self_inode=self.inode.as_ref().unwrap();
-        let mut self_idata=self_inode.lock();
+        let fs=self_inode.fs();
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let res=self.create_under_dir(file_name, itype);
+        tx.end()?;
+        res
+    }
+
+    fn create_under_dir(&self,file_name:&str,itype:InodeType)->Result<Self,FsError>{
+        let self_inode=self.inode.as_ref().unwrap();
+        let mut self_idata=self_inode.lock()?;
+        let fs=self_inode.fs();
         let dev=self_inode.dev;
-        let inum=inode_alloc(dev,itype);
+        // check the name before allocating, so that a failure leaks no inode
+        if file_name.len() >= DIRSIZ {
+            return Err(FsError::NameTooLong);
+        }
+        match self_idata.dir_lookup(file_name.as_bytes()) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let inum=inode_alloc(fs,dev,itype)?;
         info!("vfile create: inum is {}",inum);
-        let inode=ICACHE.get(dev, inum);
-        let mut idata=inode.lock();
+        let inode=fs.icache.get(dev, inum)?;
+        let mut idata=inode.lock()?;
         idata.dinode.major=2;
         idata.dinode.minor=1;
         idata.dinode.nlink=1;
-        idata.update();
+        idata.update()?;
         let mut ftype=FileType::File;
         if itype==InodeType::Directory{
             ftype=FileType::Directory;
             idata.dinode.nlink+=1;
-            idata.update();
-            idata.dir_link(".".as_bytes(), inum);
-            idata.dir_link("..".as_bytes(), self_inode.inum);
+            idata.update()?;
+            idata.dir_link(".".as_bytes(), inum)?;
+            idata.dir_link("..".as_bytes(), self_inode.inum)?;
         }
-        self_idata.dir_link(file_name.as_bytes(), inode.inum).expect("parent inode fail to link");
         drop(idata);
+        self_idata.dir_link(file_name.as_bytes(), inode.inum)?;
         drop(self_idata);
-        LOG_MANAGER.end_op();
-        VFile { ftype, readable:true, writeable:true, inode:Some(inode), offset:0}
-        
+        Ok(VFile { ftype, readable:true, writeable:true, inode:Some(inode), offset:AtomicU32::new(0)})
+    }
+
+    /// The bytes read through this file so far, where a sequential read goes on.
+    pub fn vfile_offset(&self)->usize{
+        self.offset.load(Ordering::Relaxed) as usize
     }
 
-    pub fn vfile_size(&self)->usize{
+    pub fn vfile_size(&self)->Result<usize,FsError>{
         let inode=self.inode.as_ref().unwrap();
-        let idata=inode.lock();
-        idata.dinode.size as usize
+        let idata=inode.lock()?;
+        Ok(idata.dinode.size as usize)
     }
 
-    pub fn vfile_link(&self,src_path:&str,dir_path:&str){
-        let inode=match ICACHE.namei(src_path.as_bytes()) {
-            Some(cur)=>{
-                cur
-            },
-            None=>{
-                panic!("[Xv6fs] vfile_link: not find src path");
-            }
-        };
-        let mut inode_guard=inode.lock();
+    pub fn vfile_link(&self,src_path:&str,dir_path:&str)->Result<(),FsError>{
+        let fs=self.fs();
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let res=self.link(src_path, dir_path);
+        tx.end()?;
+        res
+    }
+
+    fn link(&self,src_path:&str,dir_path:&str)->Result<(),FsError>{
+        let fs=self.fs();
+        let inode=fs.icache.namei(src_path.as_bytes())?;
+        let mut inode_guard=inode.lock()?;
         if inode_guard.dinode.itype == InodeType::Directory {
-            panic!("[Xv6fs] vfile_link: cannot link directory");
+            return Err(FsError::PermissionDenied);
         }
-        inode_guard.dinode.nlink+=1;
         let mut name = [0u8; DIRSIZ];
-        let parent=match ICACHE.namei_parent(&dir_path.as_bytes(), &mut name) {
-            Some(cur)=>{
-                cur
-            },
-            None => {
-                panic!("[Xv6fs] vfile_link: not find dir path");
-            }
-        };
-        let mut parent_guard=parent.lock();
-        parent_guard.dir_link(&name, inode.inum);
-        inode_guard.update();
-        parent_guard.update();
+        let parent=fs.icache.namei_parent(&dir_path.as_bytes(), &mut name)?;
+        let mut parent_guard=parent.lock()?;
+        parent_guard.dir_link(&name, inode.inum)?;
+        inode_guard.dinode.nlink+=1;
+        inode_guard.update()?;
+        parent_guard.update()?;
         drop(parent_guard);
         drop(inode_guard);
-        LOG_MANAGER.end_op();
+        Ok(())
     }
 
-    pub fn vfile_unlink(&self,path:&str){//目录没有删掉dir entry
+    pub fn vfile_unlink(&self,path:&str)->Result<(),FsError>{//目录没有删掉dir entry
         info!("[Xv6fs] vfile unlink: unlink {}",path);
+        let fs=self.fs();
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let res=self.unlink(path);
+        tx.end()?;
+        res
+    }
+
+    fn unlink(&self,path:&str)->Result<(),FsError>{
+        let fs=self.fs();
         let mut name = [0u8; DIRSIZ];
-        let parent=match ICACHE.namei_parent(&path.as_bytes(), &mut name) {
-            Some(cur)=>cur,
-            None=>panic!("[Xv6fs] vfile_unlink: not find path")
-        };
-        let mut parent_guard=parent.lock();
-        let inode=match parent_guard.dir_lookup(&name) {
-            Some(cur) => {
-                cur
-            },
-            _ => {
-                panic!("[Xv6fs] vfile_unlink: not find name");
-            }
-        };
-        let mut inode_guard=inode.lock();
+        let parent=fs.icache.namei_parent(&path.as_bytes(), &mut name)?;
+        let mut parent_guard=parent.lock()?;
+        let inode=parent_guard.dir_lookup(&name)?;
+        let mut inode_guard=inode.lock()?;
         if inode_guard.dinode.itype==InodeType::Directory{
-            panic!("[Xv6fs] vfile_link: cannot unlink directory");
+            return Err(FsError::IsDir);
         }
-        inode_guard.dinode.nlink-=1;
+        inode_guard.dinode.nlink=inode_guard.dinode.nlink.saturating_sub(1);
         info!("now disk inode nlink is {}",inode_guard.dinode.nlink);
         let flag=match inode_guard.dinode.nlink {
             0=>true,
             _=>false
         };
-        inode_guard.update();
+        inode_guard.update()?;
         drop(inode_guard);
         if flag{
             drop(parent_guard);
-            self.vfile_remove(path);
-        }else{
-            parent_guard.dir_unlink(&name);
-            drop(parent_guard);
+            return fs.icache.remove(path.as_bytes());
         }
-        LOG_MANAGER.end_op();
+        parent_guard.dir_unlink(&name)
     }
 
-    pub fn vfile_rename(&self,path:&str,new_name:&str){
-        InodeData::rename(path, new_name);
+    pub fn vfile_rename(&self,path:&str,new_name:&str)->Result<(),FsError>{
+        let fs=self.fs();
+        fs.check_writable()?;
+        let tx=fs.begin_op();
+        let res=InodeData::rename(fs, path, new_name);
+        tx.end()?;
+        res
     }
 
-    pub fn vfile_pass_dir(&self)->Option<Vec<(String,InodeType)>>{
-        let mut inode_guard=self.inode.as_ref().unwrap().lock();
-        let mut v=Vec::new();
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..inode_guard.dinode.size).step_by(de_size) {
-            inode_guard.read(
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
+    pub fn vfile_pass_dir(&self)->Result<Vec<(String,InodeType)>,FsError>{
+        let mut inode_guard=self.inode.as_ref().unwrap().lock()?;
+        let mut entries=Vec::new();
+        for offset in (0..inode_guard.dinode.size).step_by(DirEntry::SIZE) {
+            let dir_entry = inode_guard.read_dirent(offset)?;
             if dir_entry.inum == 0 {
                 continue;
             }
             // info!("dir_entry_name: {}, name: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap());
-            let name=String::from_utf8(dir_entry.name.to_vec()).unwrap();
-            let itype=ICACHE.get_inum_type(inode_guard.dev,dir_entry.inum as u32);
+            let name=String::from_utf8_lossy(&dir_entry.name).into_owned();
+            entries.push((name,dir_entry.inum as u32));
+        }
+        let dev=inode_guard.dev;
+        // "." is this directory, whose lock must be released first
+        drop(inode_guard);
+        let mut v=Vec::new();
+        for (name,inum) in entries{
+            let itype=self.fs().icache.get_inum_type(dev,inum)?;
             v.push((name,itype));
-
         }
         info!("xv6fs: vfile pass dir is {:?}",v);
-        Some(v)
+        Ok(v)
     }
 
-    pub fn vfile_truncate(&self,size:u64)->usize{
-        let mut inode_guard=self.inode.as_ref().unwrap().lock();
+    pub fn vfile_truncate(&self,size:u64)->Result<usize,FsError>{
+        self.fs().check_writable()?;
+        if !self.vfile_writeable() {
+            return Err(FsError::BadFile)
+        }
+        let tx=self.fs().begin_op();
+        let mut inode_guard=self.inode.as_ref().unwrap().lock()?;
         let res=inode_guard.resize(self.inode.as_ref().unwrap(), size);
-        LOG_MANAGER.end_op();
+        drop(inode_guard);
+        tx.end()?;
         res
 
     }
@@ -481,9 +525,9 @@ impl VFile {
     // }
 }
 
-pub fn test_link_unlink(){
-    let inode=ICACHE.get_root_dir();
-    let idata=inode.lock();
+pub fn test_link_unlink(fs:&Xv6FS){
+    let inode=fs.icache.get_root_dir().unwrap();
+    let idata=inode.lock().unwrap();
     let mut ftype=FileType::Directory;
     drop(idata);
     let root=VFile { 
@@ -491,7 +535,7 @@ pub fn test_link_unlink(){
         readable:true, 
         writeable:true, 
         inode:Some(inode), 
-        offset:0,
+        offset:AtomicU32::new(0),
     };
     root.vfile_readdir().map(|x| {
         for file_name in x {
@@ -499,7 +543,7 @@ pub fn test_link_unlink(){
         }
     })
     .expect("can't read root directory");
-    root.vfile_create_under_dir("test\0", InodeType::File);
+    root.vfile_create_under_dir("test\0", InodeType::File).unwrap();
     root.vfile_readdir().map(|x| {
         for file_name in x {
             info!("{}", file_name);
@@ -507,12 +551,12 @@ pub fn test_link_unlink(){
     })
     .expect("can't read root directory");
     //root.vfile_remove("/test\0");
-    root.vfile_link("/test\0", "/test1\0");
+    root.vfile_link("/test\0", "/test1\0").unwrap();
     let data="hello".as_bytes();
-    let test1=VFile::vfile_create_file("/test1\0", true, true).unwrap();
-    test1.vfile_write(data.as_ptr() as usize, data.len());
-    root.vfile_unlink("/test1\0");
-    root.vfile_unlink("/test\0");
+    let test1=VFile::vfile_create_file(fs, "/test1\0", true, true).unwrap();
+    test1.vfile_write(data.as_ptr() as usize, data.len()).unwrap();
+    root.vfile_unlink("/test1\0").unwrap();
+    root.vfile_unlink("/test\0").unwrap();
     root.vfile_readdir().map(|x| {
         for file_name in x {
             info!("{}", file_name);
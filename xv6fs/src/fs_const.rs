@@ -1,4 +1,3 @@
-use core::mem::size_of;
 use super::DiskInode;
 /// magic number indentifying this specific file system
 pub const FSMAGIC: u32 = 0x10203040;
@@ -6,9 +5,9 @@ pub const FSMAGIC: u32 = 0x10203040;
 pub const BSIZE: usize = 512;
 /// Maxinum of blocks an FS op can write
 pub const MAXOPBLOCKS: usize = 10;
-/// size of buffer cache for block
+/// default size of the buffer cache, the mount options set the real one
 pub const NBUF: usize = MAXOPBLOCKS * 3;
-/// size of log space in disk
+/// default size of log space in disk, the super block records the real one
 pub const LOGSIZE: usize = MAXOPBLOCKS * 3;
 
 /// open files per process
@@ -37,7 +36,7 @@ pub const MAXFILE: usize = NDIRECT + NINDIRECT + NININDIRECT;
 pub const DIRSIZ: usize = 14;
 
 /// Inodes per block. 
-pub const IPB: usize = BSIZE / size_of::<DiskInode>();
+pub const IPB: usize = BSIZE / DiskInode::SIZE;
 
 /// Bitmap bits per block
 pub const BPB: u32 = (BSIZE*8) as u32;
@@ -0,0 +1,564 @@
+//! Offline consistency check and repair.
+//!
+//! [`check`] walks the inode table and the directory tree from the root
+//! and reports every [`Problem`] it finds. [`repair`] fixes them:
+//! bad inodes and block pointers are cleared, broken directory entries
+//! are dropped or pointed back, orphans are reattached under `/lost+found`,
+//! link counts are recomputed and the bitmap is rebuilt.
+//! Both expect the file system to be idle, nothing else may hold inodes.
+
+#[cfg(not(test))]
+use axlog::info; // Use log crate when building application
+
+#[cfg(test)]
+use std::println as info; // Workaround to use prinltn! for logs.
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+
+use crate::disk_inode::{DirEntry, DiskInode, InodeType};
+use crate::error::FsError;
+use crate::fs_const::{BPB, BSIZE, DIRSIZ, IPB, MAXFILE, NDIRECT, NINDIRECT, ROOTINUM};
+use crate::misc::{get_u16, get_u32, put_u16};
+use crate::xv6fs::Xv6FS;
+
+/// Repairs that keep uncovering problems give up after this many passes
+const MAX_PASSES: usize = 32;
+
+/// An inconsistency found by [`check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The inode has a type no file can have
+    BadType { inum: u32, itype: u16 },
+    /// The size is beyond the largest possible file
+    BadSize { inum: u32, size: u32 },
+    /// A block pointer outside the data area
+    BadBlock { inum: u32, bno: u32 },
+    /// A block already claimed by another inode
+    DupBlock { inum: u32, bno: u32, owner: u32 },
+    /// A directory entry naming a free or out of range inode
+    Dangling { dir: u32, name: [u8; DIRSIZ], inum: u32 },
+    /// A second entry for a directory, directories have a single parent
+    DirLinked { dir: u32, name: [u8; DIRSIZ], inum: u32 },
+    /// `.` or `..` not pointing to the directory or its parent
+    BadDot { dir: u32, name: [u8; DIRSIZ], inum: u32, expected: u32 },
+    /// An allocated inode no directory leads to
+    Unreachable { inum: u32, nlink: i16 },
+    /// The link count disagrees with the directory entries
+    LinkCount { inum: u32, nlink: i16, refs: u32 },
+    /// Blocks in use but free in the bitmap
+    MarkedFree { start: u32, len: u32 },
+    /// Blocks in use in the bitmap but claimed by no inode
+    MarkedUsed { start: u32, len: u32 },
+}
+
+fn name_str(name: &[u8; DIRSIZ]) -> &str {
+    let len = name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+    str::from_utf8(&name[..len]).unwrap_or("?")
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadType { inum, itype } =>
+                write!(f, "inode {}: bad type {}", inum, itype),
+            Problem::BadSize { inum, size } =>
+                write!(f, "inode {}: size {} too large", inum, size),
+            Problem::BadBlock { inum, bno } =>
+                write!(f, "inode {}: bad block {}", inum, bno),
+            Problem::DupBlock { inum, bno, owner } =>
+                write!(f, "inode {}: block {} already used by inode {}", inum, bno, owner),
+            Problem::Dangling { dir, name, inum } =>
+                write!(f, "directory {}: entry {} points to free inode {}", dir, name_str(name), inum),
+            Problem::DirLinked { dir, name, inum } =>
+                write!(f, "directory {}: entry {} links directory {} a second time", dir, name_str(name), inum),
+            Problem::BadDot { dir, name, inum, expected } =>
+                write!(f, "directory {}: {} is {} instead of {}", dir, name_str(name), inum, expected),
+            Problem::Unreachable { inum, nlink } =>
+                write!(f, "inode {}: unreachable with {} links", inum, nlink),
+            Problem::LinkCount { inum, nlink, refs } =>
+                write!(f, "inode {}: {} links but {} references", inum, nlink, refs),
+            Problem::MarkedFree { start, len } =>
+                write!(f, "blocks {}..{}: in use but marked free", start, start + len),
+            Problem::MarkedUsed { start, len } =>
+                write!(f, "blocks {}..{}: unused but marked in use", start, start + len),
+        }
+    }
+}
+
+/// Where a block pointer lives: an inode's addrs or an indirect block.
+#[derive(Clone, Copy)]
+enum Slot {
+    Inode(u32, usize),
+    Block(u32, usize),
+}
+
+/// A directory entry by block number and byte offset in the block
+type EntryLoc = (u32, usize);
+
+enum Fix {
+    ZeroInode(u32),
+    ClampSize(u32),
+    ClearSlot(Slot),
+    SetEntry(EntryLoc, u16),
+}
+
+/// What one pass over the file system found.
+struct Scan {
+    problems: Vec<Problem>,
+    /// structural repairs, applied before anything else
+    fixes: Vec<Fix>,
+    inodes: Vec<Option<DiskInode>>,
+    /// logical to physical blocks of every directory
+    dir_blocks: Vec<Vec<u32>>,
+    /// the inode claiming each block, 0 if none
+    owner: Vec<u32>,
+    /// directory entries pointing to each inode, `..` excluded
+    refs: Vec<u32>,
+    reached: Vec<bool>,
+    /// unreachable inodes listed by an unreachable directory
+    listed: Vec<bool>,
+    nmeta: u32,
+    bitmap_ok: bool,
+}
+
+fn read_block(fs: &Xv6FS, bno: u32) -> Result<[u8; BSIZE], FsError> {
+    let buf = fs.bcache.bread(fs.dev, bno)?;
+    Ok(*buf.data())
+}
+
+/// Change a block in a transaction of its own.
+fn edit_block(fs: &Xv6FS, bno: u32, f: impl FnOnce(&mut [u8])) -> Result<(), FsError> {
+    let tx = fs.begin_op();
+    let mut buf = fs.bcache.bread(fs.dev, bno)?;
+    fs.log.reserve(&buf)?;
+    f(buf.data_mut());
+    fs.log.write(buf)?;
+    tx.end()
+}
+
+/// Change an inode of a valid type on disk.
+fn edit_dinode(fs: &Xv6FS, inum: u32, f: impl FnOnce(&mut DiskInode)) -> Result<(), FsError> {
+    let offset = (inum as usize % IPB) * DiskInode::SIZE;
+    let mut res = Ok(());
+    edit_block(fs, fs.sb.locate_inode(inum)?, |data| {
+        match DiskInode::decode(&data[offset..]) {
+            Ok(mut dinode) => {
+                f(&mut dinode);
+                dinode.encode(&mut data[offset..]);
+            }
+            Err(err) => res = Err(err),
+        }
+    })?;
+    res
+}
+
+fn slot_entry(data: &[u8; BSIZE], index: usize) -> u32 {
+    get_u32(data, index * 4)
+}
+
+fn is_name(name: &[u8; DIRSIZ], s: &[u8]) -> bool {
+    name[..s.len()] == *s && name[s.len()..].iter().all(|&c| c == 0)
+}
+
+fn set_block(map: &mut Vec<u32>, lbn: usize, bno: u32) {
+    if map.len() <= lbn {
+        map.resize(lbn + 1, 0);
+    }
+    map[lbn] = bno;
+}
+
+impl Scan {
+    fn new(fs: &Xv6FS) -> Self {
+        let ninodes = fs.sb.ninodes() as usize;
+        let size = fs.sb.size() as usize;
+        Self {
+            problems: Vec::new(),
+            fixes: Vec::new(),
+            inodes: vec![None; ninodes],
+            dir_blocks: vec![Vec::new(); ninodes],
+            owner: vec![0; size],
+            refs: vec![0; ninodes],
+            reached: vec![false; ninodes],
+            listed: vec![false; ninodes],
+            nmeta: fs.sb.size() - fs.sb.nblocks(),
+            bitmap_ok: true,
+        }
+    }
+
+    /// Load the inode table, checking types and sizes.
+    fn load_inodes(&mut self, fs: &Xv6FS) -> Result<(), FsError> {
+        for inum in 1..fs.sb.ninodes() {
+            let data = read_block(fs, fs.sb.locate_inode(inum)?)?;
+            let offset = (inum as usize % IPB) * DiskInode::SIZE;
+            // check the type before decoding the inode
+            let itype = get_u16(&data, offset);
+            if itype == InodeType::Empty as u16 {
+                continue;
+            }
+            if itype > InodeType::Device as u16 {
+                self.problems.push(Problem::BadType { inum, itype });
+                self.fixes.push(Fix::ZeroInode(inum));
+                continue;
+            }
+            let dinode = DiskInode::decode(&data[offset..])?;
+            if dinode.size as usize > MAXFILE * BSIZE {
+                self.problems.push(Problem::BadSize { inum, size: dinode.size });
+                self.fixes.push(Fix::ClampSize(inum));
+            }
+            self.inodes[inum as usize] = Some(dinode);
+        }
+        Ok(())
+    }
+
+    /// Claim block `bno` for `inum`.
+    /// Returns false if the pointer is empty or had to be dropped.
+    fn claim(&mut self, inum: u32, bno: u32, slot: Slot) -> bool {
+        if bno == 0 {
+            return false;
+        }
+        if bno < self.nmeta || bno as usize >= self.owner.len() {
+            self.problems.push(Problem::BadBlock { inum, bno });
+            self.fixes.push(Fix::ClearSlot(slot));
+            return false;
+        }
+        let owner = self.owner[bno as usize];
+        if owner != 0 {
+            self.problems.push(Problem::DupBlock { inum, bno, owner });
+            self.fixes.push(Fix::ClearSlot(slot));
+            return false;
+        }
+        self.owner[bno as usize] = inum;
+        true
+    }
+
+    /// Claim the direct, indirect and double-indirect blocks of `inum`.
+    fn claim_blocks(&mut self, fs: &Xv6FS, inum: u32, dinode: &DiskInode) -> Result<(), FsError> {
+        let is_dir = dinode.itype == InodeType::Directory;
+        let mut map = Vec::new();
+        for i in 0..NDIRECT {
+            let bno = dinode.addrs[i];
+            if self.claim(inum, bno, Slot::Inode(inum, i)) && is_dir {
+                set_block(&mut map, i, bno);
+            }
+        }
+        let ind = dinode.addrs[NDIRECT];
+        if self.claim(inum, ind, Slot::Inode(inum, NDIRECT)) {
+            let data = read_block(fs, ind)?;
+            for j in 0..NINDIRECT {
+                let bno = slot_entry(&data, j);
+                if self.claim(inum, bno, Slot::Block(ind, j)) && is_dir {
+                    set_block(&mut map, NDIRECT + j, bno);
+                }
+            }
+        }
+        let dind = dinode.addrs[NDIRECT + 1];
+        if self.claim(inum, dind, Slot::Inode(inum, NDIRECT + 1)) {
+            let data = read_block(fs, dind)?;
+            for i in 0..NINDIRECT {
+                let ind = slot_entry(&data, i);
+                if !self.claim(inum, ind, Slot::Block(dind, i)) {
+                    continue;
+                }
+                let idata = read_block(fs, ind)?;
+                for j in 0..NINDIRECT {
+                    let bno = slot_entry(&idata, j);
+                    if self.claim(inum, bno, Slot::Block(ind, j)) && is_dir {
+                        set_block(&mut map, NDIRECT + NINDIRECT + i * NINDIRECT + j, bno);
+                    }
+                }
+            }
+        }
+        self.dir_blocks[inum as usize] = map;
+        Ok(())
+    }
+
+    /// The used entries of directory `dir`.
+    fn entries(&self, fs: &Xv6FS, dir: u32) -> Result<Vec<(EntryLoc, u32, [u8; DIRSIZ])>, FsError> {
+        let size = self.inodes[dir as usize].as_ref().map_or(0, |d| d.size as usize);
+        let map = &self.dir_blocks[dir as usize];
+        let de_size = DirEntry::SIZE;
+        let mut v = Vec::new();
+        let mut off = 0;
+        while off + de_size <= size {
+            let lbn = off / BSIZE;
+            let bno = map.get(lbn).copied().unwrap_or(0);
+            if bno == 0 {
+                // a hole holds no entries
+                off = (lbn + 1) * BSIZE;
+                continue;
+            }
+            let data = read_block(fs, bno)?;
+            let mut o = off % BSIZE;
+            while o + de_size <= BSIZE && off + de_size <= size {
+                let de = DirEntry::decode(&data[o..]);
+                if de.inum != 0 {
+                    v.push(((bno, o), de.inum as u32, de.name));
+                }
+                o += de_size;
+                off += de_size;
+            }
+        }
+        Ok(v)
+    }
+
+    fn is_dir(&self, inum: u32) -> bool {
+        matches!(&self.inodes[inum as usize], Some(d) if d.itype == InodeType::Directory)
+    }
+
+    /// Walk the tree from the root, counting references.
+    fn walk(&mut self, fs: &Xv6FS) -> Result<(), FsError> {
+        if !self.is_dir(ROOTINUM) {
+            // nothing to hang the tree on
+            return Err(FsError::Corrupted);
+        }
+        let ninodes = self.inodes.len() as u32;
+        self.reached[ROOTINUM as usize] = true;
+        let mut stack = vec![(ROOTINUM, ROOTINUM)];
+        while let Some((dir, parent)) = stack.pop() {
+            for (loc, inum, name) in self.entries(fs, dir)? {
+                let dot = is_name(&name, b".");
+                if dot || is_name(&name, b"..") {
+                    let expected = if dot { dir } else { parent };
+                    if inum != expected {
+                        self.problems.push(Problem::BadDot { dir, name, inum, expected });
+                        self.fixes.push(Fix::SetEntry(loc, expected as u16));
+                    }
+                    if dot {
+                        self.refs[dir as usize] += 1;
+                    }
+                    continue;
+                }
+                if inum >= ninodes || self.inodes[inum as usize].is_none() {
+                    self.problems.push(Problem::Dangling { dir, name, inum });
+                    self.fixes.push(Fix::SetEntry(loc, 0));
+                    continue;
+                }
+                let is_dir = self.is_dir(inum);
+                if is_dir && self.reached[inum as usize] {
+                    self.problems.push(Problem::DirLinked { dir, name, inum });
+                    self.fixes.push(Fix::SetEntry(loc, 0));
+                    continue;
+                }
+                self.refs[inum as usize] += 1;
+                if !self.reached[inum as usize] {
+                    self.reached[inum as usize] = true;
+                    if is_dir {
+                        stack.push((inum, dir));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Report unreachable inodes, and what unreachable directories hold.
+    fn find_orphans(&mut self, fs: &Xv6FS) -> Result<(), FsError> {
+        let ninodes = self.inodes.len() as u32;
+        for inum in 1..ninodes {
+            let nlink = match &self.inodes[inum as usize] {
+                Some(d) if !self.reached[inum as usize] => d.nlink,
+                _ => continue,
+            };
+            self.problems.push(Problem::Unreachable { inum, nlink });
+            if nlink <= 0 {
+                // unlinked but never freed, e.g. by a crash
+                self.fixes.push(Fix::ZeroInode(inum));
+                continue;
+            }
+            if self.is_dir(inum) {
+                for (_, child, name) in self.entries(fs, inum)? {
+                    if child < ninodes && !is_name(&name, b".") && !is_name(&name, b"..") {
+                        self.listed[child as usize] = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_links(&mut self) {
+        for inum in 1..self.inodes.len() {
+            if let Some(d) = &self.inodes[inum] {
+                if self.reached[inum] && d.nlink as i64 != self.refs[inum] as i64 {
+                    self.problems.push(Problem::LinkCount {
+                        inum: inum as u32,
+                        nlink: d.nlink,
+                        refs: self.refs[inum],
+                    });
+                }
+            }
+        }
+    }
+
+    fn in_use(&self, bno: u32) -> bool {
+        bno < self.nmeta || self.owner[bno as usize] != 0
+    }
+
+    /// Compare the bitmap with the claimed blocks, reporting runs of mismatches.
+    fn check_bitmap(&mut self, fs: &Xv6FS) -> Result<(), FsError> {
+        let size = fs.sb.size();
+        // (marked, start) of the current run of mismatching blocks
+        let mut run: Option<(bool, u32)> = None;
+        let mut data = [0u8; BSIZE];
+        for b in 0..=size {
+            let mismatch = if b < size {
+                if b % BPB == 0 {
+                    data = read_block(fs, fs.sb.bitmap_blockno(b))?;
+                }
+                let bi = (b % BPB) as usize;
+                let marked = data[bi / 8] & (1 << (bi % 8)) != 0;
+                (marked != self.in_use(b)).then(|| marked)
+            } else {
+                None
+            };
+            if let Some((marked, start)) = run {
+                if mismatch != Some(marked) {
+                    let len = b - start;
+                    self.problems.push(if marked {
+                        Problem::MarkedUsed { start, len }
+                    } else {
+                        Problem::MarkedFree { start, len }
+                    });
+                    self.bitmap_ok = false;
+                    run = None;
+                }
+            }
+            if run.is_none() {
+                run = mismatch.map(|marked| (marked, b));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn scan(fs: &Xv6FS) -> Result<Scan, FsError> {
+    let mut s = Scan::new(fs);
+    s.load_inodes(fs)?;
+    for inum in 1..fs.sb.ninodes() {
+        if let Some(dinode) = s.inodes[inum as usize] {
+            s.claim_blocks(fs, inum, &dinode)?;
+        }
+    }
+    s.walk(fs)?;
+    s.find_orphans(fs)?;
+    s.check_links();
+    s.check_bitmap(fs)?;
+    Ok(s)
+}
+
+fn apply(fs: &Xv6FS, fix: &Fix) -> Result<(), FsError> {
+    match *fix {
+        Fix::ZeroInode(inum) => {
+            let offset = (inum as usize % IPB) * DiskInode::SIZE;
+            edit_block(fs, fs.sb.locate_inode(inum)?, |data| {
+                data[offset..offset + DiskInode::SIZE].fill(0)
+            })
+        }
+        Fix::ClampSize(inum) => edit_dinode(fs, inum, |d| d.size = (MAXFILE * BSIZE) as u32),
+        Fix::ClearSlot(Slot::Inode(inum, i)) => edit_dinode(fs, inum, |d| d.addrs[i] = 0),
+        Fix::ClearSlot(Slot::Block(bno, i)) => edit_block(fs, bno, |data| data[i * 4..i * 4 + 4].fill(0)),
+        Fix::SetEntry((bno, offset), inum) => {
+            edit_block(fs, bno, |data| put_u16(data, offset, inum))
+        }
+    }
+}
+
+/// Write the bitmap back from the blocks the scan found in use.
+fn rebuild_bitmap(fs: &Xv6FS, s: &Scan) -> Result<(), FsError> {
+    let size = fs.sb.size();
+    let mut b = 0;
+    while b < size {
+        let mut want = [0u8; BSIZE];
+        let mut bi = 0;
+        while bi < BPB && b + bi < size {
+            if s.in_use(b + bi) {
+                want[(bi / 8) as usize] |= 1 << (bi % 8);
+            }
+            bi += 1;
+        }
+        let bno = fs.sb.bitmap_blockno(b);
+        if read_block(fs, bno)? != want {
+            edit_block(fs, bno, |data| data.copy_from_slice(&want))?;
+        }
+        b += BPB;
+    }
+    Ok(())
+}
+
+/// Link orphans into `/lost+found` as `#<inum>`.
+/// Only orphans no other orphan lists are linked, the rest come along.
+fn reattach(fs: &Xv6FS, s: &Scan) -> Result<(), FsError> {
+    let orphans: Vec<u32> = (1..s.inodes.len() as u32)
+        .filter(|&i| s.inodes[i as usize].is_some() && !s.reached[i as usize])
+        .collect();
+    let mut roots: Vec<u32> = orphans.iter().copied().filter(|&i| !s.listed[i as usize]).collect();
+    if roots.is_empty() {
+        // orphan directories listing each other, break the cycle anywhere
+        roots.extend(orphans.first());
+    }
+
+    let root = fs.icache.get_root_dir()?;
+    let found = root.lock()?.dir_lookup(b"lost+found");
+    let lost = match found {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) => {
+            let tx = fs.begin_op();
+            let res = fs.icache.create_at(&root, b"lost+found", InodeType::Directory, 0, 0);
+            tx.end()?;
+            res?
+        }
+        Err(err) => return Err(err),
+    };
+    if lost.lock()?.dinode.itype != InodeType::Directory {
+        return Err(FsError::NotDir);
+    }
+    for inum in roots {
+        info!("[Xv6fs] fsck: reattach inode {}", inum);
+        let name = format!("#{}", inum);
+        let tx = fs.begin_op();
+        let res = lost.lock()?.dir_link(name.as_bytes(), inum);
+        tx.end()?;
+        res?;
+    }
+    Ok(())
+}
+
+/// Check the file system without changing it.
+pub fn check(fs: &Xv6FS) -> Result<Vec<Problem>, FsError> {
+    Ok(scan(fs)?.problems)
+}
+
+/// Check the file system and repair it.
+/// Returns what the first check found, [`check`] again to see what is left.
+pub fn repair(fs: &Xv6FS) -> Result<Vec<Problem>, FsError> {
+    let mut found = None;
+    for _ in 0..MAX_PASSES {
+        let s = scan(fs)?;
+        if found.is_none() {
+            found = Some(s.problems.clone());
+        }
+        if !s.fixes.is_empty() {
+            info!("[Xv6fs] fsck: {} fixes", s.fixes.len());
+            for fix in &s.fixes {
+                apply(fs, fix)?;
+            }
+        } else if !s.bitmap_ok {
+            info!("[Xv6fs] fsck: rebuild bitmap");
+            rebuild_bitmap(fs, &s)?;
+        } else if s.reached.iter().zip(&s.inodes).any(|(&r, d)| !r && d.is_some()) {
+            reattach(fs, &s)?;
+        } else {
+            for p in &s.problems {
+                if let Problem::LinkCount { inum, refs, .. } = *p {
+                    edit_dinode(fs, inum, |d| d.nlink = refs.min(i16::MAX as u32) as i16)?;
+                }
+            }
+            break;
+        }
+    }
+    Ok(found.unwrap_or_default())
+}
@@ -0,0 +1,206 @@
+//! Support for running the file system in a hosted program,
+//! built with the `std` feature.
+//!
+//! ```ignore
+//! StdInterface::install();
+//! let dev = Arc::new(FileDevice::open("fs.img")?);
+//! let fs = Xv6FS::open(dev, 1)?;
+//! ```
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex, Once, RwLock};
+use std::thread_local;
+use std::time::Duration;
+
+use crate::block_dev::{BlockDevice, DevError, DevResult};
+use crate::fs_const::BSIZE;
+use crate::inode::Inode;
+use crate::interface::{FsInterface, InterfaceManager, INTERFACE_MANAGER};
+
+/// One sleep lock: threads wait on the condvar while it is held.
+struct HostLock {
+    held: Mutex<bool>,
+    cond: Condvar,
+}
+
+thread_local! {
+    static CUR_DIR: RefCell<Option<Inode>> = RefCell::new(None);
+}
+
+/// Sleep locks on OS threads, and a current directory per thread.
+pub struct StdInterface {
+    locks: RwLock<Vec<Arc<HostLock>>>,
+}
+
+impl StdInterface {
+    pub fn new() -> Self {
+        Self { locks: RwLock::new(Vec::new()) }
+    }
+
+    /// Make a `StdInterface` the interface of the library.
+    /// Must be called before opening a file system, later calls do nothing.
+    pub fn install() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            INTERFACE_MANAGER.init_by(InterfaceManager { interface: Arc::new(StdInterface::new()) });
+        });
+    }
+
+    /// Set the directory relative paths of the calling thread start from.
+    /// `None` goes back to the root.
+    pub fn set_cur_dir(inode: Option<Inode>) {
+        CUR_DIR.with(|cur| *cur.borrow_mut() = inode);
+    }
+
+    fn lock_at(&self, index: usize) -> Arc<HostLock> {
+        self.locks.read().unwrap()[index].clone()
+    }
+}
+
+impl Default for StdInterface {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FsInterface for StdInterface {
+    fn get_cur_dir_inode(&self) -> Option<Inode> {
+        CUR_DIR.with(|cur| cur.borrow().clone())
+    }
+
+    /// Block until the sleep lock is free, then take it.
+    fn sleep_cur_proc(&self, index: usize) {
+        let lock = self.lock_at(index);
+        let mut held = lock.held.lock().unwrap();
+        while *held {
+            held = lock.cond.wait(held).unwrap();
+        }
+        *held = true;
+    }
+
+    /// Release the sleep lock and wake one waiter.
+    fn wake_up_next_proc(&self, index: usize) {
+        let lock = self.lock_at(index);
+        *lock.held.lock().unwrap() = false;
+        lock.cond.notify_one();
+    }
+
+    fn new_sleep_lock(&self) -> usize {
+        let mut locks = self.locks.write().unwrap();
+        locks.push(Arc::new(HostLock { held: Mutex::new(false), cond: Condvar::new() }));
+        locks.len() - 1
+    }
+
+    /// Whether the sleep lock is held.
+    fn get_flag(&self, index: usize) -> bool {
+        *self.lock_at(index).held.lock().unwrap()
+    }
+
+    /// Run the task on its own thread, sleeping between calls.
+    fn run_periodic(&self, period_ms: u64, task: Box<dyn Fn() -> bool + Send + Sync>) -> bool {
+        std::thread::Builder::new()
+            .name("xv6fs-flush".into())
+            .spawn(move || loop {
+                std::thread::sleep(Duration::from_millis(period_ms));
+                if !task() {
+                    break;
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// A block device backed by a host file.
+pub struct FileDevice {
+    file: Mutex<File>,
+}
+
+impl FileDevice {
+    pub fn new(file: File) -> Self {
+        Self { file: Mutex::new(file) }
+    }
+
+    /// Open an existing image for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Open an existing image for reading only.
+    /// Writing to the device then fails with `Io`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Create an image of `blocks` zeroed blocks, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P, blocks: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(blocks as u64 * BSIZE as u64)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BSIZE) as u64))
+            .map_err(|_| DevError::Io)?;
+        file.read_exact(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => DevError::OutOfRange,
+            _ => DevError::Io,
+        })
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BSIZE) as u64))
+            .map_err(|_| DevError::Io)?;
+        file.write_all(buf).map_err(|_| DevError::Io)
+    }
+
+    /// One seek and one read for the whole range.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> DevResult {
+        self.read_block(block_id, buf)
+    }
+
+    /// One seek and one write for the whole range.
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> DevResult {
+        self.write_block(block_id, buf)
+    }
+
+    /// Sync the file data to the host disk.
+    fn flush(&self) -> DevResult {
+        self.file.lock().unwrap().sync_data().map_err(|_| DevError::Io)
+    }
+
+    /// Punch a hole over the blocks, keeping the file size.
+    /// A host file system without holes keeps them as they are.
+    #[cfg(target_os = "linux")]
+    fn discard(&self, blocks: core::ops::Range<usize>) -> DevResult {
+        use std::os::unix::io::AsRawFd;
+        let file = self.file.lock().unwrap();
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let offset = (blocks.start * BSIZE) as libc::off_t;
+        let len = (blocks.len() * BSIZE) as libc::off_t;
+        if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } == 0 {
+            return Ok(());
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(()),
+            _ => Err(DevError::Io),
+        }
+    }
+}
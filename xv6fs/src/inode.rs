@@ -7,52 +7,59 @@ use axlog::{info, warn}; use core::fmt::DebugList;
 use std::{println as info, println as warn}; // Workaround to use prinltn! for logs.
 
 use crate::{SleepLock, init_lock, SleepLockGuard, disk_inode};
-use crate::fs_const::{BSIZE, DIRSIZ, IPB, NDIRECT, NINDIRECT, NINODE, ROOTDEV, ROOTINUM, NININDIRECT};
-use crate::log::LOG_MANAGER;
-use crate::bitmap::{inode_alloc, bisalloc};
-use crate::misc::{min, mem_set};
+use crate::fs_const::{BSIZE, DIRSIZ, IPB, MAXFILE, NDIRECT, NINDIRECT, NINODE, ROOTINUM, NININDIRECT};
+use crate::bitmap::inode_alloc;
+use crate::misc::{min, mem_set, get_u32, put_u32};
 use crate::interface::INTERFACE_MANAGER;
 
 use spin::{Mutex,MutexGuard};
 
-use core::mem::size_of;
-use core::ptr::{self, read, write};
+use core::fmt;
+use core::ptr;
 use core::{str, usize};
 
 use array_macro::array;
 
-use crate::buffer_cache::{BLOCK_CACHE_MANAGER, BufData};
-use crate::superblock::{SUPER_BLOCK, RawSuperBlock};
+use crate::xv6fs::Xv6FS;
+use crate::log::Transaction;
+use crate::buffer_cache::Buf;
+use crate::error::FsError;
 use super::stat::Stat;
 use crate::disk_inode::{ InodeType, DiskInode, DirEntry };
-use super::bitmap::{balloc, bfree};
+use super::bitmap::{balloc, balloc_data, bfree};
 use alloc::{vec::Vec,string::String};
-use lazy_init::LazyInit;
-
-pub static ICACHE: LazyInit<InodeCache> = LazyInit::new();
+use alloc::sync::{Arc, Weak};
 
 type BlockNo = u32;
 
- 
+/// Inode cache of one mounted file system, owned by its `Xv6FS` handle.
 pub struct InodeCache {
+    fs: Weak<Xv6FS>,
     meta: Mutex<[InodeMeta; NINODE]>,
     data: [SleepLock<InodeData>; NINODE]
 }
 
 impl InodeCache {
-    pub fn new() -> Self {
+    pub fn new(fs: Weak<Xv6FS>) -> Self {
         Self {
             meta: Mutex::new(array![_ => InodeMeta::new(); NINODE]),
-            data: array![_ => SleepLock::new(InodeData::new(),init_lock()); NINODE],
+            data: array![_ => SleepLock::new(InodeData::new(fs.clone()),init_lock()); NINODE],
+            fs,
         }
     }
 
+    /// The file system owning this cache.
+    fn fs(&self) -> Arc<Xv6FS> {
+        self.fs.upgrade().expect("inode cache: file system is gone")
+    }
+
 
     /// Clone an inode by just increment its reference count by 1. 
     fn dup(&self, inode: &Inode) -> Inode {
         let mut guard = self.meta.lock();
         guard[inode.index].refs += 1;
         Inode {
+            fs: Arc::clone(&inode.fs),
             dev: inode.dev,
             inum: inode.inum,
             index: inode.index
@@ -78,10 +85,28 @@ impl InodeCache {
                 drop(guard);
             } else {
                 drop(guard);
-                idata.dinode.itype = InodeType::Empty;
-                idata.truncate(inode);
+                // free it in the operation dropping it, or in one of its own
+                let tx = Transaction::join(&inode.fs.log, &inode.fs.bcache);
+                // the inode stays allocated unless its blocks are freed
+                match idata.truncate(inode) {
+                    Ok(()) => {
+                        idata.dinode.itype = InodeType::Empty;
+                        if let Err(err) = idata.update() {
+                            warn!("[Xv6fs] inode {}: freeing: {}", inode.inum, err);
+                        }
+                    }
+                    Err(err) => {
+                        warn!("[Xv6fs] inode {}: freeing its blocks: {}", inode.inum, err);
+                        if err != FsError::ReadOnly {
+                            let _ = inode.fs.corrupted();
+                        }
+                    }
+                }
                 idata.valid = false;
                 drop(idata);
+                if let Err(err) = tx.end() {
+                    warn!("[Xv6fs] inode {}: committing its freeing: {}", inode.inum, err);
+                }
 
                 // recycle after this inode content in the cache is no longer valid. 
                 // note: it is wrong to recycle it earlier, 
@@ -101,38 +126,45 @@ impl InodeCache {
     /// Allocate an inode on device dev. 
     /// Mark it as allocated by giving it type type. 
     /// Returns an unlocked but allocated and reference inode 
-    pub fn alloc(&self, dev: u32, itype: InodeType) -> Option<Inode> {
-        let ninodes = unsafe {
-            SUPER_BLOCK.ninodes()
-        };
-        for inum in 1 ..= ninodes {
+    pub fn alloc(&self, dev: u32, itype: InodeType) -> Result<Inode, FsError> {
+        let fs = self.fs();
+        fs.check_writable()?;
+        let ninodes = fs.sb.ninodes();
+        for inum in 1 .. ninodes {
             // get block id
-            let block_id = unsafe {
-                SUPER_BLOCK.locate_inode(inum)
-            };
+            let block_id = fs.sb.locate_inode(inum)?;
             // read block into buffer by device and block_id
             debug!("alloc");
-            let mut block = BLOCK_CACHE_MANAGER.bread(dev, block_id);
+            let mut block = fs.bcache.bread(dev, block_id)?;
         
             // Get inode offset in the block
-            let offset = locate_inode_offset(inum) as isize;
-            let dinode = unsafe { (block.raw_data_mut() as *mut DiskInode).offset(offset) };
-            let dinode = unsafe{ &mut *dinode };
+            let offset = locate_inode_offset(inum) * DiskInode::SIZE;
+            let mut dinode = match DiskInode::decode(&block.data()[offset..]) {
+                Ok(dinode) => dinode,
+                Err(_) => continue,
+            };
             // Find a empty inode
             if dinode.try_alloc(itype).is_ok() {
-                LOG_MANAGER.write(block);
-                return Some(self.get(dev, inum))
+                fs.log.reserve(&block)?;
+                dinode.encode(&mut block.data_mut()[offset..]);
+                fs.log.write(block)?;
+                return self.get(dev, inum)
             }
             // drop(block);
         }
-        None
+        Err(FsError::NoInodes)
     }
 
     /// Lookup the inode in the inode cache. 
     /// If found, return an handle. 
     /// If not found, alloc an in-memory location in the cache, 
     /// but not fetch it from the disk yet. 
-    pub fn get(&self, dev: u32, inum: u32) -> Inode {
+    /// Fails with `CacheFull` if every slot is in use,
+    /// and with `NotFound` if the inode number is out of range. 
+    pub fn get(&self, dev: u32, inum: u32) -> Result<Inode, FsError> {
+        if inum == 0 || inum >= self.fs().sb.ninodes() {
+            return Err(FsError::NotFound);
+        }
         let mut guard = self.meta.lock();
 
         // lookup in the cache 
@@ -141,11 +173,12 @@ impl InodeCache {
             if guard[i].inum == inum && guard[i].refs > 0 && guard[i].dev == dev {
                 guard[i].refs += 1;
                 // info!("[Debug] 获取Inode");
-                return Inode {
+                return Ok(Inode {
+                    fs: self.fs(),
                     dev,
                     inum,
                     index: i,
-                }
+                })
             }
             if empty_i.is_none() && guard[i].refs == 0 {
                 empty_i = Some(i);
@@ -155,28 +188,31 @@ impl InodeCache {
         // not found 
         let empty_i = match empty_i {
             Some(i) => i,
-            None => panic!("inode: not enough"),
+            None => return Err(FsError::CacheFull),
         };
         guard[empty_i].dev = dev;
         guard[empty_i].inum = inum;
         guard[empty_i].refs = 1;
         // 此时 Inode Cache 应当是无效的
-        let idata = self.data[empty_i].lock();
-        assert!(idata.valid == false, "此时 idata 应当无效");
-        Inode {
+        // a slot left valid would show the inode it held before
+        let mut idata = self.data[empty_i].lock();
+        idata.valid = false;
+        drop(idata);
+        Ok(Inode {
+            fs: self.fs(),
             dev,
             inum,
             index: empty_i
-        }
+        })
     }
 
-    pub fn get_inum_type(&self,dev: u32,inum: u32)->InodeType{
-        let inode=self.get(dev, inum);
-        let inode_data=inode.lock();
+    pub fn get_inum_type(&self,dev: u32,inum: u32)->Result<InodeType, FsError>{
+        let inode=self.get(dev, inum)?;
+        let inode_data=inode.lock()?;
         let dinode=inode_data.dinode;
         let itype=dinode.itype;
         drop(inode_data);
-        itype 
+        Ok(itype)
     }
 
     /// Helper function for 'namei' and 'namei_parent'
@@ -185,78 +221,72 @@ impl InodeCache {
         path: &[u8], 
         name: &mut [u8;DIRSIZ], 
         is_parent: bool
-    ) -> Option<Inode> {
+    ) -> Result<Inode, FsError> {
         let mut inode: Inode;
-        if path[0] == b'/' {
-            inode = self.get(ROOTDEV, ROOTINUM);
+        if path_byte(path, 0) == b'/' {
+            inode = self.get_root_dir()?;
             //info!("path 0 is /");
         } else {
             //这里是要获取当前目录的名称
-            inode=self.dup(INTERFACE_MANAGER.interface.as_ref().get_cur_dir_inode().as_ref().unwrap());
+            // the current directory may live in another mounted file system
+            inode = match INTERFACE_MANAGER.interface.as_ref().get_cur_dir_inode() {
+                Some(cwd) if ptr::eq(Arc::as_ptr(&cwd.fs), self.fs.as_ptr()) => cwd,
+                _ => self.get_root_dir()?,
+            };
         }
         let mut cur: usize = 0;
         loop {
-            cur = skip_path(path, cur, name);//这里name获取了/后面的第一个路径名
+            cur = skip_path(path, cur, name)?;//这里name获取了/后面的第一个路径名
             if cur == 0 { break; }
             //info!("cur is {:?}, and name is {:?}",cur,String::from_utf8(name.to_vec()).unwrap());
-            let mut data_guard = inode.lock();
+            let mut data_guard = inode.lock()?;
             //info!("acquire lock");
             if data_guard.dinode.itype != InodeType::Directory {
                 drop(data_guard);
-                return None
+                return Err(FsError::NotDir)
             }
-            if is_parent && path[cur] == 0 {
+            if is_parent && path_byte(path, cur) == 0 {
                 //info!("is is parent and path[cur]=0");
                 drop(data_guard);
-                return Some(inode)
+                return Ok(inode)
             }
 
-            match data_guard.dir_lookup(name) {
-                None => {
-                    drop(data_guard);
-                    // info!("[Kernel] name: {}", String::from_utf8(name.to_vec()).unwrap());
-                    return None
-                },
-                Some(last_inode) => {
-                    drop(data_guard);
-                    inode = last_inode;
-                }
-            }
+            let next = data_guard.dir_lookup(name);
+            drop(data_guard);
+            // info!("[Kernel] name: {}", String::from_utf8(name.to_vec()).unwrap());
+            inode = next?;
             mem_set(name.as_mut_ptr(), 0, DIRSIZ);
         }
         if is_parent {
             // only when querying root inode's parent 
             //info!("[Kernel] Warning: namex querying root inode's parent");
-            None 
+            Err(FsError::InvalidArg)
         } else {
-            Some(inode)
+            Ok(inode)
         }
     }
 
     /// namei interprets the path argument as an pathname to Unix file. 
     /// It will return an [`inode`] if succeed, Err(()) if fail. 
     /// It must be called inside a transaction(i.e.,'begin_op' and `end_op`) since it calls `put`.
-    /// Note: the path should end with 0u8, otherwise it might panic due to out-of-bound. 
-    pub fn namei(&self, path: &[u8]) -> Option<Inode> {
+    /// Note: the path ends at its first 0u8 or at the end of the slice. 
+    pub fn namei(&self, path: &[u8]) -> Result<Inode, FsError> {
         let mut name: [u8;DIRSIZ] = [0;DIRSIZ];
         self.namex(path, &mut name, false)
     }
 
     /// Same behavior as `namei`, but return the parent of the inode, 
     /// and copy the end path into name. 
-    pub fn namei_parent(&self, path: &[u8], name: &mut [u8;DIRSIZ]) -> Option<Inode> {
+    pub fn namei_parent(&self, path: &[u8], name: &mut [u8;DIRSIZ]) -> Result<Inode, FsError> {
         self.namex(path, name, true)
     }
 
-    pub fn look_up(&self,path: &[u8])->Result<Inode, &'static str>{
-        info!("[Xv6fs] lookup file/dir: path: {}", String::from_utf8(path.to_vec()).unwrap());
+    pub fn look_up(&self,path: &[u8])->Result<Inode, FsError>{
+        info!("[Xv6fs] lookup file/dir: path: {:?}", str::from_utf8(path));
         let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
-        let dirinode = self.namei_parent(path, &mut name).unwrap();
-        let mut dirinode_guard = dirinode.lock();
-        match dirinode_guard.dir_lookup(&name) {
-            Some(node) => Ok(node), 
-            None => Err("not found"),
-        }
+        let dirinode = self.namei_parent(path, &mut name)?;
+        let mut dirinode_guard = dirinode.lock()?;
+        dirinode_guard.dir_lookup(&name)
     }
 
     pub fn create(
@@ -265,153 +295,280 @@ impl InodeCache {
         itype: InodeType,
         major: i16,
         minor: i16
-    ) -> Result<Inode, &'static str> {
-        info!("[Xv6fs] create file/dir: path: {}", String::from_utf8(path.to_vec()).unwrap());
+    ) -> Result<Inode, FsError> {
+        info!("[Xv6fs] create file/dir: path: {:?}", str::from_utf8(path));
         let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
-        let dirinode = self.namei_parent(path, &mut name).unwrap();
-        let mut dirinode_guard = dirinode.lock();
-        match dirinode_guard.dir_lookup(&name) {
-            Some(inode) => {
+        let dirinode = self.namei_parent(path, &mut name)?;
+        self.create_at(&dirinode, &name, itype, major, minor)
+    }
+
+    /// Create `name` in the directory `dirinode`.
+    /// An existing file or directory is returned as is
+    /// when a file or directory is asked for.
+    pub fn create_at(
+        &self,
+        dirinode: &Inode,
+        name: &[u8],
+        itype: InodeType,
+        major: i16,
+        minor: i16
+    ) -> Result<Inode, FsError> {
+        let mut dirinode_guard = dirinode.lock()?;
+        match dirinode_guard.dir_lookup(name) {
+            Ok(inode) => {
                 drop(dirinode_guard);
-                let inode_guard = inode.lock();
+                let inode_guard = inode.lock()?;
                 match inode_guard.dinode.itype {
                     InodeType::Directory| InodeType::Device | InodeType::File => {
                         if itype == InodeType::File || itype == InodeType::Directory {
                             drop(inode_guard);
                             return Ok(inode)
                         }
-                        return Err("create: unmatched type.");
+                        return Err(FsError::Exists);
                     },
     
                     _ => {
-                        return Err("create: unmatched type.")
+                        return Err(FsError::Exists)
                     }
                 }
             },
-    
-            None => {}
+
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
         }
+        self.fs().check_writable()?;
         // Allocate a new inode to create file
         let dev = dirinode_guard.dev;
-        let inum = inode_alloc(dev, itype);
-        let inode = self.get(dev, inum);
+        let inum = inode_alloc(&self.fs(), dev, itype)?;
+        let inode = self.get(dev, inum)?;
         
-        let mut inode_guard = inode.lock();
+        let mut inode_guard = inode.lock()?;
         // initialize new allocated inode
         inode_guard.dinode.major = major;
         inode_guard.dinode.minor = minor;
         inode_guard.dinode.nlink = 1;
         // Write back to disk
-        inode_guard.update();
+        inode_guard.update()?;
         debug_assert_eq!(inode_guard.dinode.itype, itype);
     
         // Directory, create .. 
         if itype == InodeType::Directory {
             // Create . and .. entries. 
             inode_guard.dinode.nlink += 1;
-            inode_guard.update();
+            inode_guard.update()?;
             // No nlink++ for . to avoid recycle ref count. 
             inode_guard.dir_link(".".as_bytes(), inode.inum)?;
             inode_guard.dir_link("..".as_bytes(), dirinode_guard.inum)?;
         }
-        dirinode_guard
-            .dir_link(&name, inode_guard.inum)
-            .expect("Parent inode fail to link");
+        dirinode_guard.dir_link(name, inode_guard.inum)?;
 
         drop(inode_guard);
         drop(dirinode_guard);
         Ok(inode)
     }
 
-    pub fn get_root_dir(&self)->Inode{
-        self.get(ROOTDEV, ROOTINUM)
+    /// Remove the entry `name` from the directory `dirinode`
+    /// and drop one link of its inode, which is freed
+    /// once the last link and the last reference are gone.
+    /// Directories must be empty.
+    pub fn unlink_at(&self, dirinode: &Inode, name: &[u8]) -> Result<(), FsError> {
+        if name_eq(name, b".") || name_eq(name, b"..") {
+            return Err(FsError::InvalidArg);
+        }
+        self.fs().check_writable()?;
+        let mut dirinode_guard = dirinode.lock()?;
+        let inode = dirinode_guard.dir_lookup(name)?;
+        let mut inode_guard = inode.lock()?;
+        if inode_guard.dinode.itype == InodeType::Directory {
+            if !inode_guard.is_dir_empty()? {
+                return Err(FsError::NotEmpty);
+            }
+            // its "." goes with it
+            inode_guard.dinode.nlink = 0;
+        } else {
+            inode_guard.dinode.nlink = inode_guard.dinode.nlink.saturating_sub(1);
+        }
+        dirinode_guard.dir_unlink(name)?;
+        inode_guard.update()?;
+        drop(inode_guard);
+        drop(dirinode_guard);
+        Ok(())
     }
 
-    pub fn remove(&self,path: &[u8])->Result<(),&'static str>{
+    /// Add the entry `name` for `inode` to the directory `dirinode`.
+    /// Directories cannot be hard-linked.
+    pub fn link_at(&self, inode: &Inode, dirinode: &Inode, name: &[u8]) -> Result<(), FsError> {
+        self.fs().check_writable()?;
+        let mut inode_guard = inode.lock()?;
+        if inode_guard.dinode.itype == InodeType::Directory {
+            return Err(FsError::PermissionDenied);
+        }
+        drop(inode_guard);
+        let mut dirinode_guard = dirinode.lock()?;
+        dirinode_guard.dir_link(name, inode.inum)?;
+        drop(dirinode_guard);
+        inode_guard = inode.lock()?;
+        inode_guard.dinode.nlink += 1;
+        inode_guard.update()
+    }
+
+    /// Move the entry `old_name` of `old_dir` to `new_name` in `new_dir`,
+    /// replacing what `new_name` refers to.
+    pub fn rename_at(
+        &self,
+        old_dir: &Inode,
+        old_name: &[u8],
+        new_dir: &Inode,
+        new_name: &[u8]
+    ) -> Result<(), FsError> {
+        if name_eq(old_name, b".") || name_eq(old_name, b"..")
+            || name_eq(new_name, b".") || name_eq(new_name, b"..") {
+            return Err(FsError::InvalidArg);
+        }
+        self.fs().check_writable()?;
+        let inode = old_dir.lock()?.dir_lookup(old_name)?;
+        let is_dir = inode.lock()?.dinode.itype == InodeType::Directory;
+        if is_dir && old_dir.inum != new_dir.inum {
+            // a directory cannot move below itself
+            let mut cur = self.dup(new_dir);
+            let mut depth = 0;
+            while cur.inum != ROOTINUM {
+                if cur.inum == inode.inum {
+                    return Err(FsError::InvalidArg);
+                }
+                // the ".." chain of a sound tree reaches the root
+                depth += 1;
+                if depth > self.fs().sb.ninodes() {
+                    return Err(self.fs().corrupted());
+                }
+                let parent = cur.lock()?.dir_lookup(b"..")?;
+                cur = parent;
+            }
+        }
+        let target = new_dir.lock()?.dir_lookup(new_name);
+        match target {
+            Ok(target) if target.inum == inode.inum => return Ok(()),
+            Ok(target) => {
+                let target_is_dir = target.lock()?.dinode.itype == InodeType::Directory;
+                if target_is_dir != is_dir {
+                    return Err(if is_dir { FsError::NotDir } else { FsError::IsDir });
+                }
+                drop(target);
+                self.unlink_at(new_dir, new_name)?;
+            }
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        new_dir.lock()?.dir_link(new_name, inode.inum)?;
+        old_dir.lock()?.dir_unlink(old_name)?;
+        if is_dir && old_dir.inum != new_dir.inum {
+            let mut inode_guard = inode.lock()?;
+            inode_guard.dir_unlink(b"..")?;
+            inode_guard.dir_link(b"..", new_dir.inum)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_root_dir(&self)->Result<Inode, FsError>{
+        self.get(self.fs().dev, ROOTINUM)
+    }
+
+    pub fn remove(&self,path: &[u8])->Result<(),FsError>{
         //info!("begin remove");
         info!("[Xv6fs] remove file/dir, path is {:?}",core::str::from_utf8(path));
+        self.fs().check_writable()?;
         let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
-        let dirinode = self.namei_parent(path, &mut name).unwrap();
+        let dirinode = self.namei_parent(path, &mut name)?;
         //info!("name is {:?} as {:?}",&name,String::from_utf8(name.to_vec()));
-        let mut dirinode_guard = dirinode.lock();
+        let mut dirinode_guard = dirinode.lock()?;
         //info!("get locked dirinode!");
-        match dirinode_guard.dir_lookup(&name) {
-            Some(inode) => {
-                let mut idata = inode.lock();
-                //info!("get locked inode!");
-                match idata.dinode.itype {
-                    InodeType::Directory=> {
-                        idata.clear_dir()?;
-                        idata.dinode.itype=InodeType::Empty;
-                        idata.truncate(&inode);
-                        idata.valid=false;
-                        drop(idata);
-                        dirinode_guard.dir_unlink(&name)?;
-                        dirinode_guard.update();
-                        return Ok(());
-                    },
+        let inode = dirinode_guard.dir_lookup(&name)?;
+        let mut idata = inode.lock()?;
+        //info!("get locked inode!");
+        match idata.dinode.itype {
+            InodeType::Directory=> {
+                idata.clear_dir()?;
+                idata.dinode.itype=InodeType::Empty;
+                idata.truncate(&inode)?;
+                idata.valid=false;
+                drop(idata);
+                dirinode_guard.dir_unlink(&name)?;
+                dirinode_guard.update()?;
+                Ok(())
+            },
 
-                    InodeType::File=>{
-                        idata.dinode.itype=InodeType::Empty;
-                        idata.truncate(&inode);
-                        idata.valid=false;
-                        drop(idata);
-                        dirinode_guard.dir_unlink(&name)?;
-                        dirinode_guard.update();
-                        return Ok(());
-                    },
-    
-                    _ => {
-                        return Err("remove: unmatched type.")
-                    }
-                }
+            InodeType::File=>{
+                idata.dinode.itype=InodeType::Empty;
+                idata.truncate(&inode)?;
+                idata.valid=false;
+                drop(idata);
+                dirinode_guard.dir_unlink(&name)?;
+                dirinode_guard.update()?;
+                Ok(())
             },
-    
-            None => {
-                return  Err("remove: error path.");
+
+            _ => {
+                Err(FsError::InvalidArg)
             }
         }
     }
 }
 
+/// The byte at `i` of a path, treating the end of the slice as its terminator.
+#[inline]
+fn path_byte(path: &[u8], i: usize) -> u8 {
+    path.get(i).copied().unwrap_or(0)
+}
+
 /// Skip the path starting at cur by b'/'s. 
 /// It will copy the skipped content to name. 
-/// Return the current offset after skiping. 
+/// Return the current offset after skiping, 
+/// or `NameTooLong` if the component does not fit in name. 
 fn skip_path(
     path: &[u8], 
     mut cur: usize, 
     name: &mut [u8; DIRSIZ]
-) -> usize {
+) -> Result<usize, FsError> {
     // skip preceding b'/'
-    while path[cur] == b'/' {
+    while path_byte(path, cur) == b'/' {
         cur += 1;
     }
-    if path[cur] == 0 {
-        return 0
+    if path_byte(path, cur) == 0 {
+        return Ok(0)
     }
 
     let start = cur;
-    while path[cur] != b'/' && path[cur] != 0 {
+    while path_byte(path, cur) != b'/' && path_byte(path, cur) != 0 {
         cur += 1;
     }
 
-    let mut count = cur - start; 
+    let count = cur - start; 
     if count >= name.len() {
-        debug_assert!(false);
-        count = name.len() - 1;
-    }
-    unsafe{
-        ptr::copy(path.as_ptr().offset(start as isize), name.as_mut_ptr(), count);
+        return Err(FsError::NameTooLong);
     }
+    name[..count].copy_from_slice(&path[start..cur]);
     name[count] = 0;
 
     // skip succeeding b'/'
-    while path[cur] == b'/' {
+    while path_byte(path, cur) == b'/' {
         cur += 1;
     }
-    cur
+    Ok(cur)
 }
 
+/// Compare two directory entry names, each ending at its first 0u8 or at DIRSIZ. 
+fn name_eq(a: &[u8], b: &[u8]) -> bool {
+    for i in 0..DIRSIZ {
+        let (x, y) = (path_byte(a, i), path_byte(b, i));
+        if x != y {
+            return false;
+        }
+        if x == 0 {
+            return true;
+        }
+    }
+    true
+}
 
 struct InodeMeta {
     /// device number
@@ -437,22 +594,35 @@ impl InodeMeta {
 
 /// In-memory copy of an inode
 pub struct InodeData {
+    fs: Weak<Xv6FS>,
     pub valid: bool,
     pub dev: u32,
     pub inum: u32,
-    pub dinode: DiskInode
+    pub dinode: DiskInode,
+    /// the block a read continuing the last one starts at
+    ra_next: u32,
+    /// the end of the blocks read-ahead already asked for
+    ra_end: u32,
 }
 
 impl InodeData {
-    const fn new() -> Self {
+    fn new(fs: Weak<Xv6FS>) -> Self {
         Self {
+            fs,
             valid: false,
             dev: 0,
             inum: 0,
-            dinode: DiskInode::new()
+            dinode: DiskInode::new(),
+            ra_next: 0,
+            ra_end: 0,
         }
     }
 
+    /// The file system this inode belongs to.
+    fn fs(&self) -> Arc<Xv6FS> {
+        self.fs.upgrade().expect("inode: file system is gone")
+    }
+
 
     /// Copy stat information from inode
     pub fn stat(&self, stat: &mut Stat) {
@@ -463,21 +633,25 @@ impl InodeData {
         stat.size = self.dinode.size as usize;
     }
 
-    pub fn clear_block(dev:u32,block_id:u32){
+    pub fn clear_block(&self,block_id:u32)->Result<(),FsError>{
         //debug!("clear block blockid is {}",block_id);
-        let mut buf=BLOCK_CACHE_MANAGER.bread(dev, block_id);
+        let fs=self.fs();
+        let mut buf=fs.bcache.bread(self.dev, block_id)?;
+        fs.log.reserve(&buf)?;
         let buf_ptr=unsafe{(buf.raw_data_mut() as *mut u8).offset(0)};
         let empty_block:[u8;BSIZE]=[0;BSIZE];
         unsafe{ptr::copy(&empty_block as *const u8, buf_ptr, BSIZE)};
-        LOG_MANAGER.write(buf);
+        fs.log.write(buf)
     }
 
     /// Discard the inode data/content. 
-    pub fn truncate(&mut self, inode: &Inode) {
+    pub fn truncate(&mut self, inode: &Inode) -> Result<(), FsError> {
+        let fs=self.fs();
+        fs.check_writable()?;
         // direct block
         for i in 0..NDIRECT {
             if self.dinode.addrs[i] > 0 {
-                let _=bfree(self.dinode.addrs[i]);
+                bfree(&fs, self.dinode.addrs[i])?;
                 self.dinode.addrs[i] = 0;
             }
         }
@@ -485,249 +659,295 @@ impl InodeData {
         // indirect block
         if self.dinode.addrs[NDIRECT] > 0 {
             //debug!("truncate bread indirect block ");
-            let buf = BLOCK_CACHE_MANAGER.bread(inode.dev, self.dinode.addrs[NDIRECT]);
-            let buf_ptr = buf.raw_data() as *const BlockNo;
+            let buf = fs.bcache.bread(inode.dev, self.dinode.addrs[NDIRECT])?;
             for i in 0..NINDIRECT {
-                let bn = unsafe{ read(buf_ptr.offset(i as isize)) };
+                let bn = get_u32(buf.data(), i * 4);
                 if bn > 0 {
-                    let _=bfree(bn);
+                    bfree(&fs, bn)?;
                 }
             }
             drop(buf);
-            let _=bfree(self.dinode.addrs[NDIRECT]);//这里要清空这个页面才行
+            bfree(&fs, self.dinode.addrs[NDIRECT])?;//这里要清空这个页面才行
             self.dinode.addrs[NDIRECT] = 0;
         }
 
         if self.dinode.addrs[NDIRECT+1] > 0 {
             //debug!("truncate bread inindirect block");
-            let buf = BLOCK_CACHE_MANAGER.bread(inode.dev, self.dinode.addrs[NDIRECT+1]);
-            let buf_ptr=buf.raw_data() as *const BlockNo;
+            let buf = fs.bcache.bread(inode.dev, self.dinode.addrs[NDIRECT+1])?;
+            let ibns: Vec<BlockNo> = (0..NINDIRECT)
+                .map(|i| get_u32(buf.data(), i * 4))
+                .filter(|&ibn| fs.sb.is_data_block(ibn))
+                .collect();
+            fs.bcache.preload(inode.dev, &ibns);
             for i in 0..NINDIRECT{
-                let ibn=unsafe { read(buf_ptr.offset(i as isize))};
+                let ibn=get_u32(buf.data(), i * 4);
                 info!("[Xv6fs] inode truncate: indirect block no is {}",ibn);
+                if ibn > 0 && !fs.sb.is_data_block(ibn) {
+                    let _ = fs.corrupted();
+                    continue;
+                }
                 if ibn > 0{
                     //debug!("ibn is {}",ibn);
-                    let ibuf=BLOCK_CACHE_MANAGER.bread(inode.dev, ibn);
-                    let ibuf_ptr=ibuf.raw_data() as *const BlockNo;
+                    let ibuf=fs.bcache.bread(inode.dev, ibn)?;
                     for j in 0..NINDIRECT{
-                        let bn = unsafe{ read(ibuf_ptr.offset(j as isize)) };
+                        let bn = get_u32(ibuf.data(), j * 4);
                         info!("[Xv6fs] inode truncate: direct block no is {}",bn);
                         if bn > 0 {
-                            let _=bfree(bn);
+                            bfree(&fs, bn)?;
                         }
                     }
                     drop(ibuf);
-                    let _=bfree(ibn);
+                    bfree(&fs, ibn)?;
                 }
             }
             drop(buf);
-            let _=bfree(self.dinode.addrs[NDIRECT+1]);
+            bfree(&fs, self.dinode.addrs[NDIRECT+1])?;
             self.dinode.addrs[NDIRECT+1]=0;
         }
 
         self.dinode.size = 0;
-        self.update();
+        self.update()
     }
 
-    pub fn resize(&mut self,inode: &Inode,size:u64)->usize{//todo! need verify！！！
-        let nblocks:usize=match size%BSIZE as u64{
-            0=>size as usize/BSIZE,
-            _=>size as usize/BSIZE+1,
-        };
-        let begin:usize=match self.dinode.size as usize%BSIZE{
-            0=>self.dinode.size as usize/BSIZE,
-            _=>self.dinode.size as usize/BSIZE+1,
-        };
-        if self.dinode.size == size as u32{
-            return size as usize;
-        }else if self.dinode.size > size as u32{
-            for i in nblocks..NDIRECT {
+    /// Set the file size, freeing the blocks past the new end when shrinking.
+    /// Growing leaves a hole, which reads as zeros.
+    pub fn resize(&mut self,inode: &Inode,size:u64)->Result<usize,FsError>{
+        if size > (MAXFILE * BSIZE) as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+        let fs=self.fs();
+        fs.check_writable()?;
+        if size < self.dinode.size as u64 {
+            // first block index to free
+            let nblocks = (size as usize + BSIZE - 1) / BSIZE;
+            for i in nblocks.min(NDIRECT)..NDIRECT {
                 if self.dinode.addrs[i] > 0 {
-                    let _=bfree(self.dinode.addrs[i]);
+                    bfree(&fs, self.dinode.addrs[i])?;
                     self.dinode.addrs[i] = 0;
                 }
             }
-    
+
             // indirect block
-            let mut _count=NDIRECT;
-            let indirect_index=(nblocks-NDIRECT).max(0);
             if self.dinode.addrs[NDIRECT] > 0 {
-                //debug!("truncate bread indirect block ");
-                let buf = BLOCK_CACHE_MANAGER.bread(inode.dev, self.dinode.addrs[NDIRECT]);
-                let buf_ptr = buf.raw_data() as *const BlockNo;
-                for i in indirect_index..NINDIRECT {
-                    let bn = unsafe{ read(buf_ptr.offset(i as isize)) };
-                    if bn > 0 {
-                        let _=bfree(bn);
-                    }
-                    _count += 1;
-                }
-                drop(buf);
-                if nblocks <= NDIRECT{
-                    let _=bfree(self.dinode.addrs[NDIRECT]);//这里要清空这个页面才行
+                let first = nblocks.saturating_sub(NDIRECT).min(NINDIRECT);
+                self.free_slots(inode, self.dinode.addrs[NDIRECT], first)?;
+                if first == 0 {
+                    bfree(&fs, self.dinode.addrs[NDIRECT])?;
                     self.dinode.addrs[NDIRECT] = 0;
                 }
             }
-            let left_blocks=(nblocks-NDIRECT-NINDIRECT).max(0);
-            let inindirect_index=left_blocks/NINDIRECT;
-            let indirect_index=left_blocks%NINDIRECT;
-            if self.dinode.addrs[NDIRECT+1] > 0 {//这个还没弄呢
-                //debug!("truncate bread inindirect block");
-                let buf = BLOCK_CACHE_MANAGER.bread(inode.dev, self.dinode.addrs[NDIRECT+1]);
-                let buf_ptr=buf.raw_data() as *const BlockNo;
-                let ibn=unsafe { read(buf_ptr.offset(inindirect_index as isize))};
-                if ibn > 0{
-                    //debug!("ibn is {}",ibn);
-                    let ibuf=BLOCK_CACHE_MANAGER.bread(inode.dev, ibn);
-                    let ibuf_ptr=ibuf.raw_data() as *const BlockNo;
-                    for j in indirect_index..NINDIRECT{
-                        let bn = unsafe{ read(ibuf_ptr.offset(j as isize)) };
-                        info!("[Xv6fs] inode truncate: direct block no is {}",bn);
-                        if bn > 0 {
-                            let _=bfree(bn);
-                        }
+
+            // double indirect block
+            if self.dinode.addrs[NDIRECT+1] > 0 {
+                let first = nblocks.saturating_sub(NDIRECT+NINDIRECT);
+                let buf = fs.bcache.bread(inode.dev, self.dinode.addrs[NDIRECT+1])?;
+                let ibns: Vec<BlockNo> = (0..NINDIRECT)
+                    .map(|i| get_u32(buf.data(), i * 4))
+                    .collect();
+                drop(buf);
+                let freed: Vec<BlockNo> = ibns.iter().enumerate()
+                    .filter(|&(i, &ibn)| (i + 1) * NINDIRECT > first && fs.sb.is_data_block(ibn))
+                    .map(|(_, &ibn)| ibn)
+                    .collect();
+                fs.bcache.preload(inode.dev, &freed);
+                let mut cleared = Vec::new();
+                for (i, ibn) in ibns.into_iter().enumerate() {
+                    let begin = i * NINDIRECT;
+                    if ibn == 0 || begin + NINDIRECT <= first {
+                        continue;
                     }
-                    drop(ibuf);
-                    if indirect_index==0{
-                        let _=bfree(ibn);
+                    if !fs.sb.is_data_block(ibn) {
+                        return Err(fs.corrupted());
+                    }
+                    let from = first.saturating_sub(begin);
+                    self.free_slots(inode, ibn, from)?;
+                    if from == 0 {
+                        bfree(&fs, ibn)?;
+                        cleared.push(i);
                     }
                 }
-                for i in inindirect_index+1..NINDIRECT{
-                    let ibn=unsafe { read(buf_ptr.offset(i as isize))};
-                    info!("[Xv6fs] inode truncate: indirect block no is {}",ibn);
-                    if ibn > 0{
-                        //debug!("ibn is {}",ibn);
-                        let ibuf=BLOCK_CACHE_MANAGER.bread(inode.dev, ibn);
-                        let ibuf_ptr=ibuf.raw_data() as *const BlockNo;
-                        for j in 0..NINDIRECT{
-                            let bn = unsafe{ read(ibuf_ptr.offset(j as isize)) };
-                            info!("[Xv6fs] inode truncate: direct block no is {}",bn);
-                            if bn > 0 {
-                                let _=bfree(bn);
-                            }
-                        }
-                        drop(ibuf);
-                        let _=bfree(ibn);
+                if first > 0 && !cleared.is_empty() {
+                    let mut buf = fs.bcache.bread(inode.dev, self.dinode.addrs[NDIRECT+1])?;
+                    fs.log.reserve(&buf)?;
+                    for i in cleared {
+                        put_u32(buf.data_mut(), i * 4, 0);
                     }
+                    fs.log.write(buf)?;
                 }
-                drop(buf);
-                if nblocks <= NDIRECT+NINDIRECT{
-                    let _=bfree(self.dinode.addrs[NDIRECT+1]);
+                if first == 0 {
+                    bfree(&fs, self.dinode.addrs[NDIRECT+1])?;
                     self.dinode.addrs[NDIRECT+1]=0;
                 }
             }
-        }else{
-            for i in begin..=nblocks{
-                let _=self.bmap(i as u32,true);
+
+            // zero the tail of the last block, so that growing the file again reads zeros
+            let tail = size as usize % BSIZE;
+            if tail != 0 {
+                let block_no = self.bmap((size as usize / BSIZE) as u32, false)?;
+                if block_no != 0 {
+                    let mut buf = fs.bcache.bread(self.dev, block_no)?;
+                    self.reserve_content(&fs, &buf)?;
+                    unsafe{ ptr::write_bytes((buf.raw_data_mut() as *mut u8).add(tail), 0, BSIZE - tail) };
+                    self.write_content(&fs, buf)?;
+                }
             }
         }
-        self.update();
-        0
+        self.dinode.size = size as u32;
+        self.update()?;
+        Ok(size as usize)
+    }
+
+    /// Free the blocks referenced from slot `first` on in the indirect block `iaddr`.
+    /// The cleared slots only go to the log if the indirect block itself is kept. 
+    fn free_slots(&mut self, inode: &Inode, iaddr: BlockNo, first: usize) -> Result<(), FsError> {
+        if first >= NINDIRECT {
+            return Ok(());
+        }
+        let fs=self.fs();
+        let mut buf = fs.bcache.bread(inode.dev, iaddr)?;
+        if first > 0 {
+            // the block stays, so its cleared slots are logged
+            fs.log.reserve(&buf)?;
+        }
+        let mut dirty = false;
+        for i in first..NINDIRECT {
+            let bn = get_u32(buf.data(), i * 4);
+            if bn > 0 {
+                bfree(&fs, bn)?;
+                if first > 0 {
+                    put_u32(buf.data_mut(), i * 4, 0);
+                    dirty = true;
+                }
+            }
+        }
+        if dirty && first > 0 {
+            fs.log.write(buf)?;
+        }
+        Ok(())
     }
 
     /// Update a modified in-memory inode to disk. 
     /// Typically called after changing the content of inode info. 
-    pub fn update(&mut self) {
+    pub fn update(&mut self) -> Result<(), FsError> {
         //info!("update: begin update");
-        let mut buf = BLOCK_CACHE_MANAGER.bread(
+        let fs = self.fs();
+        let mut buf = fs.bcache.bread(
             self.dev, 
-            unsafe { SUPER_BLOCK.locate_inode(self.inum)}
-        );
-        let offset = locate_inode_offset(self.inum) as isize;
-        let dinode = unsafe{ (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
-        unsafe{ write(dinode, self.dinode) };
+            fs.sb.locate_inode(self.inum)?
+        )?;
+        let offset = locate_inode_offset(self.inum) * DiskInode::SIZE;
+        fs.log.reserve(&buf)?;
+        self.dinode.encode(&mut buf.data_mut()[offset..]);
         //info!("update: self.dindoe: {:?}", self.dinode);
-        LOG_MANAGER.write(buf);
+        fs.log.write(buf)
     }
 
     /// The content (data) associated with each inode is stored
     /// in blocks on the disk. The first NDIRECT block numbers
     /// are listed in self.dinode.addrs, The next NINDIRECT blocks are 
-    /// listed in block self.dinode.addrs[NDIRECT]. 
+    /// listed in block self.dinode.addrs[NDIRECT], and the last
+    /// NININDIRECT blocks go through the double indirect block
+    /// self.dinode.addrs[NDIRECT+1]. 
     /// 
     /// Return the disk block address of the nth block in inode. 
-    /// If there is no such block, bmap allocates one. 
-    pub fn bmap(&mut self, offset_bn: u32, balloc_flag: bool) -> Result<u32, &'static str> {
-        let mut addr;
-        let mut iaddr:u32;
+    /// If there is no such block, bmap allocates a zeroed one when alloc is set,
+    /// and returns 0 (a hole) otherwise. 
+    pub fn bmap(&mut self, offset_bn: u32, alloc: bool) -> Result<u32, FsError> {
+        let fs = self.fs();
         let offset_bn = offset_bn as usize;
         if offset_bn < NDIRECT {
             if self.dinode.addrs[offset_bn] == 0 {
-                addr = balloc(self.dev);
-                self.dinode.addrs[offset_bn] = addr;
-                return Ok(addr)
-            } else {
-                return Ok(self.dinode.addrs[offset_bn])
+                if !alloc {
+                    return Ok(0)
+                }
+                self.dinode.addrs[offset_bn] = self.alloc_content(&fs)?;
             }
+            return Ok(self.dinode.addrs[offset_bn])
         }
         if offset_bn < NINDIRECT + NDIRECT {
             // Load indirect block, allocating if necessary. 
             let count = offset_bn - NDIRECT;
             if self.dinode.addrs[NDIRECT] == 0 {
-                iaddr = balloc(self.dev);
-                self.dinode.addrs[NDIRECT] = iaddr;
-                Self::clear_block(self.dev, iaddr);
-            } else {
-                iaddr = self.dinode.addrs[NDIRECT]
-            }
-            //debug!("bread iaddr {}",iaddr);
-            let mut buf = BLOCK_CACHE_MANAGER.bread(self.dev, iaddr);
-            let mut buf_data = buf.raw_data() as *mut u32;
-            addr = unsafe{ read(buf_data.offset(count as isize)) };
-            debug!("[Xv6fs] bmap: addr is {}",addr);
-            if addr == 0 || !(bisalloc(addr)) || balloc_flag{
-                unsafe{
-                    addr = balloc(self.dev);
-                    write(buf_data.offset(count as isize), addr);
+                if !alloc {
+                    return Ok(0)
                 }
-                LOG_MANAGER.write(buf);//这里是个什么玩意啊，裂开
+                self.dinode.addrs[NDIRECT] = balloc(&fs, self.dev)?;
             }
-            // drop(buf);
-            return Ok(addr)
+            let iaddr = self.dinode.addrs[NDIRECT];
+            return self.bmap_slot(iaddr, count, alloc, true)
         }
         if offset_bn < NINDIRECT+NDIRECT+NININDIRECT{
             let count=offset_bn-NDIRECT-NINDIRECT;
             if self.dinode.addrs[NDIRECT+1]==0{
-                addr=balloc(self.dev);
-                self.dinode.addrs[NDIRECT+1]=addr;
-                Self::clear_block(self.dev, addr);
-            }else {
-                addr=self.dinode.addrs[NDIRECT+1];
-            }
-            let indirect_count=count/64;
-            let indirect_offset=count%64;
-            //debug!("bread addr {}",addr);
-            let mut buf=BLOCK_CACHE_MANAGER.bread(self.dev, addr);
-            let mut buf_data=buf.raw_data() as * mut u32;
-            let mut iaddr = unsafe { read(buf_data.offset(indirect_count as isize))};
-            //debug!("[Xv6fs] bmap: iaddr is {}, balloc_flag is {}, bisalloc is {}",iaddr,balloc_flag,bisalloc(iaddr));
-            if balloc_flag!=(!bisalloc(iaddr)) && iaddr != 0{
-                //panic!("balloc flag is not same with !bisalloc");
-            }
-            if iaddr == 0 || !(bisalloc(iaddr)) /*|| balloc_flag*/{
-                unsafe{
-                    iaddr=balloc(self.dev);
-                    write(buf_data.offset(indirect_count as isize), iaddr);
-                    Self::clear_block(self.dev, iaddr);
+                if !alloc {
+                    return Ok(0)
                 }
-                LOG_MANAGER.write(buf);
-                drop(buf_data);
+                self.dinode.addrs[NDIRECT+1]=balloc(&fs, self.dev)?;
             }
-            //debug!("bread indirect iaddr {}",iaddr);
-            let mut ibuf=BLOCK_CACHE_MANAGER.bread(self.dev, iaddr);
-            let mut ibuf_data=ibuf.raw_data() as *mut u32;
-            addr=unsafe { read(ibuf_data.offset(indirect_offset as isize))};
-            //debug!("[Xv6fs] bmap: addr is {}, balloc_flag is {}, bisalloc is {}",addr,balloc_flag,bisalloc(addr));
-            if addr ==0 || !(bisalloc(addr)) /*|| balloc_flag*/{
-                unsafe{
-                    addr=balloc(self.dev);
-                    write(ibuf_data.offset(indirect_offset as isize), addr);
-                }
-                LOG_MANAGER.write(ibuf);
+            let addr=self.dinode.addrs[NDIRECT+1];
+            let iaddr=self.bmap_slot(addr, count/NINDIRECT, alloc, false)?;
+            if iaddr == 0 {
+                return Ok(0)
             }
-            return Ok(addr);
+            return self.bmap_slot(iaddr, count%NINDIRECT, alloc, true)
+        }
+        Err(FsError::FileTooLarge)
+    }
+
+    /// Look up slot `index` of the indirect block `iaddr`,
+    /// allocating a zeroed block for it if alloc is set. 
+    /// `leaf` tells a content block from another indirect block.
+    fn bmap_slot(&mut self, iaddr: u32, index: usize, alloc: bool, leaf: bool) -> Result<u32, FsError> {
+        let fs = self.fs();
+        let mut buf = fs.bcache.bread(self.dev, iaddr)?;
+        let mut addr = get_u32(buf.data(), index * 4);
+        debug!("[Xv6fs] bmap: addr is {}",addr);
+        if addr != 0 && !fs.sb.is_data_block(addr) {
+            return Err(fs.corrupted());
+        }
+        if addr == 0 && alloc {
+            fs.log.reserve(&buf)?;
+            addr = if leaf { self.alloc_content(&fs)? } else { balloc(&fs, self.dev)? };
+            put_u32(buf.data_mut(), index * 4, addr);
+            fs.log.write(buf)?;
+        }
+        Ok(addr)
+    }
+
+    /// Whether the content of this inode is file data,
+    /// written in place unless the file system journals data.
+    fn is_data(&self, fs: &Xv6FS) -> bool {
+        self.dinode.itype == InodeType::File && !fs.journals_data()
+    }
+
+    /// Allocate a zeroed block for the content of this inode.
+    fn alloc_content(&self, fs: &Xv6FS) -> Result<u32, FsError> {
+        if self.dinode.itype == InodeType::File {
+            balloc_data(fs, self.dev)
+        } else {
+            balloc(fs, self.dev)
+        }
+    }
+
+    /// Reserve a content block of this inode in the log before changing it,
+    /// unless it is file data written in place.
+    fn reserve_content(&self, fs: &Xv6FS, buf: &Buf<'_>) -> Result<(), FsError> {
+        if self.is_data(fs) {
+            Ok(())
+        } else {
+            fs.log.reserve(buf)
+        }
+    }
+
+    /// Write a changed content block of this inode:
+    /// in place for file data, through the log otherwise.
+    fn write_content(&self, fs: &Xv6FS, mut buf: Buf<'_>) -> Result<(), FsError> {
+        if self.is_data(fs) {
+            fs.log.note_data(buf.read_blockno());
+            buf.bdwrite()
+        } else {
+            fs.log.write(buf)
         }
-        panic!("inode bmap: out of range.");
     }
 
     /// Read data from inode. 
@@ -741,9 +961,9 @@ impl InodeData {
         mut dst: usize, 
         offset: u32, 
         count: u32
-    ) -> Result<usize, &'static str> { 
+    ) -> Result<usize, FsError> { 
         // Check the reading content is in range.
-        let end = offset.checked_add(count).ok_or("Fail to add count.")?;
+        let end = offset.checked_add(count).ok_or(FsError::InvalidArg)?;
         if end > self.dinode.size {
             info!("[Kernel] read: end: {}, dinode.size: {}", end, self.dinode.size);
             //return Err("inode read: end is more than diskinode's size.")
@@ -753,6 +973,7 @@ impl InodeData {
             info!("[Kernel] read: end: {}, dinode.size: {}, offset: {}", end, self.dinode.size,offset);
             return Ok(0);
         }
+        let fs = self.fs();
         let mut total: usize = 0;
         let mut offset = offset as usize;
         let count=count as usize;
@@ -761,12 +982,29 @@ impl InodeData {
         //return Ok(10);
         let mut block_basic = offset / BSIZE;
         let mut block_offset = offset % BSIZE;
+        let first = block_basic as u32;
+        let end_block = (offset + count).div_ceil(BSIZE) as u32;
+        // blocks up to which the read was preloaded
+        let mut preloaded = first;
         while total < count as usize {
+            if block_basic as u32 >= preloaded {
+                preloaded = self.preload(&fs, block_basic as u32, end_block);
+            }
             let surplus_len = count - total;
             let block_no = self.bmap(block_basic as u32, false)?;
             debug!("read block no is {},offset is {}",block_no,offset);
-            let buf = BLOCK_CACHE_MANAGER.bread(self.dev, block_no);
             let write_len = min(surplus_len, BSIZE - block_offset);
+            if block_no == 0 {
+                // a hole reads as zeros
+                unsafe{ptr::write_bytes(dst as *mut u8, 0, write_len);}
+                total += write_len;
+                offset += write_len;
+                dst += write_len;
+                block_basic = offset / BSIZE;
+                block_offset = offset % BSIZE;
+                continue;
+            }
+            let buf = fs.bcache.bread(self.dev, block_no)?;
             // if copy_from_kernel(
             //     is_user, 
             //     dst, 
@@ -786,9 +1024,60 @@ impl InodeData {
             block_basic = offset / BSIZE;
             block_offset = offset % BSIZE;
         }
+        self.read_ahead(&fs, first, offset.div_ceil(BSIZE) as u32);
         Ok(total)
     }
 
+    /// Read the mapped blocks from `from` towards `to` into the cache,
+    /// a batch of contiguous ones at a time.
+    /// Returns where it stopped, there is nothing to batch for a single block.
+    fn preload(&mut self, fs: &Xv6FS, from: u32, to: u32) -> u32 {
+        let to = to.min(from + (fs.bcache.capacity() / 4).max(1) as u32);
+        if to - from < 2 {
+            return to;
+        }
+        let mut blocknos = Vec::new();
+        for bn in from..to {
+            match self.bmap(bn, false) {
+                Ok(0) => {}
+                Ok(blockno) => blocknos.push(blockno),
+                // the read itself reports it
+                Err(_) => break,
+            }
+        }
+        fs.bcache.preload(self.dev, &blocknos);
+        to
+    }
+
+    /// Note a read of blocks `first..end`, and if it continues the last one
+    /// prefetch the mapped blocks of the window past it.
+    fn read_ahead(&mut self, fs: &Xv6FS, first: u32, end: u32) {
+        let window = fs.bcache.read_ahead_window() as u32;
+        // a read may start in the block the last one ended in
+        let sequential = first == self.ra_next || first + 1 == self.ra_next;
+        self.ra_next = end;
+        if !sequential || window == 0 {
+            self.ra_end = end;
+            return;
+        }
+        let size = self.dinode.size.div_ceil(BSIZE as u32);
+        let from = self.ra_end.max(end);
+        let to = end.saturating_add(window).min(size);
+        if from >= to {
+            return;
+        }
+        let mut blocknos = Vec::new();
+        for bn in from..to {
+            match self.bmap(bn, false) {
+                Ok(0) => {}
+                Ok(blockno) => blocknos.push(blockno),
+                Err(_) => break,
+            }
+        }
+        self.ra_end = to;
+        fs.bcache.prefetch(self.dev, &blocknos);
+    }
+
 
     /// Write data to inode. 
     /// Caller must hold inode's sleeplock. 
@@ -802,31 +1091,30 @@ impl InodeData {
         mut src: usize, 
         offset: u32, 
         count: u32
-    ) -> Result<usize, &'static str> {
-        // let end = offset.checked_add(count).ok_or("Fail to add count.")?;
+    ) -> Result<usize, FsError> {
+        offset.checked_add(count).ok_or(FsError::FileTooLarge)?;
+        self.fs().check_writable()?;
         // if end > self.dinode.size {
         //     info!("[Kernel] write: end: {}, dinode.size: {}", end, self.dinode.size);
         //     return Err("inode write: end is more than diskinode's size.")
         // }
         info!("[Xv6fs] inode write file/dir: begin inode write");
+        let fs = self.fs();
         let mut offset = offset as usize;
         info!("[Xv6fs] inode write file/dir: write block offset is {}",offset);
         let count = count as usize;
         let mut total = 0;
         let mut block_basic = offset / BSIZE;
         let mut block_offset = offset % BSIZE;
-        let mut balloc_flag=false;
+        // file data written in place, to go to the device together
+        let mut in_place = Vec::new();
         while total < count {
             let surplus_len = count - total;
             let write_len = min(surplus_len, BSIZE - block_offset);
-            if self.dinode.size < (offset+write_len) as u32{
-                balloc_flag=true;
-            }else{
-                balloc_flag=false;
-            }
-            let block_no = self.bmap(block_basic as u32,balloc_flag)?;
+            let block_no = self.bmap(block_basic as u32, true)?;
             info!("[Xv6fs] inode write file/dir: write block no is {}",block_no);
-            let mut buf = BLOCK_CACHE_MANAGER.bread(self.dev, block_no);
+            let mut buf = fs.bcache.bread(self.dev, block_no)?;
+            self.reserve_content(&fs, &buf)?;
             let dst=unsafe{ (buf.raw_data_mut() as *mut u8).offset((offset % BSIZE) as isize ) };
             unsafe{ptr::copy(src as *const u8, dst, write_len);}
             offset += write_len;
@@ -836,288 +1124,287 @@ impl InodeData {
             block_basic = offset / BSIZE;
             block_offset = offset % BSIZE;
 
-            LOG_MANAGER.write(buf);
+            if self.is_data(&fs) {
+                buf.mark_dirty();
+                fs.log.note_data(block_no);
+                in_place.push(block_no);
+            } else {
+                fs.log.write(buf)?;
+            }
+        }
+        if !fs.bcache.is_write_back() {
+            fs.bcache.flush_blocks(self.dev, &in_place)?;
         }
 
         if self.dinode.size < offset as u32 {
             self.dinode.size = offset as u32;
         }
 
-        self.update();
+        self.update()?;
         
         // info!("[Kernel] Write end");
         Ok(total)
     }
 
+    /// Read the directory entry at offset.
+    /// Fails with `Corrupted` if it names an inode past the inode table.
+    pub(crate) fn read_dirent(&mut self, offset: u32) -> Result<DirEntry, FsError> {
+        let mut raw = [0u8; DirEntry::SIZE];
+        self.read(raw.as_mut_ptr() as usize, offset, DirEntry::SIZE as u32)?;
+        let dir_entry = DirEntry::decode(&raw);
+        if dir_entry.inum as u32 >= self.fs().sb.ninodes() {
+            return Err(self.fs().corrupted());
+        }
+        Ok(dir_entry)
+    }
+
+    /// Write the directory entry at offset.
+    pub(crate) fn write_dirent(&mut self, offset: u32, dir_entry: &DirEntry) -> Result<(), FsError> {
+        let mut raw = [0u8; DirEntry::SIZE];
+        dir_entry.encode(&mut raw);
+        self.write(raw.as_ptr() as usize, offset, DirEntry::SIZE as u32)?;
+        Ok(())
+    }
+
     /// Look for an inode entry in this directory according the name. 
-    /// Panics if this is not a directory. 
-    pub fn dir_lookup(&mut self, name: &[u8]) -> Option<Inode> {
+    /// Fails with `NotDir` if this is not a directory. 
+    pub fn dir_lookup(&mut self, name: &[u8]) -> Result<Inode, FsError> {
         // assert!(name.len() == DIRSIZ);
         info!("[Xv6fs] dir lookup: name is {:?}",core::str::from_utf8(name));
         if self.dinode.itype != InodeType::Directory {
-            panic!("inode type is not directory");
+            return Err(FsError::NotDir);
         }
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..self.dinode.size).step_by(de_size) {
-            self.read(
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
+        for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
+            let dir_entry = self.read_dirent(offset)?;
             if dir_entry.inum == 0 {
                 continue;
             }
-            info!("dir_entry_name: {}, name: {}, inum: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap(),dir_entry.inum);
-            for i in 0..DIRSIZ {
-                if dir_entry.name[i] != name[i] {
-                    break;
-                }
-                if dir_entry.name[i] == 0 {
-                    info!("find you!");
-                    return Some(ICACHE.get(self.dev, dir_entry.inum as u32))
-                }
+            info!("dir_entry_name: {:?}, name: {:?}, inum: {}", str::from_utf8(&dir_entry.name), str::from_utf8(name),dir_entry.inum);
+            if name_eq(&dir_entry.name, name) {
+                info!("find you!");
+                return self.fs().icache.get(self.dev, dir_entry.inum as u32)
             }
         }
-        None
+        Err(FsError::NotFound)
     }
 
     /// Write s new directory entry (name, inum) into the directory
-    pub fn dir_link(&mut self, name: &[u8], inum: u32) -> Result<(), &'static str>{
-        info!("[Xv6fs] dir link: path is {:?}",String::from_utf8(name.to_vec()).unwrap());
-        if self.dir_lookup(name).is_some() {
-            return Err("It's incorrect to find entry in disk")
+    pub fn dir_link(&mut self, name: &[u8], inum: u32) -> Result<(), FsError>{
+        info!("[Xv6fs] dir link: path is {:?}",str::from_utf8(name));
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        if len >= DIRSIZ {
+            return Err(FsError::NameTooLong);
+        }
+        match self.dir_lookup(name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
         }
-        let mut dir_entry = DirEntry::new();
         // look for an empty dir_entry
         let mut entry_offset = 0;
-        for offset in (0..self.dinode.size).step_by(size_of::<DirEntry>()) {
+        for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
             //info!("dir link begin read dir entry");
-            self.read(
-                (&mut dir_entry) as *mut DirEntry as usize, 
-                offset, 
-                size_of::<DirEntry>() as u32
-            )?;
+            let dir_entry = self.read_dirent(offset)?;
             //info!("read entry is {:?}",dir_entry);
             if dir_entry.inum == 0 {
                 break;
             }
-            entry_offset += size_of::<DirEntry>() as u32;
-        }
-        unsafe {
-            ptr::copy(name.as_ptr(), dir_entry.name.as_mut_ptr(), name.len());
+            entry_offset += DirEntry::SIZE as u32;
         }
+        let mut dir_entry = DirEntry::new();
+        dir_entry.name[..len].copy_from_slice(&name[..len]);
         dir_entry.inum = inum as u16;
-        self.write(
-            (&dir_entry) as *const _ as usize, 
-            entry_offset, 
-            size_of::<DirEntry>() as u32
-        )?;
+        self.write_dirent(entry_offset, &dir_entry)?;
         
         Ok(())
     }
 
     /// Is the directory empty execpt for "." and ".." ?
-    pub fn is_dir_empty(&mut self) -> bool {
-        let mut dir_entry = DirEntry::new();
+    pub fn is_dir_empty(&mut self) -> Result<bool, FsError> {
         // "." and ".." size
-        let init_size = 2 * size_of::<DirEntry>() as u32;
+        let init_size = 2 * DirEntry::SIZE as u32;
         let final_size = self.dinode.size;
-        for offset in (init_size..final_size).step_by(size_of::<DirEntry>()) {
+        for offset in (init_size..final_size).step_by(DirEntry::SIZE) {
             // Check each direntry, foreach step by size of DirEntry. 
-            if self.read(
-                &mut dir_entry as *mut DirEntry as usize, 
-                offset, 
-                size_of::<DirEntry>() as u32
-            ).is_err() {
-                panic!("is_dir_empty(): Fail to read dir content");
-            }
+            let dir_entry = self.read_dirent(offset)?;
 
             if dir_entry.inum != 0 {
-                return true
+                return Ok(false)
             }
         }
-        false
+        Ok(true)
     }
 
-    pub fn rename(path:&str,new_name:&str){
-        let mut flag=false;
+    pub fn rename(fs:&Xv6FS,path:&str,new_name:&str)->Result<(),FsError>{
         let mut old_name = [0u8; DIRSIZ];
-        let parent=match ICACHE.namei_parent(&path.as_bytes(), &mut old_name) {
-            Some(cur)=>cur,
-            None=>panic!("[Xv6fs] vfile_unlink: not find path")
-        };
-        let mut parent_guard=parent.lock();
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..parent_guard.dinode.size).step_by(de_size) {
-            parent_guard.read(
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
+        let parent=fs.icache.namei_parent(&path.as_bytes(), &mut old_name)?;
+        let name=new_name.as_bytes();
+        let len=name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        if len >= DIRSIZ {
+            return Err(FsError::NameTooLong);
+        }
+        let mut parent_guard=parent.lock()?;
+        for offset in (0..parent_guard.dinode.size).step_by(DirEntry::SIZE) {
+            let mut dir_entry = parent_guard.read_dirent(offset)?;
             if dir_entry.inum == 0 {
                 continue;
             }
             //info!("dir_entry_name: {}, name: {}, inum: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap(),dir_entry.inum);
-            for i in 0..DIRSIZ {
-                if dir_entry.name[i] != old_name[i] {
-                    break;
-                }
-                if dir_entry.name[i] == 0 {
-                    let name=&new_name.as_bytes();
-                    for i in 0..DIRSIZ {
-                        dir_entry.name[i]=name[i];
-                        if name[i]==0{
-                            break;
-                        }
-                    }
-                    parent_guard.write(dir_entry_ptr as usize, offset, de_size as u32);
-                    LOG_MANAGER.end_op();
-                    return;
-                }
+            if name_eq(&dir_entry.name, &old_name) {
+                dir_entry.name=[0;DIRSIZ];
+                dir_entry.name[..len].copy_from_slice(&name[..len]);
+                return parent_guard.write_dirent(offset, &dir_entry);
             }
         }
-        panic!("[Xv6fs] inode rename: not find dir entry");
+        Err(FsError::NotFound)
     }
 
-    pub fn ls(&mut self)->Option<Vec<String>>{
+    pub fn ls(&mut self)->Result<Vec<String>,FsError>{
         if self.dinode.itype!=InodeType::Directory{
-            None
+            Err(FsError::NotDir)
         }else{
            let mut v=Vec::new();
-           let de_size = size_of::<DirEntry>();
-           let mut dir_entry = DirEntry::new();
-           let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-           for offset in (0..self.dinode.size).step_by(de_size) {
-               self.read(
-                   dir_entry_ptr as usize, 
-                   offset, 
-                   de_size as u32
-               ).expect("Cannot read entry in this dir");
+           for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
+               let dir_entry = self.read_dirent(offset)?;
                if dir_entry.inum == 0 {
                    continue;
                }
                // info!("dir_entry_name: {}, name: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap());
-               let name=String::from_utf8(dir_entry.name.to_vec()).unwrap();
+               let name=String::from_utf8_lossy(&dir_entry.name).into_owned();
                v.push(name);
            }
-           Some(v)
+           Ok(v)
         }
     }
 
-    pub fn dir_unlink(&mut self, name: &[u8]) -> Result<(),&'static str> {
+    /// The live entries of this directory as (inum, name) pairs,
+    /// with the NUL padding stripped from the names.
+    pub fn dir_entries(&mut self)->Result<Vec<(u32,Vec<u8>)>,FsError>{
+        if self.dinode.itype!=InodeType::Directory{
+            return Err(FsError::NotDir);
+        }
+        let mut v=Vec::new();
+        for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
+            let dir_entry = self.read_dirent(offset)?;
+            if dir_entry.inum == 0 {
+                continue;
+            }
+            let len=dir_entry.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+            v.push((dir_entry.inum as u32, dir_entry.name[..len].to_vec()));
+        }
+        Ok(v)
+    }
+
+    pub fn dir_unlink(&mut self, name: &[u8]) -> Result<(),FsError> {
         // assert!(name.len() == DIRSIZ);
-        info!("[Xv6fs] dir unlink: path is {}",String::from_utf8(name.to_vec()).unwrap());
+        info!("[Xv6fs] dir unlink: path is {:?}",str::from_utf8(name));
         if self.dinode.itype != InodeType::Directory {
-            panic!("inode type is not directory");
+            return Err(FsError::NotDir);
         }
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..self.dinode.size).step_by(de_size) {
-            self.read(
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
+        for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
+            let mut dir_entry = self.read_dirent(offset)?;
             if dir_entry.inum == 0 {
                 continue;
             }
             //info!("dir_entry_name: {}, name: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap());
-            for i in 0..DIRSIZ {
-                if dir_entry.name[i] != name[i] {
-                    break;
-                }
-                if dir_entry.name[i] == 0 {
-                    //info!("find you!!!");
-                    dir_entry.inum=0;
-                    dir_entry.name=[0;DIRSIZ];
-                    self.write(dir_entry_ptr as usize, offset, de_size as u32);
-                    return Ok(());
-                }
+            if name_eq(&dir_entry.name, name) {
+                //info!("find you!!!");
+                dir_entry.inum=0;
+                dir_entry.name=[0;DIRSIZ];
+                self.write_dirent(offset, &dir_entry)?;
+                return Ok(());
             }
         }
-        Err("not find this file in the directory")
+        Err(FsError::NotFound)
     }
 
-    pub fn clear_dir(&mut self) -> Result<(),&'static str> {
+    pub fn clear_dir(&mut self) -> Result<(),FsError> {
         // assert!(name.len() == DIRSIZ);
         if self.dinode.itype != InodeType::Directory {
-            panic!("inode type is not directory");
+            return Err(FsError::NotDir);
         }
-        let de_size = size_of::<DirEntry>();
-        let mut dir_entry = DirEntry::new();
-        let dir_entry_ptr = &mut dir_entry as *mut _ as *mut u8;
-        for offset in (0..self.dinode.size).step_by(de_size) {
-            self.read(
-                dir_entry_ptr as usize, 
-                offset, 
-                de_size as u32
-            ).expect("Cannot read entry in this dir");
-            if dir_entry.inum == 0 || offset/(de_size as u32) < 2{
+        for offset in (0..self.dinode.size).step_by(DirEntry::SIZE) {
+            let dir_entry = self.read_dirent(offset)?;
+            if dir_entry.inum == 0 || offset/(DirEntry::SIZE as u32) < 2{
                 continue;
             }
             // info!("dir_entry_name: {}, name: {}", String::from_utf8(dir_entry.name.to_vec()).unwrap(), String::from_utf8(name.to_vec()).unwrap());
-            let mut child_inode=ICACHE.get(self.dev, dir_entry.inum as u32);
-            let mut cdata=child_inode.lock();
+            let child_inode=self.fs().icache.get(self.dev, dir_entry.inum as u32)?;
+            let mut cdata=child_inode.lock()?;
             match cdata.dinode.itype {
                 InodeType::File=>{
                     cdata.dinode.itype=InodeType::Empty;
-                    cdata.truncate(&child_inode);
+                    cdata.truncate(&child_inode)?;
                     cdata.valid=false;
                     drop(cdata);
-                    self.dir_unlink(&dir_entry.name);
+                    self.dir_unlink(&dir_entry.name)?;
                 },
                 InodeType::Directory=>{
-                    cdata.clear_dir();
+                    cdata.clear_dir()?;
                     cdata.dinode.itype=InodeType::Empty;
-                    cdata.truncate(&child_inode);
+                    cdata.truncate(&child_inode)?;
                     cdata.valid=false;
                     drop(cdata);
-                    self.dir_unlink(&dir_entry.name);
+                    self.dir_unlink(&dir_entry.name)?;
                 },
 
                 _=>{
-                    panic!("this is not shoud be in the directory!");
+                    return Err(FsError::Corrupted);
                 }
             }
         }
-        self.update();
-        Ok(())
+        self.update()
     }
 }
 
 /// Inode handed out by inode cache. 
-/// It is actually a handle pointing to the cache. 
-#[derive(Debug)]
+/// It is actually a handle pointing to the cache
+/// of its owning file system. 
 pub struct Inode {
+    pub(crate) fs: Arc<Xv6FS>,
     pub dev: u32,
     pub inum: u32,
     pub index: usize
 }
 
+impl fmt::Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inode")
+            .field("dev", &self.dev)
+            .field("inum", &self.inum)
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
 impl Clone for Inode {
     fn clone(&self) -> Self {
-        ICACHE.dup(self)
+        self.fs.icache.dup(self)
     }
 }
 
 impl Inode {
+    /// The file system this inode belongs to.
+    pub fn fs(&self) -> &Arc<Xv6FS> {
+        &self.fs
+    }
+
     /// Lock the inode. 
     /// Load it from the disk if its content not cached yet. 
-    pub fn lock<'a>(&'a self) -> SleepLockGuard<'a, InodeData> {
+    /// Fails with `Io` if the inode cannot be loaded,
+    /// with `NotFound` if it is not allocated,
+    /// or with `Corrupted` if it cannot be used safely.
+    pub fn lock<'a>(&'a self) -> Result<SleepLockGuard<'a, InodeData>, FsError> {
         assert!(self.index < NINODE, "index must less than NINODE");
         //info!("[Kernel] inode.lock(): inode index: {}, dev: {}, inum: {}", self.index, self.dev, self.inum);
-        let mut guard = ICACHE.data[self.index].lock();
+        let fs = &self.fs;
+        let mut guard = fs.icache.data[self.index].lock();
         
         if !guard.valid {
-            let blockno = unsafe{ SUPER_BLOCK.locate_inode(self.inum) };
+            let blockno = fs.sb.locate_inode(self.inum)?;
             //info!("lock blockno is {}",blockno);
-            let buf = BLOCK_CACHE_MANAGER.bread(self.dev, blockno);
-            let offset = locate_inode_offset(self.inum) as isize;
+            let buf = fs.bcache.bread(self.dev, blockno)?;
+            let offset = locate_inode_offset(self.inum) * DiskInode::SIZE;
             //info!("offset is {:?}",offset);
             //let data=buf.raw_data() as *const RawSuperBlock;
             //let data=buf.raw_data() as *const DiskInode;
@@ -1132,21 +1419,28 @@ impl Inode {
             // }
             //info!("data is {:?}",unsafe{core::ptr::read(data)});
             //let dinode = unsafe{ (buf.raw_data() as *const RawSuperBlock).offset(offset) };
-            let dinode = unsafe{ (buf.raw_data() as *const DiskInode).offset(offset) };
-            guard.dinode = unsafe{ core::ptr::read(dinode) };
+            let dinode = DiskInode::decode(&buf.data()[offset..]);
             //info!("{:?}",guard.dinode);
             // info!("dinode is {:?}",unsafe {
             //     core::ptr::read(dinode)
             // });
             drop(buf);
+            let dinode = match dinode {
+                Ok(dinode) if dinode.itype == InodeType::Empty => {
+                    // a stale or unallocated inode number
+                    return Err(FsError::NotFound);
+                }
+                Ok(dinode) if is_sound(fs, &dinode) => dinode,
+                _ => return Err(fs.corrupted()),
+            };
+            guard.dinode = dinode;
+            guard.ra_next = 0;
+            guard.ra_end = 0;
             guard.valid = true;
             guard.dev = self.dev;
             guard.inum = self.inum;
-            if guard.dinode.itype == InodeType::Empty {
-                panic!("inode lock: trying to lock an inode whose type is empty.")
-            }
         }
-        guard
+        Ok(guard)
     }
 }
 
@@ -1155,7 +1449,8 @@ impl Drop for Inode {
     /// If this is the last reference in the inode cache, then is might be recycled. 
     /// Further, if this inode has no links anymore, free this inode in the disk. 
     fn drop(&mut self) {
-        ICACHE.put(self)
+        let fs = Arc::clone(&self.fs);
+        fs.icache.put(self)
     }
 }
 
@@ -1166,3 +1461,13 @@ impl Drop for Inode {
 fn locate_inode_offset(inum: u32) -> usize {
     inum as usize % IPB
 }
+
+/// Whether a loaded inode can be used: its size within the largest file,
+/// a whole number of entries for a directory, and every block number
+/// in the data area.
+fn is_sound(fs: &Xv6FS, dinode: &DiskInode) -> bool {
+    dinode.size as usize <= MAXFILE * BSIZE
+        && dinode.nlink >= 0
+        && (dinode.itype != InodeType::Directory || dinode.size as usize % DirEntry::SIZE == 0)
+        && dinode.addrs.iter().all(|&addr| addr == 0 || fs.sb.is_data_block(addr))
+}
@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use lazy_init::LazyInit;
 use lazy_static::*;
@@ -12,6 +13,12 @@ pub trait FsInterface:Send + Sync + Any {
     fn wake_up_next_proc(&self,index:usize);
     fn new_sleep_lock(&self)->usize;
     fn get_flag(&self,index:usize)->bool;
+    /// Call `task` every `period_ms` milliseconds in the background
+    /// until it returns false, for the flusher of a write-back cache.
+    /// Returns false if the platform cannot run background tasks.
+    fn run_periodic(&self, _period_ms: u64, _task: Box<dyn Fn() -> bool + Send + Sync>) -> bool {
+        false
+    }
 }
 
 pub struct InterfaceManager{
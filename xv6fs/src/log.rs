@@ -7,59 +7,198 @@ use axlog::{info, warn};
 use std::{println as info, println as warn}; // Workaround to use prinltn! for logs.
 
 //use core::{ops::{Deref, DerefMut}, panic, ptr};
-use core::{ panic, ptr};
-use core::mem;
+use core::{ mem, panic, ptr};
+use core::sync::atomic::{AtomicBool, Ordering};
 //use alloc::sync::Arc;
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
-use lazy_static::*;
 
 //use crate::{fs_const::{MAXOPBLOCKS, LOGSIZE, BSIZE}, block_dev::BlockDevice};
-use crate::fs_const::{LOGSIZE, BSIZE,MAXOPBLOCKS};
-use crate::buffer_cache::{BLOCK_CACHE_MANAGER, Buf, BufData};
-use crate::interface::INTERFACE_MANAGER;
+use crate::fs_const::{BSIZE,MAXOPBLOCKS};
+use crate::buffer_cache::{BlockCacheManager, Buf};
+use crate::sync::wait_queue::WaitQueue;
 //use crate::block_dev::BlockDevice;
-use crate::superblock::SUPER_BLOCK;
-
-lazy_static!{
-    pub static ref LOG_MANAGER: LogManager = LogManager::init();
-}
+use crate::superblock::SuperBlock;
+use crate::error::FsError;
+use crate::misc::{crc32c, get_u32, put_u32};
 
+/// Log of one mounted file system, owned by its `Xv6FS` handle.
 pub struct LogManager{
     pub log: Mutex<Log>,
+    /// set once the file system stops accepting changes
+    read_only: AtomicBool,
+    /// blocks freed by the running transaction
+    freed: Mutex<BTreeSet<u32>>,
+    /// the device is told about freed blocks once their transaction commits
+    discard: AtomicBool,
+    /// ops waiting for the log to admit them
+    waiters: WaitQueue,
 }
 
 impl LogManager {
-    pub fn init()->Self{
-        LogManager { log: Mutex::new(Log::uninit()) }
+    pub fn new()->Self{
+        LogManager {
+            log: Mutex::new(Log::uninit()),
+            read_only: AtomicBool::new(false),
+            freed: Mutex::new(BTreeSet::new()),
+            discard: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Refuse every later write. The transaction already logged still commits.
+    pub fn set_read_only(&self) {
+        self.read_only.store(true, Ordering::Release);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    /// Write the file data written in place back before each commit,
+    /// so that a transaction never points at data still in memory.
+    pub fn set_ordered(&self) {
+        self.log.lock().ordered = true;
+    }
+
+    /// Note a block of file data the running transaction writes in place,
+    /// which an ordered log writes back before the commit.
+    pub fn note_data(&self, blockno: u32) {
+        let mut guard = self.log.lock();
+        if guard.ordered && !guard.written.contains(&blockno) {
+            guard.written.push(blockno);
+        }
+    }
+
+    /// Discard the blocks a transaction frees once it commits.
+    pub fn set_discard(&self) {
+        self.discard.store(true, Ordering::Release);
+    }
+
+    /// Make every change so far durable: commit what the log holds,
+    /// write the dirty bufs back, write the last committed transaction home,
+    /// empty the header and flush the device.
+    /// Waits until no op runs and keeps new ones from beginning meanwhile,
+    /// since the cache holds their changes before they commit.
+    /// Must not be called inside an op.
+    pub fn sync(&self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut guard = self.log.lock();
+        while guard.committing || guard.outstanding > 0 {
+            self.waiters.wait(guard);
+            guard = self.log.lock();
+        }
+        guard.committing = true;
+        let lh = mem::take(&mut guard.lh);
+        let written = mem::take(&mut guard.written);
+        let unflushed = mem::take(&mut guard.unflushed);
+        let mut log = Log { lh, written, unflushed, ..guard.clone() };
+        drop(guard);
+        // a failed commit waiting to be retried goes first
+        let res = unsafe { log.commit(bcache) }
+            .and_then(|()| bcache.sync())
+            .and_then(|()| if log.unflushed.is_empty() {
+                Ok(())
+            } else {
+                log.checkpoint(bcache).and_then(|()| log.empty_head(bcache))
+            })
+            .and_then(|()| bcache.flush());
+        let mut guard = self.log.lock();
+        guard.lh = log.lh;
+        guard.written = log.written;
+        guard.installing = log.installing;
+        guard.seq = log.seq;
+        guard.unflushed = log.unflushed;
+        guard.committing = false;
+        drop(guard);
+        self.waiters.wake_all();
+        res
+    }
+
+    /// Read the log info from the super block and recover the fs if necessary.
+    /// A read-only log leaves a committed transaction where it is.
+    pub fn init(&self, bcache: &BlockCacheManager, sb: &SuperBlock, dev: u32) -> Result<(), FsError> {
+        let replay = !self.is_read_only();
+        let mut guard = self.log.lock();
+        let res = unsafe { guard.init(bcache, sb, dev, replay) };
+        drop(guard);
+        res
     }
 }
 
 /// Log info about the file system.
+#[derive(Clone)]
 pub struct Log {
-    ///wait queue index
-    channel: usize,
     /// the starting block in the fs
     start: u32,
     /// the number of blocks available for log
     size: u32,
+    /// the number of header blocks at the start of the log
+    nheader: u32,
+    /// the number of bufs in the buffer cache
+    nbuf: u32,
+    /// sequence number of the last transaction committed
+    seq: u32,
     dev: u32,
+    /// how many fs ops are executing
     outstanding: u32,
     /// not allow any fs op when the log is committing
     committing: bool,
+    /// dirty file data goes to the device before each commit
+    ordered: bool,
+    /// file data written in place since the last commit, for an ordered log
+    written: Vec<u32>,
+    /// blocks of the transaction in the header that a write-back cache
+    /// may not have written home yet, as they were committed:
+    /// the next transaction may change the cached bufs before its commit
+    unflushed: Vec<(u32, [u8; BSIZE])>,
+    /// the header on disk holds a committed transaction
+    /// that is not fully installed yet
+    installing: bool,
     lh: LogHeader,
 }
 
 impl Log {
     const fn uninit() -> Self {
         Self {
-            channel: 0,
             start: 0,
             size: 0,
+            nheader: 0,
+            nbuf: 0,
+            seq: 0,
             dev: 0,
             outstanding: 0,
             committing: false,
-            lh: LogHeader { len: 0, blocknos: [0; LOGSIZE-1] },
+            ordered: false,
+            written: Vec::new(),
+            unflushed: Vec::new(),
+            installing: false,
+            lh: LogHeader { len: 0, seq: 0, checksum: 0, blocknos: Vec::new() },
+        }
+    }
+
+    /// How many blocks one transaction may log.
+    /// Every logged block stays pinned in the buffer cache until the commit,
+    /// which needs two more bufs to copy a block.
+    fn capacity(&self) -> usize {
+        (self.nlogged() as usize).min(self.nbuf as usize - 2)
+    }
+
+    /// Number of log blocks between the header and the commit record.
+    fn nlogged(&self) -> u32 {
+        self.size - self.nheader - 1
+    }
+
+    /// Whether a new op may begin.
+    /// A reserving op needs room for `MAXOPBLOCKS` more blocks on top of
+    /// the ones already reserved, unless it is the only one.
+    fn admits(&self, reserve: bool) -> bool {
+        if self.committing {
+            return false;
         }
+        !reserve || self.outstanding == 0
+            || self.lh.len as usize + (self.outstanding as usize + 1) * MAXOPBLOCKS <= self.capacity()
     }
 
     /// Init the log when booting.
@@ -67,200 +206,534 @@ impl Log {
     /// SAFETY: It must be called without holding any locks,
     ///         because it will call disk rw, which might sleep.
     /// 这里的dev要再考虑一下
-    pub unsafe fn init(&mut self, dev: u32) {
-        debug_assert!(mem::size_of::<LogHeader>() < BSIZE);
-        debug_assert_eq!(mem::align_of::<BufData>() % mem::align_of::<LogHeader>(), 0);
-        let (start, size) = SUPER_BLOCK.read_log();
-        self.channel=INTERFACE_MANAGER.interface.new_sleep_lock();
+    pub unsafe fn init(&mut self, bcache: &BlockCacheManager, sb: &SuperBlock, dev: u32, replay: bool) -> Result<(), FsError> {
+        let (start, size) = sb.read_log();
         self.start = start;
         self.size = size;
+        self.nheader = LogHeader::blocks(size);
+        self.nbuf = bcache.capacity() as u32;
         self.dev = dev;
-        self.recover();
+        self.recover(bcache, sb, replay)
     }
 
     /// Recover the file system from log if necessary.
-    fn recover(&mut self) {
+    /// Only a transaction with an intact header, a commit record of the same
+    /// sequence number, and logged blocks matching its checksum is installed.
+    /// Anything else was torn by a crash before its commit, and is discarded.
+    /// Without `replay`, a committed transaction is only reported,
+    /// and the blocks it changes read as they were before it.
+    /// Fails with `Corrupted` if an intact header lists a block
+    /// outside the inode table, bitmap and data area.
+    fn recover(&mut self, bcache: &BlockCacheManager, sb: &SuperBlock, replay: bool) -> Result<(), FsError> {
         //info!("file system: checking logs");
-        self.read_head();
-        if self.lh.len > 0 {
-            //info!("file system: recovering from logs");
-            self.install_trans(true);
-            self.empty_head();
-        } else {
+        let sealed = self.read_head(bcache)?;
+        self.seq = self.lh.seq;
+        let len = self.lh.len as usize;
+        if len == 0 {
             //info!("file system: no need to recover");
+            return Ok(());
         }
+        if sealed && self.lh.blocknos[..len].iter().any(|&b| b < sb.inodestart() || b >= sb.size()) {
+            self.lh.len = 0;
+            return Err(FsError::Corrupted);
+        }
+        if !sealed || !self.is_committed(bcache)? {
+            warn!("[Xv6fs] log: incomplete transaction {} discarded", self.lh.seq);
+            if replay {
+                self.empty_head(bcache)?;
+            }
+        } else if !replay {
+            warn!("[Xv6fs] log: {} committed blocks not replayed", self.lh.len);
+        } else {
+            //info!("file system: recovering from logs");
+            self.install_trans(bcache, true)?;
+            // no op runs before the mount is done, so every dirty buf
+            // belongs to the transaction just installed
+            bcache.sync()?;
+            bcache.flush()?;
+            self.empty_head(bcache)?;
+        }
+        self.lh.len = 0;
+        Ok(())
+    }
+
+    /// Whether the transaction in the header has its commit record,
+    /// and its logged blocks match the checksum.
+    fn is_committed(&self, bcache: &BlockCacheManager) -> Result<bool, FsError> {
+        let buf = bcache.bread(self.dev, self.start + self.nheader + self.lh.len)?;
+        let record = CommitRecord::decode(buf.data());
+        drop(buf);
+        if record != Some(CommitRecord { seq: self.lh.seq, checksum: self.lh.checksum }) {
+            return Ok(false);
+        }
+        let logged: Vec<u32> = (0..self.lh.len).map(|i| self.start + self.nheader + i).collect();
+        bcache.preload(self.dev, &logged);
+        let mut checksum = 0;
+        for blockno in logged {
+            let log_buf = bcache.bread(self.dev, blockno)?;
+            checksum = crc32c(checksum, log_buf.data());
+        }
+        Ok(checksum == self.lh.checksum)
     }
 
     /// Read the log header from disk into the in-memory log header.
-    fn read_head(&mut self) {
-        let buf = BLOCK_CACHE_MANAGER.bread(self.dev, self.start);
-        unsafe {
-            ptr::copy_nonoverlapping(
-                buf.raw_data() as *const LogHeader,
-                &mut self.lh,
-                1
-            );
+    /// Returns whether it is intact.
+    fn read_head(&mut self, bcache: &BlockCacheManager) -> Result<bool, FsError> {
+        let mut raw = vec![0u8; self.nheader as usize * BSIZE];
+        for (i, chunk) in raw.chunks_mut(BSIZE).enumerate() {
+            let buf = bcache.bread(self.dev, self.start + i as u32)?;
+            chunk.copy_from_slice(buf.data());
         }
-        drop(buf);
+        let capacity = self.nlogged() as usize;
+        self.lh = LogHeader::decode(&raw, capacity);
+        Ok(LogHeader::is_sealed(&raw, capacity))
     }
 
     /// Write in-memory log header to disk.
+    /// Only the header blocks holding its block numbers are written.
+    fn write_head(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut raw = vec![0u8; self.nheader as usize * BSIZE];
+        self.lh.encode(&mut raw);
+        let used = LogHeader::used_blocks(self.lh.len);
+        for i in 0..used {
+            let mut buf = bcache.bread(self.dev, self.start + i)?;
+            buf.data_mut().copy_from_slice(&raw[i as usize * BSIZE..(i as usize + 1) * BSIZE]);
+            buf.bwrite()?;
+        }
+        Ok(())
+    }
+
+    /// Write the commit record after the logged blocks.
     /// This is the true point at which the current transaction commits.
-    fn write_head(&mut self) {
-        let mut buf = BLOCK_CACHE_MANAGER.bread(self.dev, self.start);
-        unsafe {
-            ptr::copy_nonoverlapping(
-                &self.lh,
-                buf.raw_data_mut() as *mut LogHeader,
-                1,
-            );
-        }
-        buf.bwrite();
-        drop(buf);
+    fn write_commit(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut buf = bcache.bread(self.dev, self.start + self.nheader + self.lh.len)?;
+        buf.data_mut().fill(0);
+        CommitRecord { seq: self.lh.seq, checksum: self.lh.checksum }.encode(buf.data_mut());
+        buf.bwrite()
     }
 
-    /// Empty log header in disk by 
-    /// setting the len of log(both in-memory and in-disk) to zero.
-    fn empty_head(&mut self) {
-        self.lh.len = 0;
-        let mut buf = BLOCK_CACHE_MANAGER.bread(self.dev, self.start);
-        let raw_lh = buf.raw_data_mut() as *mut LogHeader;
-        unsafe { raw_lh.as_mut().unwrap().len = 0; }
-        buf.bwrite();
-        drop(buf);
+    /// Empty log header in disk by setting its len to zero.
+    /// The in-memory header is left to the caller,
+    /// which still needs it to unpin the installed blocks.
+    fn empty_head(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut buf = bcache.bread(self.dev, self.start)?;
+        let empty = LogHeader { len: 0, seq: self.lh.seq, checksum: 0, blocknos: Vec::new() };
+        empty.encode(buf.data_mut());
+        buf.bwrite()
     }
 
-    /// Copy committed blocks from log to their home location.
-    fn install_trans(&mut self, recovering: bool) {
-        for i in 0..self.lh.len {
-            let log_buf  = BLOCK_CACHE_MANAGER.bread(self.dev, self.start+1+i);
-            let mut disk_buf = BLOCK_CACHE_MANAGER.bread(self.dev, self.lh.blocknos[i as usize]);
-            unsafe {
-                ptr::copy(
-                    log_buf.raw_data(),
-                    disk_buf.raw_data_mut(),
-                    1,
-                );
-            }
-            disk_buf.bwrite();
-            if !recovering {
-                //info!("unpin disk buf {}",self.lh.blocknos[i as usize]);
-                unsafe { disk_buf.unpin(); }
+    /// Write committed blocks to their home location.
+    /// Their pinned bufs already hold them, unless `recovering`,
+    /// when they are copied from the log first.
+    /// A write-back cache only marks them dirty.
+    fn install_trans(&mut self, bcache: &BlockCacheManager, recovering: bool) -> Result<(), FsError> {
+        let blocknos = &self.lh.blocknos[..self.lh.len as usize];
+        for (i, &blockno) in blocknos.iter().enumerate() {
+            let mut disk_buf = bcache.bread(self.dev, blockno)?;
+            if recovering {
+                let log_buf = bcache.bread(self.dev, self.start + self.nheader + i as u32)?;
+                unsafe {
+                    ptr::copy(
+                        log_buf.raw_data(),
+                        disk_buf.raw_data_mut(),
+                        1,
+                    );
+                }
+                drop(log_buf);
             }
-            drop(log_buf);
+            disk_buf.mark_dirty();
             drop(disk_buf);
         }
+        if !bcache.is_write_back() {
+            bcache.flush_blocks(self.dev, blocknos)?;
+        }
+        Ok(())
+    }
+
+    /// Release the bufs pinned by `LogManager::write` once they are installed.
+    fn unpin_trans(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        for i in 0..self.lh.len {
+            // pinned, so it is still cached and never touches the device
+            let disk_buf = bcache.bread(self.dev, self.lh.blocknos[i as usize])?;
+            //info!("unpin disk buf {}",self.lh.blocknos[i as usize]);
+            unsafe { disk_buf.unpin(); }
+            drop(disk_buf);
+        }
+        Ok(())
     }
 
     /// Commit the log.
+    /// If a write fails, the transaction is kept in memory with its bufs
+    /// pinned, and the next commit retries it. A failure after the header
+    /// has reached the disk only retries the install, since the log blocks
+    /// on disk must not change until the header is emptied; recovery at the
+    /// next mount replays them otherwise.
     /// SAFETY: It must be called while the committing field is set.
-    pub unsafe fn commit(&mut self) {
-        self.committing=true;
+    pub unsafe fn commit(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
         if !self.committing {
             panic!("log: committing while the committing flag is not set");
         }
-        // debug_assert!(self.lh.len > 0);     // it should have some log to commit
         if self.lh.len > 0 {
-            self.write_log();
-            self.write_head();
-            self.install_trans(false);
-            self.empty_head();
+            self.try_commit(bcache)
+        } else {
+            Ok(())
         }
-        self.committing=false;
     }
 
-    /// Copy the log content from buffer cache to disk.
-    fn write_log(&mut self) {
-        for i in 0..self.lh.len {
-            let mut log_buf  = BLOCK_CACHE_MANAGER.bread(self.dev, self.start+1+i);
-            let cache_buf = BLOCK_CACHE_MANAGER.bread(self.dev, self.lh.blocknos[i as usize]);
-            unsafe {
-                ptr::copy(
-                    cache_buf.raw_data(),
-                    log_buf.raw_data_mut(),
-                    1,
-                );
+    fn try_commit(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        if !self.installing {
+            // the header about to be overwritten must not be needed any more
+            self.checkpoint(bcache)?;
+            if self.ordered && bcache.is_write_back() {
+                // only the data, the logged metadata must wait for the commit
+                bcache.flush_blocks(self.dev, &self.written)?;
             }
-            log_buf.bwrite();
-            drop(cache_buf);
-            drop(log_buf);
+            self.lh.seq = self.seq.wrapping_add(1);
+            self.write_log(bcache)?;
+            self.write_head(bcache)?;
+            // a commit record must never reach the disk before what it seals,
+            // nor an installed block before the commit record
+            bcache.flush()?;
+            self.write_commit(bcache)?;
+            bcache.flush()?;
+            self.seq = self.lh.seq;
+            self.installing = true;
+        }
+        self.install_trans(bcache, false)?;
+        if bcache.is_write_back() {
+            // the installed blocks may still be dirty in the cache,
+            // the header keeps them until the next checkpoint
+            self.unflushed = self.lh.blocknos[..self.lh.len as usize].iter()
+                .map(|&blockno| Ok((blockno, *bcache.bread(self.dev, blockno)?.data())))
+                .collect::<Result<_, FsError>>()?;
+        } else {
+            // the header may only let go of blocks on the disk
+            bcache.flush()?;
+            self.empty_head(bcache)?;
         }
+        self.installing = false;
+        self.written.clear();
+        self.unpin_trans(bcache)?;
+        self.lh.len = 0;
+        Ok(())
+    }
+
+    /// Write the blocks of the transaction in the header home,
+    /// from the copies taken at its commit.
+    /// They are on the disk once it returns, so the log may be reused.
+    fn checkpoint(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        if self.unflushed.is_empty() {
+            return Ok(());
+        }
+        bcache.write_copies(self.dev, &self.unflushed)?;
+        bcache.flush()?;
+        self.unflushed.clear();
+        Ok(())
+    }
+
+    /// Copy the log content from buffer cache to disk in one write,
+    /// and record its checksum in the header.
+    fn write_log(&mut self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut data = vec![0u8; self.lh.len as usize * BSIZE];
+        for (i, block) in data.chunks_mut(BSIZE).enumerate() {
+            let cache_buf = bcache.bread(self.dev, self.lh.blocknos[i])?;
+            block.copy_from_slice(cache_buf.data());
+        }
+        bcache.write_blocks(self.dev, self.start + self.nheader, &data)?;
+        self.lh.checksum = crc32c(0, &data);
+        Ok(())
     }
 }
 
 impl LogManager {
-    // It should be called at the start of file system call.
-    // pub fn begin_op(&self) {
-    //     let mut guard  = self.log.lock();
-    //     loop {
-    //         if guard.committing ||
-    //             1 + guard.lh.len as usize + (guard.outstanding+1) as usize * MAXOPBLOCKS > LOGSIZE
-    //         {
-    //             // let channel = guard.deref() as *const Log as usize;
-    //             // unsafe { CPU_MANAGER.myproc().unwrap().sleep(channel, guard); }
-    //             // guard = self.acquire();
-    //             let channel=guard.channel;
-    //             drop(guard);
-    //             INTERFACE_MANAGER.interface.sleep_cur_proc(channel);
-    //             guard=self.log.lock();
-    //         } 
-    //         else 
-    //         {
-    //             guard.outstanding += 1;
-    //             drop(guard);
-    //             break;
-    //         }
-    //     }
-    // }
-
-
-
-    /// Accept a buffer, write it into the log and then release the buffer.
-    /// This function will pin this buf in the cache until the log commits.
-    pub fn write(&self, buf: Buf) {
-        let mut guard = self.log.lock();
-        
-        if (guard.lh.len+1) as usize >= LOGSIZE || guard.lh.len+1 >= guard.size {
-            panic!("log: not enough space for ongoing transactions");
-        }
-        // if guard.outstanding < 1 {
-        //     panic!("log: this log write is out of recording");
-        // }
+    /// Start a file system operation.
+    /// Waits while the log is committing, or while the operations
+    /// already started could fill it with `MAXOPBLOCKS` blocks each.
+    pub fn begin_op(&self) {
+        self.wait_for_space(true)
+    }
 
-        // record the buf's blockno in the log header
-        for i in 0..guard.lh.len {
-            if guard.lh.blocknos[i as usize] == buf.read_blockno() {
-                //info!("buf blockno {} is in the lh.blocknos, and now len is {}",guard.lh.blocknos[i as usize],guard.lh.len);
-                drop(guard);
-                drop(buf);
+    /// Start an operation that may nest inside another one,
+    /// as freeing an inode from `Drop` does.
+    /// Inside one it reserves no space, and only waits for a commit in
+    /// progress, which never runs while an outer operation is outstanding.
+    /// With no operation outstanding, as when an inode is dropped outside
+    /// of any, it reserves like `begin_op`.
+    pub fn join_op(&self) {
+        self.wait_for_space(false)
+    }
+
+    fn wait_for_space(&self, reserve: bool) {
+        loop {
+            let mut guard = self.log.lock();
+            let reserve = reserve || guard.outstanding == 0;
+            if guard.admits(reserve) {
+                guard.outstanding += 1;
                 return;
             }
+            self.waiters.wait(guard);
+        }
+    }
+
+    /// Note a block freed by the running transaction.
+    pub fn note_freed(&self, blockno: u32) {
+        self.freed.lock().insert(blockno);
+    }
+
+    /// Note a block freed by the running transaction that it allocates again,
+    /// so that it is not discarded on commit.
+    pub fn note_reused(&self, blockno: u32) {
+        self.freed.lock().remove(&blockno);
+    }
+
+    /// Whether the block was freed by a transaction that has not committed yet,
+    /// so that a crash would hand it back to its old owner.
+    pub fn is_freed(&self, blockno: u32) -> bool {
+        self.freed.lock().contains(&blockno)
+    }
+
+    /// Add the block of a buf to the running transaction before changing it,
+    /// so that a change that cannot be logged never stays in the cache.
+    /// The buf is pinned in the cache until the log commits, and reserving
+    /// it again, or logging it with `write`, then always succeeds.
+    /// A block reserved but left unchanged is logged as it is.
+    /// Fails with `NoSpace` if the transaction outgrows the log,
+    /// with `Io` while a failed commit is still waiting to be installed,
+    /// and with `ReadOnly` once the file system went read-only.
+    pub fn reserve(&self, buf: &Buf) -> Result<(), FsError> {
+        let mut guard = self.log.lock();
+        debug_assert!(guard.outstanding > 0, "log: write outside of an op");
+        if guard.installing {
+            return Err(FsError::Io);
+        }
+        // record the buf's blockno in the log header
+        let blockno = buf.read_blockno();
+        if guard.lh.blocknos[..guard.lh.len as usize].contains(&blockno) {
+            return Ok(());
+        }
+        if self.is_read_only() {
+            return Err(FsError::ReadOnly);
         }
-        if (guard.lh.len+2) as usize >= LOGSIZE || guard.lh.len+2 >= guard.size {
-            panic!("log: not enough space for this transaction");
+        if guard.lh.len as usize >= guard.capacity() {
+            return Err(FsError::NoSpace);
         }
         unsafe { buf.pin(); }
         let len = guard.lh.len as usize;
-        guard.lh.blocknos[len] = buf.read_blockno();
+        guard.lh.blocknos[len] = blockno;
         guard.lh.len += 1;
-        //info!("insert blockno {},Log Header len +1, and now len is {}",buf.read_blockno(),guard.lh.len);
-        drop(guard);
-        drop(buf);
+        //info!("insert blockno {},Log Header len +1, and now len is {}",blockno,guard.lh.len);
+        Ok(())
     }
 
-    // It should be called at the end of file system call.
-    // It will commit the log if this is the last outstanding op.
-    // 这里要不要最后一个end op再commit还需要再斟酌一下的说
-    pub fn end_op(&self) {
+    /// Accept a changed buffer, write it into the log and then release the buffer.
+    /// Callers reserve the buf before changing it, see `reserve`,
+    /// which this does for a buf not reserved yet.
+    pub fn write(&self, buf: Buf) -> Result<(), FsError> {
+        self.reserve(&buf)
+    }
+
+    /// End a file system operation.
+    /// The last outstanding operation commits the log, grouping the
+    /// changes of every operation that ended since the previous commit.
+    pub fn end_op(&self, bcache: &BlockCacheManager) -> Result<(), FsError> {
+        let mut guard = self.log.lock();
+        debug_assert!(guard.outstanding > 0, "log: end_op without begin_op");
+        guard.outstanding -= 1;
+        if guard.outstanding > 0 {
+            // the space this op reserved may let a waiting one begin
+            drop(guard);
+            self.waiters.wake_all();
+            return Ok(());
+        }
+        // nothing else touches the transaction while committing is set,
+        // so take it out and commit it without holding the spin lock
+        guard.committing = true;
+        let lh = mem::take(&mut guard.lh);
+        let written = mem::take(&mut guard.written);
+        let unflushed = mem::take(&mut guard.unflushed);
+        let mut log = Log { lh, written, unflushed, ..guard.clone() };
+        drop(guard);
+        let res = unsafe { log.commit(bcache) };
+        if res.is_ok() {
+            let freed = mem::take(&mut *self.freed.lock());
+            // once committed they may hold file data written in place
+            // before the checkpoint, which must not write them home
+            log.unflushed.retain(|(blockno, _)| !freed.contains(blockno));
+            if self.discard.load(Ordering::Acquire) && !freed.is_empty() {
+                // still committing, so no op may allocate them meanwhile
+                let freed: Vec<u32> = freed.into_iter().collect();
+                if let Err(err) = bcache.discard(log.dev, &freed) {
+                    // the blocks stay mapped, which is harmless
+                    warn!("[Xv6fs] log: discarding {} freed blocks: {}", freed.len(), err);
+                }
+            }
+        }
         let mut guard = self.log.lock();
-        unsafe{guard.commit()};
+        guard.lh = log.lh;
+        guard.written = log.written;
+        guard.installing = log.installing;
+        guard.seq = log.seq;
+        guard.unflushed = log.unflushed;
+        guard.committing = false;
         drop(guard);
+        self.waiters.wake_all();
+        res
     }
 }
 
+/// One file system operation, from `Xv6FS::begin_op` until it ends.
+/// Ending the last outstanding operation commits the log.
+///
+/// ```ignore
+/// let tx = fs.begin_op();
+/// let res = fs.icache.create(b"/a", InodeType::File, 0, 0);
+/// tx.end()?;
+/// ```
+///
+/// Dropping it also ends the operation, ignoring a failed commit.
+/// Inodes freed by the operation must be dropped before it ends.
+#[must_use]
+pub struct Transaction<'a> {
+    log: &'a LogManager,
+    bcache: &'a BlockCacheManager,
+    ended: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(log: &'a LogManager, bcache: &'a BlockCacheManager) -> Self {
+        log.begin_op();
+        Self { log, bcache, ended: false }
+    }
+
+    /// Join the running operation, see `LogManager::join_op`.
+    pub(crate) fn join(log: &'a LogManager, bcache: &'a BlockCacheManager) -> Self {
+        log.join_op();
+        Self { log, bcache, ended: false }
+    }
+
+    /// End the operation, returning the error of the commit it makes.
+    pub fn end(mut self) -> Result<(), FsError> {
+        self.ended = true;
+        self.log.end_op(self.bcache)
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.log.end_op(self.bcache);
+        }
+    }
+}
+
+/// Header of the log, in the first blocks of the log.
+///
+/// Stored little-endian as `u32`s: `len` at offset 0, the sequence number
+/// of the transaction at 4, the checksum of its logged blocks at 8 and
+/// the checksum of the header itself at 12, then the home block number
+/// of each logged block, running on through as many blocks as
+/// `LogHeader::blocks` asks for. The log blocks follow, and the
+/// `CommitRecord` right after the last logged one.
+///
+/// Every checksum is a CRC-32C; the header one covers `len`, the sequence
+/// number, the block checksum and the `len` block numbers.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct LogHeader {
     len: u32,                       // current len of blocknos array
-    blocknos: [u32; LOGSIZE-1],     // LOGSIZE-1: one block left for log info
+    seq: u32,
+    checksum: u32,
+    blocknos: Vec<u32>,             // one slot per log block between header and commit record
+}
+
+impl LogHeader {
+    /// Number of header blocks at the start of a log of `nlog` blocks:
+    /// enough for the fixed fields and the block number of every log block
+    /// but the commit record.
+    pub fn blocks(nlog: u32) -> u32 {
+        let per_block = (BSIZE / 4) as u32;
+        (nlog + 3 + per_block) / (per_block + 1)
+    }
+
+    /// Number of header blocks that hold `len` block numbers.
+    fn used_blocks(len: u32) -> u32 {
+        (16 + 4 * len + BSIZE as u32 - 1) / BSIZE as u32
+    }
+
+    /// Size of the header of a log of `nlog` blocks on disk.
+    pub fn size(nlog: u32) -> usize {
+        16 + 4 * (nlog - Self::blocks(nlog) - 1) as usize
+    }
+
+    /// A header of the transaction `seq` logging the blocks `blocknos`,
+    /// whose logged content has the CRC-32C `checksum`.
+    pub fn new(seq: u32, checksum: u32, blocknos: &[u32]) -> Self {
+        Self { len: blocknos.len() as u32, seq, checksum, blocknos: blocknos.to_vec() }
+    }
+
+    /// Decode a header with room for `capacity` blocks from `raw`,
+    /// which must hold at least `LogHeader::size` bytes.
+    /// A `len` beyond the capacity is kept, with no block numbers.
+    pub fn decode(raw: &[u8], capacity: usize) -> Self {
+        let len = get_u32(raw, 0);
+        let mut blocknos = vec![0; capacity];
+        if len as usize <= capacity {
+            for (i, blockno) in blocknos[..len as usize].iter_mut().enumerate() {
+                *blockno = get_u32(raw, 16 + 4 * i);
+            }
+        }
+        Self { len, seq: get_u32(raw, 4), checksum: get_u32(raw, 8), blocknos }
+    }
+
+    /// Whether the header in `raw` matches its checksum and fits the log.
+    pub fn is_sealed(raw: &[u8], capacity: usize) -> bool {
+        let len = get_u32(raw, 0) as usize;
+        len <= capacity && Self::header_checksum(raw, len) == get_u32(raw, 12)
+    }
+
+    fn header_checksum(raw: &[u8], len: usize) -> u32 {
+        crc32c(crc32c(0, &raw[0..12]), &raw[16..16 + 4 * len])
+    }
+
+    /// Encode the header and its checksum into the first
+    /// `16 + 4 * len` bytes of `raw`.
+    pub fn encode(&self, raw: &mut [u8]) {
+        put_u32(raw, 0, self.len);
+        put_u32(raw, 4, self.seq);
+        put_u32(raw, 8, self.checksum);
+        let len = self.len as usize;
+        for (i, blockno) in self.blocknos[..len].iter().enumerate() {
+            put_u32(raw, 16 + 4 * i, *blockno);
+        }
+        put_u32(raw, 12, Self::header_checksum(raw, len));
+    }
+}
+
+/// Commit record of a transaction, in the log block after its last logged one.
+///
+/// Stored little-endian as `u32`s: `CommitRecord::MAGIC` at offset 0,
+/// then the sequence number and the block checksum of the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitRecord {
+    pub seq: u32,
+    pub checksum: u32,
+}
+
+impl CommitRecord {
+    pub const MAGIC: u32 = 0x436f_6d74;
+
+    /// Decode a record from `raw`, `None` if it does not hold one.
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        if get_u32(raw, 0) != Self::MAGIC {
+            return None;
+        }
+        Some(Self { seq: get_u32(raw, 4), checksum: get_u32(raw, 8) })
+    }
+
+    /// Encode the record into the first 12 bytes of `raw`.
+    pub fn encode(&self, raw: &mut [u8]) {
+        put_u32(raw, 0, Self::MAGIC);
+        put_u32(raw, 4, self.seq);
+        put_u32(raw, 8, self.checksum);
+    }
 }
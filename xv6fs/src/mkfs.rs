@@ -0,0 +1,154 @@
+//! Create a new file system on a block device
+
+#[cfg(not(test))]
+use axlog::info; // Use log crate when building application
+
+#[cfg(test)]
+use std::println as info; // Workaround to use prinltn! for logs.
+
+use crate::block_dev::BlockDevice;
+use crate::disk_inode::{DirEntry, DiskInode, InodeType};
+use crate::error::FsError;
+use crate::fs_const::{BPB, BSIZE, FSMAGIC, FSSIZE, IPB, LOGSIZE, NDINODES, ROOTINUM};
+use crate::superblock::RawSuperBlock;
+use alloc::vec;
+
+/// Blocks zeroed by one device write when formatting
+const ZERO_BATCH: u32 = 128;
+
+/// Layout parameters of a new file system.
+///
+/// ```ignore
+/// let opts = FormatOptions::new(4096).inode_count(512);
+/// xv6fs::format(dev.as_ref(), opts)?;
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct FormatOptions {
+    /// Size of the image in blocks, including boot block and super block
+    pub total_blocks: u32,
+    /// Number of on-disk inodes, inode 0 is never used
+    pub inode_count: u32,
+    /// Number of log blocks, including the header blocks
+    /// (one for every 129 log blocks) and the commit record
+    pub log_blocks: u32,
+}
+
+impl FormatOptions {
+    pub fn new(total_blocks: u32) -> Self {
+        Self {
+            total_blocks,
+            inode_count: NDINODES as u32,
+            log_blocks: LOGSIZE as u32,
+        }
+    }
+
+    pub fn inode_count(mut self, inode_count: u32) -> Self {
+        self.inode_count = inode_count;
+        self
+    }
+
+    pub fn log_blocks(mut self, log_blocks: u32) -> Self {
+        self.log_blocks = log_blocks;
+        self
+    }
+
+    /// Compute the disk layout.
+    /// Fails with `InvalidArg` if the image cannot hold it.
+    pub fn layout(&self) -> Result<RawSuperBlock, FsError> {
+        // a header block, a block to log and the commit record
+        if self.log_blocks < 3 || self.log_blocks >= self.total_blocks {
+            return Err(FsError::InvalidArg);
+        }
+        // inode 0 is unused and inode 1 is the root,
+        // and directory entries hold 16-bit inode numbers
+        if self.inode_count <= ROOTINUM || self.inode_count > u16::MAX as u32 + 1 {
+            return Err(FsError::InvalidArg);
+        }
+        let ninodeblocks = self.inode_count / IPB as u32 + 1;
+        let nbitmap = self.total_blocks / BPB + (self.total_blocks % BPB != 0) as u32;
+        let logstart = 2;
+        let inodestart = logstart + self.log_blocks;
+        let bmapstart = inodestart + ninodeblocks;
+        let nmeta = bmapstart as u64 + nbitmap as u64;
+        // at least one data block for the root directory
+        if nmeta >= self.total_blocks as u64 {
+            return Err(FsError::InvalidArg);
+        }
+        let mut sb = RawSuperBlock::new();
+        sb.magic = FSMAGIC;
+        sb.size = self.total_blocks;
+        sb.nblocks = self.total_blocks - nmeta as u32;
+        sb.ninodes = self.inode_count;
+        sb.nlog = self.log_blocks;
+        sb.logstart = logstart;
+        sb.inodestart = inodestart;
+        sb.bmapstart = bmapstart;
+        Ok(sb)
+    }
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self::new(FSSIZE as u32)
+    }
+}
+
+/// Lay out a new file system on the block device:
+/// super block, empty log, inode table, free bitmap
+/// and a root directory holding `.` and `..`.
+pub fn format(dev: &dyn BlockDevice, opts: FormatOptions) -> Result<(), FsError> {
+    let sb = opts.layout()?;
+    let nmeta = sb.size - sb.nblocks;
+    info!("[Xv6fs] format: {:?}", sb);
+
+    // zero the whole device, which also empties the log and the inode table
+    let zeros = vec![0u8; ZERO_BATCH as usize * BSIZE];
+    let mut b = 0;
+    while b < sb.size {
+        let n = (sb.size - b).min(ZERO_BATCH);
+        dev.write_blocks(b as usize, &zeros[..n as usize * BSIZE])?;
+        b += n;
+    }
+    let mut buf = [0u8; BSIZE];
+
+    sb.encode(&mut buf);
+    dev.write_block(1, &buf)?;
+
+    // the root directory takes the first data block
+    let root_block = nmeta;
+    let mut root = DiskInode::new();
+    root.itype = InodeType::Directory;
+    root.nlink = 1;
+    root.size = 2 * DirEntry::SIZE as u32;
+    root.addrs[0] = root_block;
+    let inode_block = (sb.inodestart + ROOTINUM / IPB as u32) as usize;
+    let offset = (ROOTINUM as usize % IPB) * DiskInode::SIZE;
+    buf.fill(0);
+    root.encode(&mut buf[offset..]);
+    dev.write_block(inode_block, &buf)?;
+
+    buf.fill(0);
+    for (i, name) in [&b"."[..], &b".."[..]].iter().enumerate() {
+        let mut de = DirEntry::new();
+        de.inum = ROOTINUM as u16;
+        de.name[..name.len()].copy_from_slice(name);
+        de.encode(&mut buf[i * DirEntry::SIZE..]);
+    }
+    dev.write_block(root_block as usize, &buf)?;
+
+    // mark the metadata and the root directory block as in use
+    let used = root_block + 1;
+    let mut b = 0;
+    while b < used {
+        buf.fill(0);
+        let mut bi = 0;
+        while bi < BPB && b + bi < used {
+            buf[(bi / 8) as usize] |= 1 << (bi % 8);
+            bi += 1;
+        }
+        dev.write_block((sb.bmapstart + b / BPB) as usize, &buf)?;
+        b += BPB;
+    }
+    dev.flush()?;
+    Ok(())
+}
@@ -5,64 +5,39 @@ use axlog::{info, warn}; // Use log crate when building application
 #[cfg(test)]
 use std::{println as info, println as warn}; // Workaround to use prinltn! for logs.
 
-use core::ptr;
-use core::mem::{self, MaybeUninit};
-use core::sync::atomic::{AtomicBool, Ordering};
-
-use crate::fs_const::{ FSMAGIC, IPB, BPB };
-use crate::buffer_cache::{ BLOCK_CACHE_MANAGER, BufData };
-
-pub static mut SUPER_BLOCK: SuperBlock = SuperBlock::uninit();
+use crate::fs_const::{ FSMAGIC, BSIZE, IPB, BPB, ROOTINUM };
+use crate::block_dev::BlockDevice;
+use crate::error::FsError;
+use crate::misc::{get_u32, put_u32};
 
 /// In-memory copy of superblock
 #[derive(Debug)]
 pub struct SuperBlock {
-    data: MaybeUninit<RawSuperBlock>,
-    initialized: AtomicBool,
+    data: RawSuperBlock,
 }
 
-unsafe impl Sync for SuperBlock {}
-
 impl SuperBlock {
-    const fn uninit() -> Self {
-        Self {
-            data: MaybeUninit::uninit(),
-            initialized: AtomicBool::new(false),
-        }
-    }
-
-    /// Read and init the super block from disk into memory.
-    /// SAFETY: it should only be called by the first regular process alone.
-    pub unsafe fn init(&mut self, dev: u32) {
-        debug_assert_eq!(mem::align_of::<BufData>() % mem::align_of::<RawSuperBlock>(), 0);
-        if self.initialized.load(Ordering::Relaxed) {
-            return
-        }
-        let buf = BLOCK_CACHE_MANAGER.bread(dev, 1);
-        //info!("super block init end bread");
-        ptr::copy_nonoverlapping(
-            buf.raw_data() as *const RawSuperBlock,
-            self.data.as_mut_ptr(),
-            1,
-        );
+    /// Read the super block of the device into memory.
+    /// It reads the device directly, since the buffer cache
+    /// is not set up before the file system is mounted.
+    pub fn load(block_device: &dyn BlockDevice) -> Result<Self, FsError> {
+        let mut buf = [0u8; BSIZE];
+        block_device.read_block(1, &mut buf)?;
+        let data = RawSuperBlock::decode(&buf);
         //info!("check magic number");
-        if self.data.as_ptr().as_ref().unwrap().magic != FSMAGIC {
-            panic!("invalid file system magic num");
+        if !data.is_valid() {
+            warn!("[Xv6fs] super block: bad layout {:?}", data);
+            return Err(FsError::Corrupted);
         }
-        //info!("superblock init data {:?}",self.data.as_ptr().as_ref().unwrap());
-        self.initialized.store(true, Ordering::SeqCst);
-        drop(buf);
 
         #[cfg(feature = "verbose_init_info")]
-        info!("super block data: {:?}", self.data.as_ptr().as_ref().unwrap());
+        info!("super block data: {:?}", data);
+        Ok(Self { data })
     }
 
     /// Read the info of super block.
     fn read(&self) -> &RawSuperBlock {
-        debug_assert!(self.initialized.load(Ordering::Relaxed));
-        unsafe {
-            self.data.as_ptr().as_ref().unwrap()
-        }
+        &self.data
     }
 
     /// Load the log info of super block.
@@ -95,18 +70,34 @@ impl SuperBlock {
         self.read().ninodes
     }
 
+    /// The count of data blocks, the blocks before them hold metadata.
+    pub fn nblocks(&self) -> u32 {
+        self.read().nblocks
+    }
+
+    /// The first data block, the blocks before it hold metadata.
+    pub fn datastart(&self) -> u32 {
+        let sb = self.read();
+        sb.size - sb.nblocks
+    }
+
+    /// Whether `blockno` lies in the data area.
+    pub fn is_data_block(&self, blockno: u32) -> bool {
+        blockno >= self.datastart() && blockno < self.size()
+    }
+
     /// Given an inode number. 
     /// Return the blockno of the block this inode resides. 
-    /// Panic if the queryed inode out of range. 
-    pub fn locate_inode(&self, inum: u32) -> u32 {
+    /// Fails with `NotFound` if the queryed inode is out of range. 
+    pub fn locate_inode(&self, inum: u32) -> Result<u32, FsError> {
         let sb = self.read();
         if inum >= sb.ninodes {
-            panic!("query inum {} larger than maximum inode nums {}", inum, sb.ninodes);
+            return Err(FsError::NotFound);
         }
         // info!("[Debug] inum: {}", inum);
         let blockno = (inum / (IPB as u32)) + sb.inodestart;
         // info!("[Debug] block number: {}", blockno);
-        blockno
+        Ok(blockno)
     }
 
     /// Given a block number in the disk. 
@@ -120,8 +111,11 @@ impl SuperBlock {
 }
 
 /// Raw super block describes the disk layout.
+///
+/// Stored at the start of block 1 as eight little-endian `u32`
+/// in field order, `RawSuperBlock::SIZE` bytes in all.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct RawSuperBlock {
     pub magic: u32,      // Must be FSMAGIC
     pub size: u32,       // Size of file system image (blocks)
@@ -134,8 +128,54 @@ pub struct RawSuperBlock {
 }
 
 impl RawSuperBlock {
+    /// Size of the super block on disk.
+    pub const SIZE: usize = 32;
+
     pub fn new()->Self{
         RawSuperBlock { magic: 0, size: 0, nblocks: 0, ninodes: 0, 
             nlog: 0, logstart: 0, inodestart: 0, bmapstart: 0 }
     }
-}
\ No newline at end of file
+
+    /// Whether the regions fit in the image in order, without overlapping:
+    /// boot block, super block, log, inodes, bitmap, data.
+    pub fn is_valid(&self) -> bool {
+        if self.magic != FSMAGIC || self.nblocks == 0 || self.nblocks >= self.size {
+            return false;
+        }
+        // the log needs a header block, a block to log and the commit record,
+        // and directory entries hold 16-bit inode numbers
+        if self.nlog < 3
+            || self.ninodes <= ROOTINUM || self.ninodes > u16::MAX as u32 + 1 {
+            return false;
+        }
+        let ninodeblocks = (self.ninodes / IPB as u32 + 1) as u64;
+        let nbitmap = (self.size / BPB + (self.size % BPB != 0) as u32) as u64;
+        self.logstart >= 2
+            && self.logstart as u64 + self.nlog as u64 <= self.inodestart as u64
+            && self.inodestart as u64 + ninodeblocks <= self.bmapstart as u64
+            && self.bmapstart as u64 + nbitmap <= (self.size - self.nblocks) as u64
+    }
+
+    /// Decode a super block from the first `RawSuperBlock::SIZE` bytes of `raw`.
+    pub fn decode(raw: &[u8]) -> Self {
+        RawSuperBlock {
+            magic: get_u32(raw, 0),
+            size: get_u32(raw, 4),
+            nblocks: get_u32(raw, 8),
+            ninodes: get_u32(raw, 12),
+            nlog: get_u32(raw, 16),
+            logstart: get_u32(raw, 20),
+            inodestart: get_u32(raw, 24),
+            bmapstart: get_u32(raw, 28),
+        }
+    }
+
+    /// Encode the super block into the first `RawSuperBlock::SIZE` bytes of `raw`.
+    pub fn encode(&self, raw: &mut [u8]) {
+        let fields = [self.magic, self.size, self.nblocks, self.ninodes,
+            self.nlog, self.logstart, self.inodestart, self.bmapstart];
+        for (i, field) in fields.iter().enumerate() {
+            put_u32(raw, 4 * i, *field);
+        }
+    }
+}
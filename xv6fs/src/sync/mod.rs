@@ -1,4 +1,4 @@
 mod up;
 pub mod sleeplock;
+pub mod wait_queue;
 pub use up::UPSafeCell;
-
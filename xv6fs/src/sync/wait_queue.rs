@@ -0,0 +1,58 @@
+//! wait queue
+
+use spin::{Mutex, MutexGuard};
+
+use crate::interface::INTERFACE_MANAGER;
+
+/// Tasks waiting for a condition guarded by a spin lock,
+/// such as a free buf or room in the log.
+///
+/// Waiters sleep on a sleep lock of the interface, which `wake_all` lets go
+/// for the tasks waiting at that moment, each one passing it on to the next.
+/// Nobody sleeps with a spin lock held, and a wake-up coming between
+/// registering and sleeping is not lost, since the sleep lock stays free
+/// until the waiter takes it. A waiter may also return without a wake-up,
+/// so it must check its condition again.
+pub struct WaitQueue {
+    channel: usize,
+    state: Mutex<WaitState>,
+}
+
+struct WaitState {
+    /// tasks registered and not woken yet
+    waiters: usize,
+    /// how many of them the last `wake_all` still has to wake
+    pending: usize,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            channel: INTERFACE_MANAGER.interface.new_sleep_lock(),
+            state: Mutex::new(WaitState { waiters: 0, pending: 0 }),
+        }
+    }
+
+    /// Register the calling task, release the spin lock guarding
+    /// the condition, and sleep until a `wake_all`.
+    pub fn wait<T>(&self, guard: MutexGuard<'_, T>) {
+        self.state.lock().waiters += 1;
+        drop(guard);
+        INTERFACE_MANAGER.interface.sleep_cur_proc(self.channel);
+        let mut state = self.state.lock();
+        state.waiters -= 1;
+        state.pending = state.pending.saturating_sub(1);
+        if state.pending > 0 {
+            INTERFACE_MANAGER.interface.wake_up_next_proc(self.channel);
+        }
+    }
+
+    /// Wake every task waiting now, to check its condition again.
+    pub fn wake_all(&self) {
+        let mut state = self.state.lock();
+        if state.waiters > 0 {
+            state.pending = state.waiters;
+            INTERFACE_MANAGER.interface.wake_up_next_proc(self.channel);
+        }
+    }
+}
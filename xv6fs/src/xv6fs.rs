@@ -1,25 +1,159 @@
-use core::ptr::copy_nonoverlapping;
+#[cfg(not(test))]
+use axlog::{info, warn}; // Use log crate when building application
+
+#[cfg(test)]
+use std::{println as info, eprintln as warn}; // Workaround to use prinltn! for logs.
+
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use core::sync::atomic::AtomicU32;
 
 use crate::BlockDevice;
-use crate::disk_inode::{DirEntry,DiskInode, InodeType};
+use crate::error::FsError;
+use crate::buffer_cache::BlockCacheManager;
 use crate::file::{VFile,FileType};
-use crate::inode::{ICACHE,Inode};
-use crate::superblock::RawSuperBlock;
-use crate::fs_const::{FSMAGIC,BSIZE,IPB,FSSIZE,NDINODES, LOGSIZE};
+use crate::inode::{InodeCache,Inode};
+use crate::interface::INTERFACE_MANAGER;
+use crate::log::{LogManager, Transaction};
+use crate::mkfs::{format, FormatOptions};
+use crate::superblock::SuperBlock;
+use crate::fs_const::{IPB, MAXOPBLOCKS, NBUF};
 
 
-static mut FREEBLOCK:usize=0;
-static mut FREEINODE:usize=1;
-
+/// A mounted xv6 file system.
+///
+/// It owns the buffer cache, log, super block and inode cache of one
+/// block device, so several images can be mounted side by side.
+/// `Inode` and `VFile` handles keep their file system alive.
+///
 /// Disk layout:
-/// 
-/// boot block | superblock block | log | inode blocks | free bit map | data blocks 
+///
+/// boot block | superblock block | log | inode blocks | free bit map | data blocks
 pub struct Xv6FS{
-    ninodeblocks:usize,
-    nlog:usize,
-    nmeta:usize,
-    nblocks:usize,
+    pub dev: u32,
+    pub opts: MountOptions,
+    pub bcache: BlockCacheManager,
+    pub log: LogManager,
+    pub sb: SuperBlock,
+    pub icache: InodeCache,
+}
+
+/// How a file system is mounted.
+///
+/// ```ignore
+/// let fs = Xv6FS::open_with(dev, 1, MountOptions::new().read_only(true))?;
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct MountOptions {
+    /// Never write to the device: the log is not replayed,
+    /// and every change fails with `ReadOnly`
+    pub read_only: bool,
+    /// Go read-only once a corrupted structure is found,
+    /// instead of only failing the operation that found it
+    pub ro_on_corruption: bool,
+    /// What goes through the log
+    pub journal: JournalMode,
+    /// Number of blocks the buffer cache holds,
+    /// at least `MAXOPBLOCKS + 2` for an operation to fit in the log
+    pub cache_blocks: usize,
+    /// Keep changed blocks in the buffer cache until they are evicted
+    /// or synced, instead of writing them through
+    pub write_back: bool,
+    /// How often a write-back cache is flushed in the background,
+    /// 0 to only flush on eviction and `sync`
+    pub flush_interval_ms: u64,
+    /// Blocks prefetched past the end of a sequential file read, 0 for none.
+    /// The cache keeps it to at most half of its bufs
+    pub read_ahead: usize,
+    /// Discard the blocks a transaction frees once it commits,
+    /// for devices that unmap them
+    pub discard: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            ro_on_corruption: false,
+            journal: JournalMode::default(),
+            cache_blocks: NBUF,
+            write_back: false,
+            flush_interval_ms: 5000,
+            read_ahead: 8,
+            discard: false,
+        }
+    }
+}
+
+/// What goes through the log.
+/// Metadata always does: inodes, bitmap, indirect blocks and directories.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    /// File data is logged too, so a write is installed whole or not at all.
+    /// Writes are split into transactions of a few blocks.
+    Data,
+    /// File data is written in place before the transaction allocating
+    /// it commits, and blocks freed by a transaction are not reused for
+    /// data until it commits, so a file never shows another file's data.
+    Ordered,
+    /// File data is written in place with no ordering against the log,
+    /// so a file may show stale data after a crash. Freed blocks are
+    /// still kept from data until their transaction commits, since they
+    /// may have held metadata. While the buffer cache writes through,
+    /// data reaches the disk as it is written, as in `Ordered`.
+    Writeback,
+}
+
+impl Default for JournalMode {
+    fn default() -> Self {
+        JournalMode::Data
+    }
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn ro_on_corruption(mut self, ro_on_corruption: bool) -> Self {
+        self.ro_on_corruption = ro_on_corruption;
+        self
+    }
+
+    pub fn journal(mut self, journal: JournalMode) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    pub fn cache_blocks(mut self, cache_blocks: usize) -> Self {
+        self.cache_blocks = cache_blocks;
+        self
+    }
+
+    pub fn write_back(mut self, write_back: bool) -> Self {
+        self.write_back = write_back;
+        self
+    }
+
+    pub fn flush_interval_ms(mut self, flush_interval_ms: u64) -> Self {
+        self.flush_interval_ms = flush_interval_ms;
+        self
+    }
+
+    pub fn read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    pub fn discard(mut self, discard: bool) -> Self {
+        self.discard = discard;
+        self
+    }
 }
 
 pub fn iblock(inum:usize,rsb_inodestart:usize)->usize{
@@ -27,79 +161,133 @@ pub fn iblock(inum:usize,rsb_inodestart:usize)->usize{
 }
 
 impl Xv6FS {
-    pub fn new()->Self{
-        Self {
-            ninodeblocks: NDINODES/IPB + 1, 
-            nlog: LOGSIZE, 
-            // 1 fs block = 1 disk sector
-            //nmeta=2 + nlog + ninodeblocks + nbitmap
-            nmeta: 2 + LOGSIZE + NDINODES/IPB + 1 + FSSIZE/(BSIZE*8) + 1, 
-            //nblocks = FSSIZE - nmeta
-            nblocks:  FSSIZE-(2 + LOGSIZE + NDINODES/IPB + 1 + FSSIZE/(BSIZE*8) + 1)
-        }
+    /// Mount the file system on the block device.
+    /// Recover the fs from the log if necessary.
+    pub fn open(block_device: Arc<dyn BlockDevice>, dev: u32) -> Result<Arc<Self>, FsError> {
+        Self::open_with(block_device, dev, MountOptions::default())
     }
 
-    pub fn create(&self,block_device:Arc<dyn BlockDevice>){
-        //set superblock
-        let mut raw_superblock=RawSuperBlock::new();
-        raw_superblock.magic=FSMAGIC;
-        raw_superblock.size=FSSIZE as u32;
-        raw_superblock.nblocks=self.nblocks as u32;
-        raw_superblock.ninodes=NDINODES as u32;
-        raw_superblock.nlog=self.nlog as u32;
-        raw_superblock.logstart=2;
-        raw_superblock.inodestart=2+self.nlog as u32;
-        raw_superblock.bmapstart=(2+self.nlog+self.ninodeblocks) as u32;
-        let mut buf=[0 as u8;BSIZE];
-        for i in 0..FSSIZE{
-            block_device.write_block(i, &buf);
+    /// Mount the file system on the block device with the given options.
+    /// Fails with `Corrupted` if the super block or the log header is invalid,
+    /// and with `InvalidArg` if the buffer cache is too small.
+    pub fn open_with(block_device: Arc<dyn BlockDevice>, dev: u32, opts: MountOptions) -> Result<Arc<Self>, FsError> {
+        if opts.cache_blocks < MAXOPBLOCKS + 2 {
+            return Err(FsError::InvalidArg);
+        }
+        info!("init SUPER BLOCK");
+        let sb = SuperBlock::load(block_device.as_ref())?;
+        info!("init ICACHE");
+        let fs = Arc::new_cyclic(|me| Self {
+            dev,
+            opts,
+            bcache: BlockCacheManager::new(block_device, opts.cache_blocks, opts.write_back)
+                .read_ahead(opts.read_ahead),
+            log: LogManager::new(),
+            sb,
+            icache: InodeCache::new(me.clone()),
+        });
+        if opts.read_only {
+            fs.log.set_read_only();
+        }
+        if opts.journal == JournalMode::Ordered {
+            fs.log.set_ordered();
+        }
+        if opts.discard {
+            fs.log.set_discard();
         }
-        unsafe{copy_nonoverlapping(&raw_superblock as *const RawSuperBlock, buf.as_mut_ptr() as *mut RawSuperBlock, 1);}
-        block_device.write_block(1, &buf);
-        //set root inode
-        unsafe{FREEBLOCK=self.nmeta;}
-        let mut drinode=DiskInode::new();
-        let rinum:usize=unsafe{FREEINODE+1 as usize};
-        unsafe{FREEINODE+=1;}
-        drinode.itype=InodeType::Directory;
-        drinode.nlink=1;
-        drinode.size=0;
-        let block_id=iblock(rinum, raw_superblock.inodestart as usize);
-        block_device.read_block(block_id, &mut buf);
-        unsafe{
-            copy_nonoverlapping(
-                &drinode as *const DiskInode, 
-                (buf.as_mut_ptr() as usize + (rinum%IPB)*core::mem::size_of::<DiskInode>()) as *mut DiskInode, 
-                1
-            );
+        info!("init LOG");
+        fs.log.init(&fs.bcache, &fs.sb, dev)?;
+        if opts.write_back && !opts.read_only && opts.flush_interval_ms > 0 {
+            let me = Arc::downgrade(&fs);
+            let flusher = Box::new(move || match me.upgrade() {
+                Some(fs) => {
+                    let _ = fs.sync();
+                    true
+                }
+                None => false,
+            });
+            if !INTERFACE_MANAGER.interface.run_periodic(opts.flush_interval_ms, flusher) {
+                warn!("[Xv6fs] no background flusher, dev {} is flushed on sync only", dev);
+            }
+        }
+        info!("file system: setup done!");
+        Ok(fs)
+    }
+
+    /// Format the block device with a new file system and mount it with the given options.
+    pub fn create(block_device: Arc<dyn BlockDevice>, dev: u32, format_opts: FormatOptions, opts: MountOptions) -> Result<Arc<Self>, FsError> {
+        format(block_device.as_ref(), format_opts)?;
+        Self::open_with(block_device, dev, opts)
+    }
+
+    /// Start a file system operation.
+    /// Every change must be made inside one, and it is committed
+    /// together with the operations running beside it once the last one ends.
+    /// Operations must not nest, the outer one could keep the inner one waiting.
+    pub fn begin_op(&self) -> Transaction<'_> {
+        Transaction::new(&self.log, &self.bcache)
+    }
+
+    /// Make every change so far durable: commit what the log holds,
+    /// write the dirty bufs back, empty the log header and flush the device.
+    /// Must not be called inside an operation.
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.log.sync(&self.bcache)
+    }
+
+    /// Note a corrupted on-disk structure, and go read-only if the mount asks for it.
+    /// Returns the error for the operation that found it.
+    pub fn corrupted(&self) -> FsError {
+        warn!("[Xv6fs] corrupted structure found on dev {}", self.dev);
+        if self.opts.ro_on_corruption {
+            self.log.set_read_only();
         }
-        block_device.write_block(block_id, &buf);
-        let mut dir_entry=DirEntry::new();
-        unsafe{copy_nonoverlapping(".".as_bytes().as_ptr(), dir_entry.name.as_mut_ptr(), 2);}
-        todo!()//有空继续翻译mkfs.c里面的内容
+        FsError::Corrupted
+    }
 
+    /// Whether file data goes through the log.
+    pub fn journals_data(&self) -> bool {
+        self.opts.journal == JournalMode::Data
+    }
 
+    /// Whether the file system no longer accepts changes.
+    pub fn is_read_only(&self) -> bool {
+        self.log.is_read_only()
+    }
 
+    /// Fails with `ReadOnly` if the file system no longer accepts changes.
+    pub fn check_writable(&self) -> Result<(), FsError> {
+        if self.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
     }
 
-    pub fn get_root_inode(&mut self)->Inode{
-        ICACHE.get_root_dir()
+    pub fn get_root_inode(&self)->Result<Inode,FsError>{
+        self.icache.get_root_dir()
     }
 
-    pub fn get_root_vfile(&self)->VFile{
-        let inode=ICACHE.get_root_dir();
-        let idata=inode.lock();
+    pub fn get_root_vfile(&self)->Result<VFile,FsError>{
+        let inode=self.icache.get_root_dir()?;
+        let idata=inode.lock()?;
         let ftype=FileType::Directory;
         drop(idata);
-        VFile { 
+        Ok(VFile {
             ftype,
-            readable:true, 
-            writeable:true, 
-            inode:Some(inode), 
-            offset:0,
-        }
+            readable:true,
+            writeable:!self.is_read_only(),
+            inode:Some(inode),
+            offset:AtomicU32::new(0),
+        })
     }
-    
+
 
 }
 
+impl Drop for Xv6FS {
+    /// Retry a failed commit left in the log and write the cache back
+    /// before the caches go away.
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
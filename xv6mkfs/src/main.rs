@@ -1,156 +1,112 @@
-use crate::structs::*;
-use crate::fs_const::*;
+use clap::{App, Arg};
 
-mod structs;
-mod fs_const;
+use xv6fs::fs_const::{DIRSIZ, FSSIZE, LOGSIZE, NDINODES};
+use xv6fs::file::VFile;
+use xv6fs::host::{FileDevice, StdInterface};
+use xv6fs::{FormatOptions, FsError, MountOptions, Xv6FS};
 
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::Path;
+use std::process::exit;
 use std::sync::Arc;
-use std::sync::Mutex;
 
-use std::ptr::copy_nonoverlapping;
+/// Host files are copied in chunks of this size
+const COPY_CHUNK: usize = 64 * 1024;
 
-/// Use a block size of 512 bytes
-const BLOCK_SZ: usize = 512;
-const BLOCK_NUM:usize = 1000;
-
-
-static mut FREEBLOCK:usize=0;
-static mut FREEINODE:usize=1;
-
-struct BlockFile(Mutex<File>);
-
-impl BlockDevice for BlockFile {
-    /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
-    /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+fn main() {
+    let matches = App::new("xv6mkfs")
+        .about("Create an xv6 file system image")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Path of the image to create"))
+        .arg(Arg::with_name("size")
+            .long("size")
+            .takes_value(true)
+            .help("Size of the image in blocks"))
+        .arg(Arg::with_name("inodes")
+            .long("inodes")
+            .takes_value(true)
+            .help("Number of inodes"))
+        .arg(Arg::with_name("log")
+            .long("log")
+            .takes_value(true)
+            .help("Number of log blocks"))
+        .arg(Arg::with_name("from")
+            .long("from")
+            .takes_value(true)
+            .help("Host directory to copy into the image"))
+        .get_matches();
+
+    let opts = FormatOptions::new(parse_arg(matches.value_of("size"), "size", FSSIZE as u32))
+        .inode_count(parse_arg(matches.value_of("inodes"), "inodes", NDINODES as u32))
+        .log_blocks(parse_arg(matches.value_of("log"), "log", LOGSIZE as u32));
+    let image = matches.value_of("image").unwrap();
+
+    let dev = FileDevice::create(image, opts.total_blocks)
+        .unwrap_or_else(|err| fail(&format!("cannot create {}: {}", image, err)));
+
+    StdInterface::install();
+    let fs = Xv6FS::create(Arc::new(dev), 1, opts, MountOptions::new())
+        .unwrap_or_else(|err| fail(&format!("cannot format {}: {}", image, err)));
+
+    if let Some(from) = matches.value_of("from") {
+        if let Err(err) = copy_dir(&fs, Path::new(from), "") {
+            fail(&err);
+        }
     }
+    drop(fs);
 }
 
-fn iblock(inum:usize,rsb_inodestart:usize)->usize{
-    inum/IPB+rsb_inodestart
+fn parse_arg(value: Option<&str>, name: &str, default: u32) -> u32 {
+    match value {
+        Some(v) => v.parse().unwrap_or_else(|_| fail(&format!("invalid --{} value: {}", name, v))),
+        None => default,
+    }
 }
 
-fn ialloc(itype:InodeType)->DiskInode{
-    let mut dinode=DiskInode::new();
-    dinode.itype=itype;
-    dinode.nlink=1;
-    dinode.size=0;
-    dinode.major=0;
-    dinode.minor=0;
-    dinode.size=512;
-    unsafe{dinode.addrs[0]=FREEBLOCK as u32;}
-    unsafe{FREEBLOCK+=1};
-    dinode
-    
+fn fail(msg: &str) -> ! {
+    eprintln!("xv6mkfs: {}", msg);
+    exit(1)
 }
 
-fn main() {
-    //let nbitmap= FSSIZE/(BSIZE*8) + 1;
-    let ninodeblocks= NDINODES/IPB + 1;
-    let nlog=LOGSIZE;
-    let nmeta=2 + LOGSIZE + NDINODES/IPB + 1 + FSSIZE/(BSIZE*8) + 1;
-    let nblocks= FSSIZE-(2 + LOGSIZE + NDINODES/IPB + 1 + FSSIZE/(BSIZE*8) + 1);
-    //init superblock
-    let mut raw_superblock=RawSuperBlock::new();
-    raw_superblock.magic=FSMAGIC;
-    raw_superblock.size=FSSIZE as u32;
-    raw_superblock.nblocks=nblocks as u32;
-    raw_superblock.ninodes=NDINODES as u32;
-    raw_superblock.nlog=nlog as u32;
-    raw_superblock.logstart=2;
-    raw_superblock.inodestart=2+nlog as u32;
-    raw_superblock.bmapstart=(2+30+ninodeblocks) as u32;
-
-    //memset disk to 0
-    let mut buf=[0 as u8;BSIZE];
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/disk.img").unwrap();
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    for i in 0..FSSIZE{
-        block_file.write_block(i, &buf);
-    }
-
-    //write superblock
-    unsafe{copy_nonoverlapping(&raw_superblock as *const RawSuperBlock, buf.as_mut_ptr() as *mut RawSuperBlock, 1);}
-    block_file.write_block(1, &buf);
-
-    //set root inode
-    unsafe{FREEBLOCK=nmeta;}
-    let rinum:usize=1;
-    unsafe{FREEINODE+=1;}
-    let dinode=ialloc(InodeType::Directory);
-    println!("rinum is {}, and dinode.addr[0] is {}",rinum,dinode.addrs[0]);
-    let block_id=iblock(rinum, raw_superblock.inodestart as usize);
-    println!("blockid is {}",block_id);
-    println!("dinode is {:?}",dinode);
-    block_file.read_block(block_id, &mut buf);
-    //println!("buf is {:?}",buf);
-    unsafe{
-        copy_nonoverlapping(
-            &dinode as *const DiskInode, 
-            (buf.as_mut_ptr() as usize + (rinum%IPB)*core::mem::size_of::<DiskInode>()) as *mut DiskInode, 
-            1
-        );
+/// Recursively copy the host directory `src` to the directory `dst` of the image.
+fn copy_dir(fs: &Xv6FS, src: &Path, dst: &str) -> Result<(), String> {
+    let entries = read_dir(src).map_err(|err| format!("cannot read {}: {}", src.display(), err))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("cannot read {}: {}", src.display(), err))?;
+        let host_path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_str()
+            .ok_or_else(|| format!("{}: file name is not utf-8", host_path.display()))?;
+        if name.len() >= DIRSIZ {
+            return Err(format!("{}: {}", host_path.display(), FsError::NameTooLong));
+        }
+        let path = format!("{}/{}", dst, name);
+        let file_type = entry.file_type()
+            .map_err(|err| format!("cannot stat {}: {}", host_path.display(), err))?;
+        let fs_err = |err: FsError| format!("{}: {}", path, err);
+        if file_type.is_dir() {
+            VFile::vfile_create_dir(fs, &path, true, true).map_err(fs_err)?;
+            copy_dir(fs, &host_path, &path)?;
+        } else if file_type.is_file() {
+            let file = VFile::vfile_create_file(fs, &path, true, true).map_err(fs_err)?;
+            copy_file(&file, &host_path).map_err(|err| format!("{}: {}", path, err))?;
+        } else {
+            eprintln!("xv6mkfs: skipping {}", host_path.display());
+        }
     }
-    //println!("buf is {:?}",buf);
-    block_file.write_block(block_id, &buf);
-
-    //write direct entry 
-    let mut dir_entry=DirEntry::new();
-    dir_entry.name[0]=".".as_bytes()[0];
-    dir_entry.inum=1;
-    let block_id=dinode.addrs[0];
-    block_file.read_block(block_id as usize, &mut buf);
-    unsafe{
-        copy_nonoverlapping(
-            &dir_entry as *const DirEntry,
-            buf.as_mut_ptr() as usize as *mut DirEntry,
-            1
-        );
-    }
-    block_file.write_block(block_id as usize, &buf);
-    let mut dir_entry=DirEntry::new();
-    dir_entry.name[0]="..".as_bytes()[0];
-    dir_entry.name[1]="..".as_bytes()[1];
-    dir_entry.inum=1;
-    let block_id=dinode.addrs[0];
-    block_file.read_block(block_id as usize, &mut buf);
-    unsafe{
-        copy_nonoverlapping(
-            &dir_entry as *const DirEntry,
-            (buf.as_mut_ptr() as usize + size_of::<DirEntry>()) as *mut DirEntry,
-            1
-        );
-    }
-    block_file.write_block(block_id as usize, &buf);
+    Ok(())
+}
 
-    //write bitmap
-    let bblock_id=raw_superblock.bmapstart;
-    println!("bitmap start is {}",bblock_id);
-    let used=unsafe{FREEBLOCK};
-    println!("used block is {}",used);
-    block_file.read_block(bblock_id as usize, &mut buf);
-    for i in 0..used{
-        buf[i/8]=buf[i/8]|(0x1 << (i%8));
+fn copy_file(file: &VFile, src: &Path) -> Result<(), String> {
+    let mut host = File::open(src).map_err(|err| err.to_string())?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    loop {
+        let len = host.read(&mut buf).map_err(|err| err.to_string())?;
+        if len == 0 {
+            return Ok(());
+        }
+        file.vfile_append(buf.as_ptr() as usize, len).map_err(|err| err.to_string())?;
     }
-    block_file.write_block(bblock_id as usize, &buf);
 }